@@ -0,0 +1,8 @@
+#![no_main]
+
+use hackattic::redis::AuxHeader;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = AuxHeader::parse(&mut data.iter().copied().peekable());
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use hackattic::redis::Database;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let buf = &mut data.iter().copied().peekable();
+    // keep going like `Snapshot::parse` does, so the section boundaries get exercised too
+    while let Ok(Some(_)) = Database::parse(buf) {}
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use hackattic::redis::parse_ziplist_encoding;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_ziplist_encoding(&mut data.iter().copied().peekable());
+});
@@ -3,13 +3,29 @@ mod brute_force_zip;
 mod collision;
 mod help_me_unpack;
 mod mini_miner;
-mod redis;
 mod serving_dns;
 mod websocket_chit_chat;
 
+use hackattic::redis;
 use std::{env, format};
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some(command) = args.first() {
+        let res = match command.as_str() {
+            "redis-query" => redis::query::main(&args[1..]),
+            "dns-serve" => serving_dns::main(&args[1..]),
+            "dns-bench" => serving_dns::bench::main(&args[1..]),
+            "dns-query" => serving_dns::client::main(&args[1..]),
+            _ => Err(format!("unknown command {}", command).into()),
+        };
+        if let Err(e) = res {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let token = env::var("HACKATTIC_TOKEN")
         .expect("Expected a token on environment variable HACKATTIC_TOKEN");
     let problem_name = "websocket_chit_chat";
@@ -2,15 +2,22 @@
 // https://github.com/redis/redis/blob/4505eb18213c8da31c6dd39ba7cd36d3d01141a5/src/rdb.h
 // https://github.com/redis/redis/blob/4505eb18213c8da31c6dd39ba7cd36d3d01141a5/src/rdb.c
 use num_enum::{IntoPrimitive, TryFromPrimitive};
-use regex::Regex;
+use regex::bytes::Regex;
 use serde_json::json;
 use std::error::Error;
+use std::fmt;
 use std::fs;
 use std::iter::Peekable;
 use std::time::SystemTime;
 
 use serde::Deserialize;
 
+mod export;
+pub mod query;
+pub mod stream;
+mod writer;
+use query::{Query, TtlFilter};
+
 #[derive(Deserialize, Debug)]
 struct Requirements {
     check_type_of: String,
@@ -22,55 +29,172 @@ struct ProblemData {
     requirements: Requirements,
 }
 
-#[derive(Debug)]
-struct AuxHeader {
-    entries: Vec<KVPair>,
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuxHeader {
+    pub entries: Vec<KVPair>,
 }
 
-#[derive(Debug)]
-struct Snapshot {
-    header: AuxHeader,
-    dbs: Vec<Database>,
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub header: AuxHeader,
+    pub dbs: Vec<Database>,
 }
 
-#[derive(Debug, Clone)]
-struct Database {
-    id: u8,
-    entries: Vec<KVPair>,
-    expiries: Vec<u64>,
+#[derive(Debug, Clone, PartialEq)]
+pub struct Database {
+    pub id: u64,
+    pub entries: Vec<KVPair>,
+    /// Unix time in ms for every entry, 0 if it never expires
+    pub expiries: Vec<u64>,
 }
 
+/// A length-encoded field: a length (or any other unsigned number), or for strings, the marker
+/// that what follows is an integer or compressed string instead of that many raw bytes.
 #[derive(Debug)]
-enum Value {
-    Raw(u32),
-    Encoded(u32),
-    U32(u32),
+enum Length {
+    Len(u64),
+    Special(SpecialEncoding),
 }
 
-#[derive(Debug, Clone)]
-enum RedisValue {
-    STR(String),
-    U8(u8),
+#[derive(Debug, Clone, PartialEq)]
+pub enum RedisValue {
+    STR(Vec<u8>),
+    I8(i8),
     I16(i16),
-    U16(u16),
-    U32(u32),
     I32(i32),
     I64(i64),
 }
 
-#[derive(Debug, Clone)]
-struct KVPair {
-    key: String,
-    val: KVVal,
+#[derive(Debug, Clone, PartialEq)]
+pub struct KVPair {
+    pub key: Vec<u8>,
+    pub val: KVVal,
 }
 
-#[derive(Debug, Clone)]
-enum KVVal {
-    STR(String),
-    U8(u8),
-    U16(u16),
-    U32(u32),
+#[derive(Debug, Clone, PartialEq)]
+pub enum KVVal {
+    STR(Vec<u8>),
+    I8(i8),
+    I16(i16),
+    I32(i32),
     LIST(Vec<RedisValue>),
+    STREAM(stream::Stream),
+}
+
+/// Why a dump could not be parsed. Dumps come from untrusted sources, so anything unexpected
+/// in them is reported through this instead of panicking.
+#[derive(Debug, PartialEq)]
+pub enum RdbError {
+    /// The input ended in the middle of something
+    Truncated,
+    UnexpectedOpCode(u8),
+    UnknownValueType(u8),
+    /// Anything else that does not follow the format
+    Malformed(String),
+}
+
+impl fmt::Display for RdbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RdbError::Truncated => write!(f, "truncated RDB"),
+            RdbError::UnexpectedOpCode(op) => write!(f, "unexpected RDB opcode {:#x}", op),
+            RdbError::UnknownValueType(t) => write!(f, "unknown RDB value type {}", t),
+            RdbError::Malformed(what) => write!(f, "malformed RDB: {}", what),
+        }
+    }
+}
+
+impl Error for RdbError {}
+
+type RdbResult<T> = Result<T, RdbError>;
+
+impl RedisValue {
+    /// Length of the value as a string, which is how redis reports integer encoded values too
+    fn size(&self) -> usize {
+        match self {
+            RedisValue::STR(s) => s.len(),
+            v => v.to_string().len(),
+        }
+    }
+}
+
+/// Integers are shown in decimal, strings through `Escaped`
+impl fmt::Display for RedisValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RedisValue::STR(s) => write!(f, "{}", Escaped(s)),
+            RedisValue::I8(v) => write!(f, "{}", v),
+            RedisValue::I16(v) => write!(f, "{}", v),
+            RedisValue::I32(v) => write!(f, "{}", v),
+            RedisValue::I64(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// The value of a plain string key
+impl From<RedisValue> for KVVal {
+    fn from(v: RedisValue) -> KVVal {
+        match v {
+            RedisValue::STR(s) => KVVal::STR(s),
+            RedisValue::I8(v) => KVVal::I8(v),
+            RedisValue::I16(v) => KVVal::I16(v),
+            RedisValue::I32(v) => KVVal::I32(v),
+            // never produced by the RDB string encodings
+            RedisValue::I64(v) => KVVal::STR(v.to_string().into_bytes()),
+        }
+    }
+}
+
+impl KVVal {
+    /// Name of the type as the `TYPE` command would report it
+    fn type_name(&self) -> &'static str {
+        match self {
+            KVVal::STR(_) | KVVal::I8(_) | KVVal::I16(_) | KVVal::I32(_) => "string",
+            KVVal::LIST(_) => "hash",
+            KVVal::STREAM(_) => "stream",
+        }
+    }
+
+    /// Payload size in bytes; for aggregates, the sum of their elements' sizes
+    fn size(&self) -> usize {
+        match self {
+            KVVal::STR(s) => s.len(),
+            KVVal::I8(v) => v.to_string().len(),
+            KVVal::I16(v) => v.to_string().len(),
+            KVVal::I32(v) => v.to_string().len(),
+            KVVal::LIST(l) => l.iter().map(RedisValue::size).sum(),
+            KVVal::STREAM(s) => s.size(),
+        }
+    }
+}
+
+/// Shows arbitrary bytes without losing information: valid UTF-8 is kept as is, backslashes
+/// are doubled and everything else (control characters, invalid UTF-8) is written as `\xNN`.
+struct Escaped<'a>(&'a [u8]);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for chunk in self.0.utf8_chunks() {
+            for c in chunk.valid().chars() {
+                match c {
+                    '\\' => f.write_str("\\\\")?,
+                    '\n' => f.write_str("\\n")?,
+                    '\r' => f.write_str("\\r")?,
+                    '\t' => f.write_str("\\t")?,
+                    c if c.is_control() => {
+                        for b in c.encode_utf8(&mut [0; 4]).bytes() {
+                            write!(f, "\\x{:02x}", b)?;
+                        }
+                    }
+                    c => write!(f, "{}", c)?,
+                }
+            }
+            for b in chunk.invalid() {
+                write!(f, "\\x{:02x}", b)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(IntoPrimitive, TryFromPrimitive, PartialEq, Debug)]
@@ -111,285 +235,424 @@ enum ValueTypeEncoding {
      * Intset = 11,
      */
     HashmapZe = 13,
+    StreamListpacks = 15,
+    StreamListpacks2 = 19,
+    StreamListpacks3 = 21,
 }
 
-fn read_str<I>(buf: &mut Peekable<I>, len: usize) -> String
+fn next_byte<I>(buf: &mut Peekable<I>) -> RdbResult<u8>
 where
     I: Iterator<Item = u8>,
 {
-    String::from_utf8(buf.take(len as usize).collect()).unwrap()
+    buf.next().ok_or(RdbError::Truncated)
 }
 
-fn parse_zl_entry<I>(buf: &mut Peekable<I>) -> RedisValue
+fn peek_byte<I>(buf: &mut Peekable<I>) -> RdbResult<u8>
 where
     I: Iterator<Item = u8>,
 {
-    let _prev_entry_len = match *buf.peek().unwrap() {
-        254 => {
-            buf.next(); // consume peek
-            let _bytes: Vec<u8> = buf.take(4).collect();
-            u32::from_le_bytes(_bytes.try_into().unwrap())
-        }
-        x => {
-            buf.next(); // consume peek
-            x as u32
-        }
+    buf.peek().copied().ok_or(RdbError::Truncated)
+}
+
+fn read_bytes<I, const N: usize>(buf: &mut Peekable<I>) -> RdbResult<[u8; N]>
+where
+    I: Iterator<Item = u8>,
+{
+    let mut bytes = [0; N];
+    for b in bytes.iter_mut() {
+        *b = next_byte(buf)?;
+    }
+    Ok(bytes)
+}
+
+/// `len` comes from the file, so nothing is allocated up front: a huge length on a short
+/// input only costs as much as the input.
+fn read_str<I>(buf: &mut Peekable<I>, len: u64) -> RdbResult<Vec<u8>>
+where
+    I: Iterator<Item = u8>,
+{
+    let s: Vec<u8> = buf.take(len.try_into().unwrap_or(usize::MAX)).collect();
+    if s.len() as u64 != len {
+        return Err(RdbError::Truncated);
+    }
+    Ok(s)
+}
+
+fn parse_zl_entry<I>(buf: &mut Peekable<I>) -> RdbResult<RedisValue>
+where
+    I: Iterator<Item = u8>,
+{
+    let _prev_entry_len = match next_byte(buf)? {
+        254 => u32::from_le_bytes(read_bytes(buf)?),
+        x => x as u32,
     };
 
-    let first_2_bits = (buf.peek().unwrap() & 0xC0) >> 6;
-    let second_2_bits = (buf.peek().unwrap() & 0b0011_0000) >> 4;
-    let last_6_bits = buf.next().unwrap() & 0x3F;
+    let enc = next_byte(buf)?;
+    let last_6_bits = enc & 0x3F;
 
-    match first_2_bits {
-        0b00 => RedisValue::STR(read_str(buf, last_6_bits as usize)), // string val with len = 6bits
+    Ok(match enc >> 6 {
+        0b00 => RedisValue::STR(read_str(buf, last_6_bits as u64)?), // string val with len = 6bits
         0b01 => {
             // string val with len = 14bits
-            let len = ((last_6_bits as u16) << 8) | buf.next().unwrap() as u16;
-            RedisValue::STR(read_str(buf, len as usize))
+            let len = ((last_6_bits as u64) << 8) | next_byte(buf)? as u64;
+            RedisValue::STR(read_str(buf, len)?)
         }
-
         0b10 => {
-            // string val with len = 32bits
-            RedisValue::STR(read_str(buf, 4))
+            // string val with len = 32bits, big endian like the 14bit one
+            let len = u32::from_be_bytes(read_bytes(buf)?);
+            RedisValue::STR(read_str(buf, len as u64)?)
         }
-        0b11 => match second_2_bits {
-            0b00 => {
-                let _bytes: Vec<u8> = buf.take(2).collect();
-                RedisValue::I16(i16::from_le_bytes(_bytes.try_into().unwrap()))
-            }
-            0b01 => {
-                let _bytes: Vec<u8> = buf.take(4).collect();
-                RedisValue::I32(i32::from_le_bytes(_bytes.try_into().unwrap()))
+        _ => match enc {
+            0xC0 => RedisValue::I16(i16::from_le_bytes(read_bytes(buf)?)),
+            0xD0 => RedisValue::I32(i32::from_le_bytes(read_bytes(buf)?)),
+            0xE0 => RedisValue::I64(i64::from_le_bytes(read_bytes(buf)?)),
+            0xF0 => {
+                // 24 bit int, sign extended by going through the top of an i32
+                let [a, b, c] = read_bytes(buf)?;
+                RedisValue::I32(i32::from_le_bytes([0, a, b, c]) >> 8)
             }
-            0b10 => {
-                let _bytes: Vec<u8> = buf.take(8).collect();
-                RedisValue::I64(i64::from_le_bytes(_bytes.try_into().unwrap()))
+            0xFE => RedisValue::I8(next_byte(buf)? as i8),
+            // 4 bit immediate, 0001 to 1101 meaning 0 to 12
+            0xF1..=0xFD => RedisValue::I8((enc & 0x0F) as i8 - 1),
+            _ => {
+                return Err(RdbError::Malformed(format!(
+                    "ziplist entry encoding {:#x}",
+                    enc
+                )))
             }
-            0b11 => {
-                // i24 ????
-                let mut _bytes: Vec<u8> = buf.take(3).collect();
-                _bytes.insert(0, 0);
-                RedisValue::I32(i32::from_le_bytes(_bytes.try_into().unwrap()))
-            }
-            _ => panic!(),
         },
-        _ => panic!(),
-    }
+    })
 }
 
-fn parse_ziplist_encoding<I>(buf: &mut Peekable<I>) -> Vec<RedisValue>
+pub fn parse_ziplist_encoding<I>(buf: &mut Peekable<I>) -> RdbResult<Vec<RedisValue>>
 where
     I: Iterator<Item = u8>,
 {
-    let ziplist_len = read_length(buf);
-
-    let _bytes: Vec<u8> = buf.take(4).collect();
-    let _zlbytes = u32::from_le_bytes(_bytes.try_into().unwrap());
-
-    let _bytes: Vec<u8> = buf.take(4).collect();
-    let _zltail = u32::from_le_bytes(_bytes.try_into().unwrap());
-
-    let _bytes: Vec<u8> = buf.take(2).collect();
-    let _zllen = u16::from_le_bytes(_bytes.try_into().unwrap());
-
-    println!("zl len {}", _zllen);
-    let ret = (0.._zllen).map(|_| parse_zl_entry(buf)).collect();
-    assert_eq!(buf.next().unwrap(), 0xFF); // end of ziplist
+    // the whole ziplist is saved as one string object, which may well be compressed
+    let ziplist = read_string(buf)?;
+    let buf = &mut ziplist.into_iter().peekable();
+
+    let _zlbytes = u32::from_le_bytes(read_bytes(buf)?);
+    let _zltail = u32::from_le_bytes(read_bytes(buf)?);
+    let _zllen = u16::from_le_bytes(read_bytes(buf)?);
+
+    let ret = (0.._zllen)
+        .map(|_| parse_zl_entry(buf))
+        .collect::<RdbResult<_>>()?;
+    if next_byte(buf)? != 0xFF {
+        return Err(RdbError::Malformed("ziplist without end marker".into()));
+    }
 
-    ret
+    Ok(ret)
 }
 
-fn read_value_type<I>(buf: &mut Peekable<I>) -> KVPair
+fn read_value_type<I>(buf: &mut Peekable<I>) -> RdbResult<KVPair>
 where
     I: Iterator<Item = u8>,
 {
-    let _v = buf.next().unwrap();
-    let l = read_length(buf);
+    let _v = next_byte(buf)?;
+    let val_type =
+        ValueTypeEncoding::try_from_primitive(_v).map_err(|_| RdbError::UnknownValueType(_v))?;
+    let key = read_string(buf)?;
 
-    let key_len = match l {
-        Value::Raw(len) => len as u32,
-        Value::U32(len) => len as u32,
-        v => panic!("not sure what {:?} is", v),
-    };
-    let _keybuf: Vec<u8> = buf.take(key_len as usize).collect();
-    let key = if let Ok(_key) = String::from_utf8(_keybuf.clone()) {
-        _key.to_string()
-    } else {
-        format!("<<<<key is invalid string {:?}>>>>", _keybuf)
-    };
-
-    let val_type = ValueTypeEncoding::try_from_primitive(_v).unwrap();
-    KVPair {
+    Ok(KVPair {
         key,
         val: match val_type {
-            ValueTypeEncoding::STR => match read_length_encoding(buf) {
-                RedisValue::STR(s) => KVVal::STR(s),
-                RedisValue::U16(len) => KVVal::U16(len),
-                v => panic!("RV {:#?}", v),
-            },
-            ValueTypeEncoding::HashmapZe => KVVal::LIST(parse_ziplist_encoding(buf)),
+            ValueTypeEncoding::STR => read_length_encoding(buf)?.into(),
+            ValueTypeEncoding::HashmapZe => KVVal::LIST(parse_ziplist_encoding(buf)?),
+            t @ (ValueTypeEncoding::StreamListpacks
+            | ValueTypeEncoding::StreamListpacks2
+            | ValueTypeEncoding::StreamListpacks3) => KVVal::STREAM(stream::Stream::parse(buf, t)?),
         },
-    }
+    })
 }
 
-fn read_length<I>(buf: &mut Peekable<I>) -> Value
+fn read_length<I>(buf: &mut Peekable<I>) -> RdbResult<Length>
 where
     I: Iterator<Item = u8>,
 {
-    let first_2_bits = (buf.peek().unwrap() & 0xC0) >> 6;
-    let last_6_bits = buf.next().unwrap() & 0x3F;
-    let len = LengthEnc::try_from_primitive(first_2_bits).unwrap();
-    match len {
-        LengthEnc::Encoded => Value::Encoded(last_6_bits as u32),
+    let b = next_byte(buf)?;
+    let last_6_bits = b & 0x3F;
+    // two bits can only ever be a valid LengthEnc
+    let len = LengthEnc::try_from_primitive(b >> 6).unwrap();
+    Ok(match len {
+        LengthEnc::Encoded => Length::Special(
+            SpecialEncoding::try_from_primitive(last_6_bits)
+                .map_err(|_| RdbError::Malformed(format!("string encoding {}", last_6_bits)))?,
+        ),
 
-        LengthEnc::SixBits => Value::Raw(last_6_bits as u32),
+        LengthEnc::SixBits => Length::Len(last_6_bits as u64),
 
         LengthEnc::FourteenBits => {
-            Value::Raw(((last_6_bits as u32) << 8) | buf.next().unwrap() as u32)
+            Length::Len(((last_6_bits as u64) << 8) | next_byte(buf)? as u64)
         }
-        LengthEnc::FourOrEightBytes => {
-            let len_discriminator = buf.next().unwrap();
-            if len_discriminator == 0 {
-                // 32 bit, "net order"
-                let bytes: Vec<u8> = buf.take(4).collect();
-                Value::U32(u32::from_be_bytes(bytes.try_into().unwrap()))
-                //RedisValue::U32(u32::from_be_bytes(bytes.try_into().unwrap()))
-            } else {
-                // 64 bit, "net order"
-                assert_eq!(len_discriminator, 1);
-                panic!();
-                // RedisValue::U64(u64::from_be(bytes))
-            }
+        // the remaining 6 bits tell which one, both in "net order"
+        LengthEnc::FourOrEightBytes => match last_6_bits {
+            0 => Length::Len(u32::from_be_bytes(read_bytes(buf)?) as u64),
+            1 => Length::Len(u64::from_be_bytes(read_bytes(buf)?)),
+            _ => return Err(RdbError::Malformed(format!("length encoding {:#x}", b))),
+        },
+    })
+}
+
+/// A string object as redis saves them: raw, LZF compressed, or as an integer
+fn read_length_encoding<I>(buf: &mut Peekable<I>) -> RdbResult<RedisValue>
+where
+    I: Iterator<Item = u8>,
+{
+    Ok(match read_length(buf)? {
+        Length::Len(len) => RedisValue::STR(read_str(buf, len)?),
+        Length::Special(SpecialEncoding::INT8) => RedisValue::I8(next_byte(buf)? as i8),
+        Length::Special(SpecialEncoding::INT16) => {
+            RedisValue::I16(i16::from_le_bytes(read_bytes(buf)?))
+        }
+        Length::Special(SpecialEncoding::INT32) => {
+            RedisValue::I32(i32::from_le_bytes(read_bytes(buf)?))
         }
+        Length::Special(SpecialEncoding::Compressed) => RedisValue::STR(read_lzf_str(buf)?),
+    })
+}
+
+/// A length that has to be a plain number, as opposed to a string's length-or-encoding
+fn read_len<I>(buf: &mut Peekable<I>) -> RdbResult<u64>
+where
+    I: Iterator<Item = u8>,
+{
+    match read_length(buf)? {
+        Length::Len(len) => Ok(len),
+        Length::Special(enc) => Err(RdbError::Malformed(format!(
+            "expected a length, got {:?}",
+            enc
+        ))),
     }
 }
-fn read_length_encoding<I>(buf: &mut Peekable<I>) -> RedisValue
+
+/// Like `read_length_encoding`, for places where integers are just an optimization for strings
+fn read_string<I>(buf: &mut Peekable<I>) -> RdbResult<Vec<u8>>
 where
     I: Iterator<Item = u8>,
 {
-    match read_length(buf) {
-        Value::Encoded(last_6_bits) => {
-            let enc = SpecialEncoding::try_from_primitive(last_6_bits as u8).unwrap();
-            match enc {
-                SpecialEncoding::INT8 => RedisValue::U8(buf.next().unwrap()),
-                SpecialEncoding::INT16 => {
-                    let bytes: Vec<u8> = buf.take(2).collect();
-                    assert_eq!(bytes.len(), 2);
-                    RedisValue::U16(u16::from_le_bytes(bytes.try_into().unwrap()))
-                }
-                SpecialEncoding::INT32 => {
-                    let bytes: Vec<u8> = buf.take(4).collect();
-                    assert_eq!(bytes.len(), 4);
-                    RedisValue::U32(u32::from_le_bytes(bytes.try_into().unwrap()))
-                }
-                SpecialEncoding::Compressed => panic!(),
+    Ok(match read_length_encoding(buf)? {
+        RedisValue::STR(s) => s,
+        v => v.to_string().into_bytes(),
+    })
+}
+
+fn read_lzf_str<I>(buf: &mut Peekable<I>) -> RdbResult<Vec<u8>>
+where
+    I: Iterator<Item = u8>,
+{
+    let compressed_len = read_len(buf)?;
+    let len = read_len(buf)?;
+    let compressed = read_str(buf, compressed_len)?;
+    lzf_decompress(&compressed, len)
+}
+
+/// Caps how much we preallocate based on sizes read from an untrusted file
+const MAX_PREALLOCATED: u64 = 1 << 16;
+
+// http://oldhome.schmorp.de/marc/liblzf.html, as used by redis' rdbLoadLzfStringObject
+fn lzf_decompress(input: &[u8], len: u64) -> RdbResult<Vec<u8>> {
+    let mut out: Vec<u8> = Vec::with_capacity(len.min(MAX_PREALLOCATED) as usize);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 1 << 5 {
+            // literal run of ctrl + 1 bytes
+            let run = input.get(i..i + ctrl + 1).ok_or(RdbError::Truncated)?;
+            out.extend(run);
+            i += ctrl + 1;
+        } else {
+            // back reference: 3 bits of length (7 meaning "read another byte") + 13 bits of offset
+            let mut ref_len = ctrl >> 5;
+            if ref_len == 7 {
+                ref_len += *input.get(i).ok_or(RdbError::Truncated)? as usize;
+                i += 1;
+            }
+            let offset =
+                ((ctrl & 0x1F) << 8) + *input.get(i).ok_or(RdbError::Truncated)? as usize + 1;
+            i += 1;
+            let start = out
+                .len()
+                .checked_sub(offset)
+                .ok_or_else(|| RdbError::Malformed("LZF back reference before the start".into()))?;
+            // the reference may overlap with what it is producing, so copy byte by byte
+            for k in 0..ref_len + 2 {
+                out.push(out[start + k]);
             }
         }
-        Value::Raw(bytes) => {
-            RedisValue::STR(String::from_utf8(buf.take(bytes as usize).collect()).unwrap())
+        if out.len() as u64 > len {
+            break;
         }
-        Value::U32(num) => RedisValue::U32(num),
     }
+    if out.len() as u64 != len {
+        return Err(RdbError::Malformed(format!(
+            "LZF string of {} bytes, expected {}",
+            out.len(),
+            len
+        )));
+    }
+    Ok(out)
 }
 
-fn read_key_value<I>(buf: &mut Peekable<I>) -> KVPair
+fn read_key_value<I>(buf: &mut Peekable<I>) -> RdbResult<KVPair>
 where
     I: Iterator<Item = u8>,
 {
-    let key_len = buf.next().unwrap();
-    let key = String::from_utf8(buf.take(key_len as usize).collect()).unwrap();
-    KVPair {
-        key,
-        val: match read_length_encoding(buf) {
-            RedisValue::STR(v) => KVVal::STR(v),
-            RedisValue::U8(v) => KVVal::U8(v),
-            RedisValue::U16(v) => KVVal::U16(v),
-            RedisValue::U32(v) => KVVal::U32(v),
-            _ => panic!(),
-        },
-    }
+    Ok(KVPair {
+        key: read_string(buf)?,
+        val: read_length_encoding(buf)?.into(),
+    })
 }
 
 impl AuxHeader {
-    fn parse<I>(buf: &mut Peekable<I>) -> Option<AuxHeader>
+    pub fn parse<I>(buf: &mut Peekable<I>) -> RdbResult<AuxHeader>
     where
         I: Iterator<Item = u8>,
     {
         let mut entries = vec![];
         loop {
-            match OpCodes::try_from(*buf.peek().unwrap()) {
-                Ok(OpCodes::SelectDB) => break, // AuxHeader ends on SelectDB
+            let opcode = peek_byte(buf)?;
+            match OpCodes::try_from(opcode) {
+                // AuxHeader ends on SelectDB, or on EOF for a dump without databases
+                Ok(OpCodes::SelectDB) | Ok(OpCodes::EOF) => break,
                 Ok(OpCodes::Aux) => {
                     buf.next(); // consume the peek'd position
-                    let ver = read_key_value(buf);
+                    let ver = read_key_value(buf)?;
                     entries.push(ver);
                 }
-                Ok(_) => panic!("something else"),
-                Err(_) => panic!("ran out"),
+                _ => return Err(RdbError::UnexpectedOpCode(opcode)),
             }
         }
-        Some(AuxHeader { entries })
+        Ok(AuxHeader { entries })
     }
 }
 
 impl Database {
-    fn parse<I>(buf: &mut Peekable<I>) -> Option<Database>
+    /// Returns `None` once there are no more databases
+    pub fn parse<I>(buf: &mut Peekable<I>) -> RdbResult<Option<Database>>
     where
         I: Iterator<Item = u8>,
     {
-        if OpCodes::try_from(*buf.peek().unwrap()) != Ok(OpCodes::SelectDB) {
-            return None;
-        }
-        buf.next(); // consume opcode
-        let db_id = buf.next().unwrap();
-
-        assert_eq!(
-            OpCodes::try_from(buf.next().unwrap()).unwrap(),
-            OpCodes::ResizeDB
-        );
-        let hash_size = if let Value::Raw(len) = read_length(buf) {
-            len
-        } else {
-            panic!();
+        let Some((id, db_size)) = Database::parse_header(buf)? else {
+            return Ok(None);
         };
-        let expire_size = if let Value::Raw(len) = read_length(buf) {
-            len
-        } else {
-            panic!();
+        let capacity = db_size.min(MAX_PREALLOCATED) as usize;
+        let mut entries: Vec<KVPair> = Vec::with_capacity(capacity);
+        let mut expiries: Vec<u64> = Vec::with_capacity(capacity);
+        Database::parse_entries(buf, |entry, expiry| {
+            entries.push(entry);
+            expiries.push(expiry);
+        })?;
+        Ok(Some(Database {
+            id,
+            entries,
+            expiries,
+        }))
+    }
+
+    /// Parses a single database section, handing every entry (with its expiry in ms, 0 if it
+    /// has none) to `f` as soon as it is read instead of collecting them.
+    /// Returns the database id.
+    fn stream<I, F>(buf: &mut Peekable<I>, mut f: F) -> RdbResult<Option<u64>>
+    where
+        I: Iterator<Item = u8>,
+        F: FnMut(u64, KVPair, u64),
+    {
+        let Some((id, _)) = Database::parse_header(buf)? else {
+            return Ok(None);
         };
+        Database::parse_entries(buf, |entry, expiry| f(id, entry, expiry))?;
+        Ok(Some(id))
+    }
 
-        let mut entries: Vec<KVPair> = Vec::with_capacity(hash_size as usize);
-        let mut expiries: Vec<u64> = Vec::with_capacity(expire_size as usize);
+    /// `SelectDB` and the optional `ResizeDB` that follows it. Returns the database id and the
+    /// number of keys `ResizeDB` announced (0 without one), which is only a hint for preallocating:
+    /// nothing guarantees the data that follows matches it.
+    fn parse_header<I>(buf: &mut Peekable<I>) -> RdbResult<Option<(u64, u64)>>
+    where
+        I: Iterator<Item = u8>,
+    {
+        let opcode = peek_byte(buf)?;
+        match OpCodes::try_from(opcode) {
+            Ok(OpCodes::SelectDB) => {}
+            Ok(OpCodes::EOF) => return Ok(None),
+            _ => return Err(RdbError::UnexpectedOpCode(opcode)),
+        }
+        buf.next(); // consume opcode
+        let db_id = read_len(buf)?;
+
+        // only written since RDB version 7
+        let mut db_size = 0;
+        if OpCodes::try_from(peek_byte(buf)?) == Ok(OpCodes::ResizeDB) {
+            buf.next(); // consume opcode
+            db_size = read_len(buf)?;
+            let _expires_size = read_len(buf)?;
+        }
+        Ok(Some((db_id, db_size)))
+    }
+
+    fn parse_entries<I, F>(buf: &mut Peekable<I>, mut f: F) -> RdbResult<()>
+    where
+        I: Iterator<Item = u8>,
+        F: FnMut(KVPair, u64),
+    {
         let mut last_expiry: u64 = 0;
         loop {
-            let opcode = *buf.peek().unwrap();
+            let opcode = peek_byte(buf)?;
             match OpCodes::try_from(opcode) {
                 Ok(OpCodes::SelectDB) => break, // DB ends on SelectDB
-                Ok(OpCodes::EOF) => break,      // File ends on SelectDB
+                Ok(OpCodes::EOF) => break,      // File ends on EOF
                 Ok(OpCodes::ExpireTimeMs) => {
-                    buf.next().unwrap(); // consume opcode
-                    let data: Vec<u8> = buf.take(8).collect();
-                    let expiry_ms = u64::from_le_bytes(data.try_into().unwrap());
-                    last_expiry = expiry_ms;
+                    buf.next(); // consume opcode
+                    last_expiry = u64::from_le_bytes(read_bytes(buf)?);
+                }
+                Ok(OpCodes::ExpireTimeSec) => {
+                    buf.next(); // consume opcode
+                    let expiry_sec = u32::from_le_bytes(read_bytes(buf)?);
+                    last_expiry = expiry_sec as u64 * 1000;
                 }
-                Ok(unkn) => panic!("Unknown OpCode {:#?}", unkn),
                 Err(_) if opcode < 245 => {
-                    let e = read_value_type(buf);
-                    expiries.push(last_expiry);
+                    let e = read_value_type(buf)?;
+                    f(e, last_expiry);
                     last_expiry = 0;
-                    entries.push(e);
                 }
-                Err(_) => panic!("Unknown OpCode {:#?}", opcode),
+                _ => return Err(RdbError::UnexpectedOpCode(opcode)),
             }
         }
-        assert_eq!(entries.len() as u32, hash_size);
-        Some(Database {
-            id: db_id,
-            entries,
-            expiries: expiries,
-        })
+        Ok(())
     }
 }
+
+impl Snapshot {
+    pub fn parse<I>(buf: &mut Peekable<I>) -> RdbResult<Snapshot>
+    where
+        I: Iterator<Item = u8>,
+    {
+        let _: [u8; 9] = read_bytes(buf)?; // header
+        let header = AuxHeader::parse(buf)?;
+        let mut dbs = vec![];
+        while let Some(d) = Database::parse(buf)? {
+            dbs.push(d);
+        }
+        Ok(Snapshot { header, dbs })
+    }
+
+    /// Like `parse`, but every entry of every database is handed to `f` as it is read, so
+    /// callers never need to hold the whole snapshot in memory.
+    fn stream<I, F>(buf: &mut Peekable<I>, mut f: F) -> RdbResult<AuxHeader>
+    where
+        I: Iterator<Item = u8>,
+        F: FnMut(u64, KVPair, u64),
+    {
+        let _: [u8; 9] = read_bytes(buf)?; // header
+        let header = AuxHeader::parse(buf)?;
+        while Database::stream(buf, &mut f)?.is_some() {}
+        Ok(header)
+    }
+}
+
 pub fn solve(parsed_data: String) -> Result<String, Box<dyn Error>> {
     let json_data: ProblemData = serde_json::from_str(&parsed_data)?;
     let mut rdb = base64::decode(json_data.rdb).unwrap();
@@ -415,52 +678,43 @@ pub fn solve(parsed_data: String) -> Result<String, Box<dyn Error>> {
     let rdb = fs::read("snap")?[9..].to_vec();
     */
     let buf = &mut rdb.into_iter().peekable();
-    let _: Vec<u8> = buf.take(9).collect(); // header
-
-    let header = AuxHeader::parse(buf);
-    let mut dbs = vec![];
-    while let Some(d) = Database::parse(buf) {
-        dbs.push(d);
-    }
-
-    let s = Snapshot {
-        header: header.unwrap(),
-        dbs: dbs.clone(),
-    };
+    let s = Snapshot::parse(buf)?;
     println!("{:#?}", s);
 
-    let db_count = dbs.len();
+    let db_count = s.dbs.len();
     let mut emoji_key_value: String = "???".to_string();
     let mut expiry_millis = 0;
     let mut type_of_key_to_check = "type of key to check";
 
-    let emoji_regex = Regex::new(r"\p{Emoji}").unwrap();
+    let emoji_query = Query {
+        regex: Some(Regex::new(r"\p{Emoji}").unwrap()),
+        ..Default::default()
+    };
+    let expiring_query = Query {
+        ttl: Some(TtlFilter::Volatile),
+        ..Default::default()
+    };
 
     println!("need to check type of {}", key_to_check);
-    for db in dbs {
-        for expiry in db.expiries {
-            if expiry > 0 {
+    for db in s.dbs {
+        for (entry, expiry) in db.entries.into_iter().zip(db.expiries) {
+            if expiring_query.matches(db.id, &entry, expiry) {
                 assert_eq!(expiry_millis, 0);
                 expiry_millis = expiry;
             }
-        }
-        for entry in db.entries {
-            if emoji_regex.is_match(&entry.key) {
-                println!("This key is emoji: {} {:?}", entry.key, entry.val);
-                if let KVVal::STR(s) = entry.val.clone() {
-                    emoji_key_value = s;
+            if emoji_query.matches(db.id, &entry, expiry) {
+                println!("This key is emoji: {} {:?}", Escaped(&entry.key), entry.val);
+                if let KVVal::STR(s) = &entry.val {
+                    emoji_key_value = String::from_utf8_lossy(s).into_owned();
                 } else {
                     panic!("idk how to store non-string val");
                 }
             }
 
-            if entry.key == key_to_check {
+            if entry.key == key_to_check.as_bytes() {
                 type_of_key_to_check = match entry.val {
-                    KVVal::U8(val) => "number",
-                    KVVal::U16(val) => "number",
-                    KVVal::U32(val) => "number",
-                    KVVal::STR(val) => "string",
-                    KVVal::LIST(val) => "hash",
+                    KVVal::I8(_) | KVVal::I16(_) | KVVal::I32(_) => "number",
+                    v => v.type_name(),
                 }
             }
         }
@@ -0,0 +1,106 @@
+// JSON rendering of snapshot entries. Keys and string values go through `Escaped`, so binary
+// data is never mangled by a lossy UTF-8 conversion.
+use serde_json::{json, Map, Value};
+
+use super::stream::{ConsumerGroup, Stream};
+use super::{Escaped, KVPair, KVVal, RedisValue};
+
+impl RedisValue {
+    fn to_json(&self) -> Value {
+        match self {
+            RedisValue::STR(s) => Value::String(Escaped(s).to_string()),
+            RedisValue::I8(v) => json!(v),
+            RedisValue::I16(v) => json!(v),
+            RedisValue::I32(v) => json!(v),
+            RedisValue::I64(v) => json!(v),
+        }
+    }
+}
+
+impl KVVal {
+    fn to_json(&self) -> Value {
+        match self {
+            KVVal::STR(s) => Value::String(Escaped(s).to_string()),
+            KVVal::I8(v) => json!(v),
+            KVVal::I16(v) => json!(v),
+            KVVal::I32(v) => json!(v),
+            // ziplist encoded hash: field, value, field, value, ...
+            KVVal::LIST(l) => Value::Object(
+                l.chunks(2)
+                    .map(|kv| {
+                        let val = kv.get(1).map_or(Value::Null, RedisValue::to_json);
+                        (kv[0].to_string(), val)
+                    })
+                    .collect::<Map<String, Value>>(),
+            ),
+            KVVal::STREAM(s) => s.to_json(),
+        }
+    }
+}
+
+impl ConsumerGroup {
+    fn to_json(&self) -> Value {
+        // the consumer owning each pending entry is only known through the consumers' PELs
+        let owner = |id| {
+            self.consumers
+                .iter()
+                .find(|c| c.pending.contains(id))
+                .map(|c| Escaped(&c.name).to_string())
+        };
+        json!({
+            "name": Escaped(&self.name).to_string(),
+            "last_id": self.last_id.to_string(),
+            "entries_read": self.entries_read,
+            "pending": self.pending.iter().map(|p| json!({
+                "id": p.id.to_string(),
+                "consumer": owner(&p.id),
+                "delivery_time": p.delivery_time,
+                "delivery_count": p.delivery_count,
+            })).collect::<Vec<Value>>(),
+            "consumers": self.consumers.iter().map(|c| json!({
+                "name": Escaped(&c.name).to_string(),
+                "seen_time": c.seen_time,
+                "active_time": c.active_time,
+                "pending": c.pending.iter().map(|id| id.to_string()).collect::<Vec<String>>(),
+            })).collect::<Vec<Value>>(),
+        })
+    }
+}
+
+impl Stream {
+    fn to_json(&self) -> Value {
+        json!({
+            "length": self.length,
+            "last_id": self.last_id.to_string(),
+            // before RDB_TYPE_STREAM_LISTPACKS_2, redis derives it from the entries on load
+            "first_id": self
+                .v2
+                .map(|v2| v2.first_id)
+                .or_else(|| self.entries.first().map(|e| e.id))
+                .map(|id| id.to_string()),
+            "max_deleted_id": self.v2.map(|v2| v2.max_deleted_id.to_string()),
+            "entries_added": self.v2.map(|v2| v2.entries_added),
+            // fields are kept as pairs: they are ordered and may repeat
+            "entries": self.entries.iter().map(|e| json!({
+                "id": e.id.to_string(),
+                "fields": e.fields.iter()
+                    .map(|(f, v)| [Escaped(f).to_string(), Escaped(v).to_string()])
+                    .collect::<Vec<[String; 2]>>(),
+            })).collect::<Vec<Value>>(),
+            "groups": self.groups.iter().map(ConsumerGroup::to_json).collect::<Vec<Value>>(),
+        })
+    }
+}
+
+impl KVPair {
+    /// `expiry` is in unix ms, 0 meaning none (exported as `null`)
+    pub(super) fn to_json(&self, db: u64, expiry: u64) -> Value {
+        json!({
+            "db": db,
+            "key": Escaped(&self.key).to_string(),
+            "type": self.val.type_name(),
+            "expiry": if expiry == 0 { None } else { Some(expiry) },
+            "value": self.val.to_json(),
+        })
+    }
+}
@@ -0,0 +1,343 @@
+// Filters over the keys of an RDB snapshot, roughly what `KEYS`/`SCAN ... MATCH ... TYPE` offer
+// on a live server, plus the things only a dump knows about (database, expiry, value size).
+use regex::bytes::Regex;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Read};
+
+use super::{AuxHeader, Database, Escaped, KVPair, RdbResult, Snapshot};
+
+#[derive(Debug, Clone, Copy)]
+pub enum TtlFilter {
+    /// Keys without an expiry
+    Persistent,
+    /// Keys with an expiry, whatever it is
+    Volatile,
+    /// Keys with an expiry at or before the given unix time in ms
+    ExpiredAt(u64),
+    /// Keys that still exist at the given unix time in ms
+    LiveAt(u64),
+}
+
+#[derive(Debug, Default)]
+pub struct Query {
+    /// Redis `KEYS` style glob
+    pub pattern: Option<String>,
+    pub regex: Option<Regex>,
+    /// As reported by `TYPE`
+    pub key_type: Option<String>,
+    pub db: Option<u64>,
+    pub ttl: Option<TtlFilter>,
+    pub min_size: Option<usize>,
+    pub max_size: Option<usize>,
+}
+
+impl TtlFilter {
+    fn matches(&self, expiry: u64) -> bool {
+        match *self {
+            TtlFilter::Persistent => expiry == 0,
+            TtlFilter::Volatile => expiry != 0,
+            TtlFilter::ExpiredAt(at) => expiry != 0 && expiry <= at,
+            TtlFilter::LiveAt(at) => expiry == 0 || expiry > at,
+        }
+    }
+}
+
+impl Query {
+    /// `expiry` is in unix ms, 0 meaning the key never expires.
+    pub(super) fn matches(&self, db: u64, entry: &KVPair, expiry: u64) -> bool {
+        if self.db.is_some_and(|id| id != db) {
+            return false;
+        }
+        if let Some(pattern) = &self.pattern {
+            if !glob_match(pattern.as_bytes(), &entry.key) {
+                return false;
+            }
+        }
+        if let Some(regex) = &self.regex {
+            if !regex.is_match(&entry.key) {
+                return false;
+            }
+        }
+        if let Some(key_type) = &self.key_type {
+            if key_type != entry.val.type_name() {
+                return false;
+            }
+        }
+        if self.ttl.is_some_and(|ttl| !ttl.matches(expiry)) {
+            return false;
+        }
+        let size = entry.val.size();
+        if self.min_size.is_some_and(|min| size < min) {
+            return false;
+        }
+        if self.max_size.is_some_and(|max| size > max) {
+            return false;
+        }
+        true
+    }
+
+    /// Parses the snapshot in `buf`, calling `f` for every matching entry as soon as it is read.
+    fn run<I, F>(&self, buf: I, mut f: F) -> RdbResult<AuxHeader>
+    where
+        I: Iterator<Item = u8>,
+        F: FnMut(u64, KVPair, u64),
+    {
+        Snapshot::stream(&mut buf.peekable(), |db, entry, expiry| {
+            if self.matches(db, &entry, expiry) {
+                f(db, entry, expiry);
+            }
+        })
+    }
+}
+
+/// Port of redis' `stringmatchlen`: `*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\` escapes.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // where to resume from when the last `*` has to swallow one more byte
+    let mut star: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    while p < pattern.len() && pattern[p] == b'*' {
+                        p += 1;
+                    }
+                    if p == pattern.len() {
+                        return true;
+                    }
+                    star = Some((p, s));
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    s += 1;
+                    continue;
+                }
+                b'[' => {
+                    let (matched, next) = match_class(pattern, p, string[s]);
+                    if matched {
+                        p = next;
+                        s += 1;
+                        continue;
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == string[s] {
+                        p += 2;
+                        s += 1;
+                        continue;
+                    }
+                }
+                c => {
+                    if c == string[s] {
+                        p += 1;
+                        s += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+        match star {
+            Some((star_p, star_s)) => {
+                p = star_p;
+                s = star_s + 1;
+                star = Some((star_p, s));
+            }
+            None => return false,
+        }
+    }
+    while p < pattern.len() && pattern[p] == b'*' {
+        p += 1;
+    }
+    p == pattern.len()
+}
+
+/// `start` points at the `[`; returns whether `c` is in the class and where the class ends.
+/// An unterminated class runs until the end of the pattern, like in redis.
+fn match_class(pattern: &[u8], start: usize, c: u8) -> (bool, usize) {
+    let mut i = start + 1;
+    let negate = i < pattern.len() && pattern[i] == b'^';
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    while i < pattern.len() {
+        if pattern[i] == b']' {
+            i += 1;
+            break;
+        } else if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' {
+            let (lo, hi) = if pattern[i] <= pattern[i + 2] {
+                (pattern[i], pattern[i + 2])
+            } else {
+                (pattern[i + 2], pattern[i])
+            };
+            matched |= lo <= c && c <= hi;
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+    (matched != negate, i)
+}
+
+const USAGE: &str =
+    "usage: redis-query <rdb file> [--match GLOB] [--regex RE] [--type TYPE] [--db N] \
+[--persistent] [--volatile] [--expired-at MS] [--live-at MS] [--min-size N] [--max-size N] \
+[--json] [--rdb OUT]";
+
+/// `redis-query` subcommand: prints `db type expiry size key` for every match, or one JSON object
+/// per match with `--json`. `--rdb` also writes the matches out as a new dump.
+pub fn main(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut args = args.iter();
+    let path = args.next().ok_or(USAGE)?;
+    let mut query = Query::default();
+    let mut json = false;
+    let mut rdb_out: Option<String> = None;
+    while let Some(flag) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", flag));
+        match flag.as_str() {
+            "--match" => query.pattern = Some(value()?.clone()),
+            "--regex" => query.regex = Some(Regex::new(value()?)?),
+            "--type" => query.key_type = Some(value()?.clone()),
+            "--db" => query.db = Some(value()?.parse()?),
+            "--persistent" => query.ttl = Some(TtlFilter::Persistent),
+            "--volatile" => query.ttl = Some(TtlFilter::Volatile),
+            "--expired-at" => query.ttl = Some(TtlFilter::ExpiredAt(value()?.parse()?)),
+            "--live-at" => query.ttl = Some(TtlFilter::LiveAt(value()?.parse()?)),
+            "--min-size" => query.min_size = Some(value()?.parse()?),
+            "--max-size" => query.max_size = Some(value()?.parse()?),
+            "--json" => json = true,
+            "--rdb" => rdb_out = Some(value()?.clone()),
+            _ => return Err(format!("unknown flag {}\n{}", flag, USAGE).into()),
+        }
+    }
+
+    let file = BufReader::new(File::open(path)?);
+    let mut dbs: Vec<Database> = vec![];
+    let header = query.run(file.bytes().map_while(Result::ok), |db, entry, expiry| {
+        if json {
+            println!("{}", entry.to_json(db, expiry));
+        } else {
+            println!(
+                "{}\t{}\t{}\t{}\t{}",
+                db,
+                entry.val.type_name(),
+                expiry,
+                entry.val.size(),
+                Escaped(&entry.key)
+            );
+        }
+        if rdb_out.is_some() {
+            if dbs.last().is_none_or(|d| d.id != db) {
+                dbs.push(Database {
+                    id: db,
+                    entries: vec![],
+                    expiries: vec![],
+                });
+            }
+            let d = dbs.last_mut().unwrap();
+            d.entries.push(entry);
+            d.expiries.push(expiry);
+        }
+    })?;
+    if let Some(out) = rdb_out {
+        std::fs::write(out, Snapshot { header, dbs }.to_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, string: &str) -> bool {
+        glob_match(pattern.as_bytes(), string.as_bytes())
+    }
+
+    #[test]
+    fn stars() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("*:session", "user:1:session"));
+        assert!(!matches("*:session", "user:1:sessions"));
+        assert!(matches("user:*:session", "user:1:session"));
+        assert!(matches("user:*:session", "user::session"));
+        // the star has to give back what it took to match the rest
+        assert!(matches("a*b*c", "abxbxc"));
+        assert!(!matches("a*b*c", "abxbxb"));
+        assert!(matches("user:*", "user:"));
+        assert!(!matches("user:*", "use"));
+        assert!(matches("a**b", "ab"));
+    }
+
+    #[test]
+    fn question_marks() {
+        assert!(matches("h?llo", "hello"));
+        assert!(matches("h?llo", "hallo"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(!matches("h?llo", "heello"));
+        assert!(matches("??", "ab"));
+        assert!(!matches("?", ""));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("h[ae]llo", "hello"));
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-c]llo", "hbllo"));
+        assert!(!matches("h[a-c]llo", "hdllo"));
+        assert!(matches("h[^a-c]llo", "hdllo"));
+        assert!(!matches("h[^a-c]llo", "hbllo"));
+        // as Redis has it, a range backwards is the same range
+        assert!(matches("h[c-a]llo", "hbllo"));
+        assert!(!matches("h[c-a]llo", "hdllo"));
+        assert!(matches("[*?]", "?"));
+        assert!(!matches("[*?]", "x"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(matches("a\\*b", "a*b"));
+        assert!(!matches("a\\*b", "axb"));
+        assert!(matches("\\?", "?"));
+        assert!(!matches("\\?", "x"));
+        assert!(matches("[\\]]", "]"));
+        assert!(matches("[\\^a]", "^"));
+        assert!(!matches("[\\^a]", "b"));
+        assert!(matches("[a\\-c]", "-"));
+        assert!(!matches("[a\\-c]", "b"));
+        // nothing left to escape, so it stands for itself
+        assert!(matches("a\\", "a\\"));
+    }
+
+    #[test]
+    fn unterminated_classes() {
+        // the class runs to the end of the pattern, as in Redis
+        assert!(matches("[ab", "a"));
+        assert!(matches("x[ab", "xb"));
+        assert!(!matches("x[ab", "xc"));
+        assert!(!matches("[", "["));
+        assert_eq!(match_class(b"[ab", 0, b'a'), (true, 3));
+        assert_eq!(match_class(b"[ab]c", 0, b'c'), (false, 4));
+    }
+
+    #[test]
+    fn bytes_that_arent_utf8() {
+        assert!(glob_match(b"\xff*", b"\xff\xfe\x00"));
+        assert!(glob_match(b"?\xfe", b"\xc3\xfe"));
+        assert!(glob_match(b"[\x80-\xff]", b"\xc3"));
+        assert!(!glob_match(b"[\x80-\xff]", b"a"));
+        // a byte at a time, so `?` doesn't take a whole UTF-8 character
+        assert!(!glob_match(b"?", "é".as_bytes()));
+        assert!(glob_match(b"??", "é".as_bytes()));
+    }
+}
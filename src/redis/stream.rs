@@ -0,0 +1,400 @@
+// Streams: a rax of listpacks plus the consumer group metadata.
+// https://github.com/redis/redis/blob/7.2/src/t_stream.c (layout of the listpack nodes)
+// https://github.com/redis/redis/blob/7.2/src/listpack.c
+// https://github.com/redis/redis/blob/7.2/src/rdb.c (rdbLoadObject, RDB_TYPE_STREAM_LISTPACKS*)
+use std::fmt;
+use std::iter::Peekable;
+
+use super::{
+    read_bytes, read_len, read_string, RdbError, RdbResult, RedisValue, ValueTypeEncoding,
+};
+
+/// Entry flags, stored as the first element of every entry in a node
+pub(super) const STREAM_ITEM_FLAG_DELETED: i64 = 1 << 0;
+pub(super) const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamEntry {
+    pub id: StreamId,
+    pub fields: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// An entry delivered to a consumer of the group but not acknowledged yet
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingEntry {
+    pub id: StreamId,
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Consumer {
+    pub name: Vec<u8>,
+    pub seen_time: u64,
+    /// Only saved since RDB_TYPE_STREAM_LISTPACKS_3
+    pub active_time: Option<u64>,
+    /// IDs of the group's pending entries owned by this consumer
+    pub pending: Vec<StreamId>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsumerGroup {
+    pub name: Vec<u8>,
+    pub last_id: StreamId,
+    /// Only saved since RDB_TYPE_STREAM_LISTPACKS_2
+    pub entries_read: Option<u64>,
+    pub pending: Vec<PendingEntry>,
+    pub consumers: Vec<Consumer>,
+}
+
+/// Fields only present since RDB_TYPE_STREAM_LISTPACKS_2
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamV2Meta {
+    pub first_id: StreamId,
+    pub max_deleted_id: StreamId,
+    pub entries_added: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stream {
+    /// Live (not deleted) entries, in ID order
+    pub entries: Vec<StreamEntry>,
+    pub length: u64,
+    pub last_id: StreamId,
+    pub v2: Option<StreamV2Meta>,
+    pub groups: Vec<ConsumerGroup>,
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+impl StreamId {
+    /// IDs are stored as 128 bit big endian numbers in rax keys and PELs
+    fn from_raw(raw: [u8; 16]) -> StreamId {
+        let (ms, seq) = raw.split_at(8);
+        StreamId {
+            ms: u64::from_be_bytes(ms.try_into().unwrap()),
+            seq: u64::from_be_bytes(seq.try_into().unwrap()),
+        }
+    }
+
+    pub(super) fn to_raw(self) -> Vec<u8> {
+        let mut raw = self.ms.to_be_bytes().to_vec();
+        raw.extend(self.seq.to_be_bytes());
+        raw
+    }
+
+    fn read<I>(buf: &mut Peekable<I>) -> RdbResult<StreamId>
+    where
+        I: Iterator<Item = u8>,
+    {
+        Ok(StreamId {
+            ms: read_len(buf)?,
+            seq: read_len(buf)?,
+        })
+    }
+
+    fn read_raw<I>(buf: &mut Peekable<I>) -> RdbResult<StreamId>
+    where
+        I: Iterator<Item = u8>,
+    {
+        Ok(StreamId::from_raw(read_bytes(buf)?))
+    }
+}
+
+fn read_millis<I>(buf: &mut Peekable<I>) -> RdbResult<u64>
+where
+    I: Iterator<Item = u8>,
+{
+    Ok(u64::from_le_bytes(read_bytes(buf)?))
+}
+
+/// Number of bytes used to store the length of an entry at its end, for backwards traversal
+pub(super) fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+/// `n` bytes of the listpack starting at `pos`
+fn lp_bytes(lp: &[u8], pos: usize, n: usize) -> RdbResult<&[u8]> {
+    lp.get(pos..pos.saturating_add(n))
+        .ok_or(RdbError::Truncated)
+}
+
+fn lp_byte(lp: &[u8], pos: usize) -> RdbResult<u8> {
+    lp.get(pos).copied().ok_or(RdbError::Truncated)
+}
+
+/// Decodes a whole listpack. Integers come back as `I64`, everything else as `STR`.
+pub(super) fn parse_listpack(lp: &[u8]) -> RdbResult<Vec<RedisValue>> {
+    let _total_bytes = u32::from_le_bytes(lp_bytes(lp, 0, 4)?.try_into().unwrap());
+    let _num_elements = u16::from_le_bytes(lp_bytes(lp, 4, 2)?.try_into().unwrap());
+    let mut pos = 6;
+    let mut ret = vec![];
+    while lp_byte(lp, pos)? != 0xFF {
+        let b = lp[pos];
+        let (value, len) = match b {
+            // 7 bit unsigned int
+            0x00..=0x7F => (RedisValue::I64(b as i64), 1),
+            // 6 bit string length
+            0x80..=0xBF => {
+                let len = (b & 0x3F) as usize;
+                (
+                    RedisValue::STR(lp_bytes(lp, pos + 1, len)?.to_vec()),
+                    1 + len,
+                )
+            }
+            // 13 bit signed int
+            0xC0..=0xDF => {
+                let v = (((b & 0x1F) as i64) << 8) | lp_byte(lp, pos + 1)? as i64;
+                let v = if v >= 1 << 12 { v - (1 << 13) } else { v };
+                (RedisValue::I64(v), 2)
+            }
+            // 12 bit string length
+            0xE0..=0xEF => {
+                let len = (((b & 0x0F) as usize) << 8) | lp_byte(lp, pos + 1)? as usize;
+                (
+                    RedisValue::STR(lp_bytes(lp, pos + 2, len)?.to_vec()),
+                    2 + len,
+                )
+            }
+            // 32 bit string length
+            0xF0 => {
+                let len = u32::from_le_bytes(lp_bytes(lp, pos + 1, 4)?.try_into().unwrap());
+                let len = len as usize;
+                (
+                    RedisValue::STR(lp_bytes(lp, pos + 5, len)?.to_vec()),
+                    5 + len,
+                )
+            }
+            0xF1 => {
+                let v = i16::from_le_bytes(lp_bytes(lp, pos + 1, 2)?.try_into().unwrap());
+                (RedisValue::I64(v as i64), 3)
+            }
+            0xF2 => {
+                // sign extend the 24 bits by going through the top of an i32
+                let mut bytes = [0; 4];
+                bytes[1..].copy_from_slice(lp_bytes(lp, pos + 1, 3)?);
+                (RedisValue::I64((i32::from_le_bytes(bytes) >> 8) as i64), 4)
+            }
+            0xF3 => {
+                let v = i32::from_le_bytes(lp_bytes(lp, pos + 1, 4)?.try_into().unwrap());
+                (RedisValue::I64(v as i64), 5)
+            }
+            0xF4 => {
+                let v = i64::from_le_bytes(lp_bytes(lp, pos + 1, 8)?.try_into().unwrap());
+                (RedisValue::I64(v), 9)
+            }
+            _ => {
+                return Err(RdbError::Malformed(format!(
+                    "listpack entry encoding {:#x}",
+                    b
+                )))
+            }
+        };
+        ret.push(value);
+        pos += len + backlen_size(len);
+    }
+    Ok(ret)
+}
+
+impl RedisValue {
+    fn as_int(&self) -> RdbResult<i64> {
+        match self {
+            RedisValue::I64(v) => Ok(*v),
+            RedisValue::STR(s) => std::str::from_utf8(s)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| RdbError::Malformed("stream node expected an integer".into())),
+            v => Err(RdbError::Malformed(format!(
+                "unexpected listpack value {:?}",
+                v
+            ))),
+        }
+    }
+
+    /// Stream fields and values are strings, even if the listpack stored them as integers
+    fn into_bytes(self) -> RdbResult<Vec<u8>> {
+        match self {
+            RedisValue::STR(s) => Ok(s),
+            RedisValue::I64(v) => Ok(v.to_string().into_bytes()),
+            v => Err(RdbError::Malformed(format!(
+                "unexpected listpack value {:?}",
+                v
+            ))),
+        }
+    }
+}
+
+/// Decodes the entries of a single rax node, whose key is the master ID
+fn parse_node(master_id: StreamId, lp: &[u8], entries: &mut Vec<StreamEntry>) -> RdbResult<()> {
+    let mut values = parse_listpack(lp)?.into_iter();
+    let mut next = || {
+        values
+            .next()
+            .ok_or_else(|| RdbError::Malformed("stream node ended early".into()))
+    };
+
+    let count = next()?.as_int()?;
+    let deleted = next()?.as_int()?;
+    let master_fields = (0..next()?.as_int()?)
+        .map(|_| next()?.into_bytes())
+        .collect::<RdbResult<Vec<Vec<u8>>>>()?;
+    if next()?.as_int()? != 0 {
+        return Err(RdbError::Malformed(
+            "stream master entry not terminated".into(),
+        ));
+    }
+
+    for _ in 0..count.saturating_add(deleted) {
+        let flags = next()?.as_int()?;
+        let id = StreamId {
+            ms: master_id.ms.wrapping_add(next()?.as_int()? as u64),
+            seq: master_id.seq.wrapping_add(next()?.as_int()? as u64),
+        };
+        let fields = if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            master_fields
+                .iter()
+                .map(|f| Ok((f.clone(), next()?.into_bytes()?)))
+                .collect::<RdbResult<_>>()?
+        } else {
+            (0..next()?.as_int()?)
+                .map(|_| Ok((next()?.into_bytes()?, next()?.into_bytes()?)))
+                .collect::<RdbResult<_>>()?
+        };
+        let _lp_count = next()?;
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            entries.push(StreamEntry { id, fields });
+        }
+    }
+    Ok(())
+}
+
+impl Stream {
+    pub(super) fn parse<I>(buf: &mut Peekable<I>, enc: ValueTypeEncoding) -> RdbResult<Stream>
+    where
+        I: Iterator<Item = u8>,
+    {
+        let version = match enc {
+            ValueTypeEncoding::StreamListpacks => 1,
+            ValueTypeEncoding::StreamListpacks2 => 2,
+            ValueTypeEncoding::StreamListpacks3 => 3,
+            e => panic!("{:?} is not a stream", e),
+        };
+
+        let mut entries = vec![];
+        for _ in 0..read_len(buf)? {
+            let node_key = read_string(buf)?;
+            let master_id = node_key
+                .try_into()
+                .map(StreamId::from_raw)
+                .map_err(|_| RdbError::Malformed("stream node key is not an ID".into()))?;
+            let lp = read_string(buf)?;
+            parse_node(master_id, &lp, &mut entries)?;
+        }
+
+        let length = read_len(buf)?;
+        let last_id = StreamId::read(buf)?;
+        let v2 = if version >= 2 {
+            Some(StreamV2Meta {
+                first_id: StreamId::read(buf)?,
+                max_deleted_id: StreamId::read(buf)?,
+                entries_added: read_len(buf)?,
+            })
+        } else {
+            None
+        };
+
+        let mut groups = vec![];
+        for _ in 0..read_len(buf)? {
+            let name = read_string(buf)?;
+            let last_id = StreamId::read(buf)?;
+            let entries_read = if version >= 2 {
+                Some(read_len(buf)?)
+            } else {
+                None
+            };
+            let pending = (0..read_len(buf)?)
+                .map(|_| {
+                    Ok(PendingEntry {
+                        id: StreamId::read_raw(buf)?,
+                        delivery_time: read_millis(buf)?,
+                        delivery_count: read_len(buf)?,
+                    })
+                })
+                .collect::<RdbResult<_>>()?;
+            let mut consumers = vec![];
+            for _ in 0..read_len(buf)? {
+                let name = read_string(buf)?;
+                let seen_time = read_millis(buf)?;
+                let active_time = if version >= 3 {
+                    Some(read_millis(buf)?)
+                } else {
+                    None
+                };
+                let pending = (0..read_len(buf)?)
+                    .map(|_| StreamId::read_raw(buf))
+                    .collect::<RdbResult<_>>()?;
+                consumers.push(Consumer {
+                    name,
+                    seen_time,
+                    active_time,
+                    pending,
+                });
+            }
+            groups.push(ConsumerGroup {
+                name,
+                last_id,
+                entries_read,
+                pending,
+                consumers,
+            });
+        }
+
+        Ok(Stream {
+            entries,
+            length,
+            last_id,
+            v2,
+            groups,
+        })
+    }
+
+    /// Oldest RDB stream encoding that can hold everything this stream knows about
+    pub(super) fn encoding(&self) -> ValueTypeEncoding {
+        let has_active_time = self
+            .groups
+            .iter()
+            .flat_map(|g| &g.consumers)
+            .any(|c| c.active_time.is_some());
+        if self.v2.is_none() {
+            ValueTypeEncoding::StreamListpacks
+        } else if has_active_time {
+            ValueTypeEncoding::StreamListpacks3
+        } else {
+            ValueTypeEncoding::StreamListpacks2
+        }
+    }
+
+    pub(super) fn size(&self) -> usize {
+        self.entries
+            .iter()
+            .flat_map(|e| &e.fields)
+            .map(|(f, v)| f.len() + v.len())
+            .sum()
+    }
+}
@@ -0,0 +1,355 @@
+// Serializes a `Snapshot` back into an RDB dump, the inverse of the parsers in
+// `redis.rs`. Strings are written byte for byte, so binary keys and values survive a round trip.
+use super::stream::{backlen_size, Stream, StreamEntry, StreamId, STREAM_ITEM_FLAG_SAMEFIELDS};
+use super::{
+    AuxHeader, Database, KVPair, KVVal, LengthEnc, OpCodes, RedisValue, Snapshot, SpecialEncoding,
+    ValueTypeEncoding,
+};
+
+/// Entries per rax node, redis' default `stream-node-max-entries`
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+fn write_length(out: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        out.push(((LengthEnc::SixBits as u8) << 6) | len as u8);
+    } else if len < 1 << 14 {
+        out.push(((LengthEnc::FourteenBits as u8) << 6) | (len >> 8) as u8);
+        out.push(len as u8);
+    } else if len <= u32::MAX as u64 {
+        out.push((LengthEnc::FourOrEightBytes as u8) << 6);
+        out.extend((len as u32).to_be_bytes());
+    } else {
+        out.push(((LengthEnc::FourOrEightBytes as u8) << 6) | 1);
+        out.extend(len.to_be_bytes());
+    }
+}
+
+fn write_special(out: &mut Vec<u8>, enc: SpecialEncoding) {
+    out.push(((LengthEnc::Encoded as u8) << 6) | enc as u8);
+}
+
+fn write_str(out: &mut Vec<u8>, s: &[u8]) {
+    write_length(out, s.len() as u64);
+    out.extend(s);
+}
+
+fn write_zl_entry(out: &mut Vec<u8>, prev_len: usize, v: &RedisValue) {
+    if prev_len < 254 {
+        out.push(prev_len as u8);
+    } else {
+        out.push(254);
+        out.extend((prev_len as u32).to_le_bytes());
+    }
+    match v {
+        RedisValue::STR(s) if s.len() < 1 << 6 => {
+            out.push(s.len() as u8);
+            out.extend(s);
+        }
+        RedisValue::STR(s) if s.len() < 1 << 14 => {
+            out.push(0b0100_0000 | (s.len() >> 8) as u8);
+            out.push(s.len() as u8);
+            out.extend(s);
+        }
+        RedisValue::STR(s) => {
+            out.push(0b1000_0000);
+            out.extend((s.len() as u32).to_be_bytes());
+            out.extend(s);
+        }
+        RedisValue::I8(v) => {
+            out.push(0xFE);
+            out.extend(v.to_le_bytes());
+        }
+        RedisValue::I16(v) => write_zl_entry_i16(out, *v),
+        RedisValue::I32(v) => write_zl_entry_i32(out, *v),
+        RedisValue::I64(v) => write_zl_entry_i64(out, *v),
+    }
+}
+
+fn write_zl_entry_i16(out: &mut Vec<u8>, v: i16) {
+    out.push(0b1100_0000);
+    out.extend(v.to_le_bytes());
+}
+
+fn write_zl_entry_i32(out: &mut Vec<u8>, v: i32) {
+    out.push(0b1101_0000);
+    out.extend(v.to_le_bytes());
+}
+
+fn write_zl_entry_i64(out: &mut Vec<u8>, v: i64) {
+    out.push(0b1110_0000);
+    out.extend(v.to_le_bytes());
+}
+
+fn ziplist_to_bytes(values: &[RedisValue]) -> Vec<u8> {
+    let mut entries = vec![];
+    let mut prev_len = 0;
+    let mut tail = 0;
+    for v in values {
+        tail = entries.len();
+        write_zl_entry(&mut entries, prev_len, v);
+        prev_len = entries.len() - tail;
+    }
+    // zlbytes + zltail + zllen
+    let header_len = 4 + 4 + 2;
+    let mut zl = Vec::with_capacity(header_len + entries.len() + 1);
+    zl.extend(((header_len + entries.len() + 1) as u32).to_le_bytes());
+    zl.extend(((header_len + tail) as u32).to_le_bytes());
+    zl.extend((values.len() as u16).to_le_bytes());
+    zl.extend(entries);
+    zl.push(0xFF);
+    zl
+}
+
+fn write_value(out: &mut Vec<u8>, val: &KVVal) {
+    match val {
+        KVVal::STR(s) => write_str(out, s),
+        KVVal::I8(v) => {
+            write_special(out, SpecialEncoding::INT8);
+            out.extend(v.to_le_bytes());
+        }
+        KVVal::I16(v) => {
+            write_special(out, SpecialEncoding::INT16);
+            out.extend(v.to_le_bytes());
+        }
+        KVVal::I32(v) => {
+            write_special(out, SpecialEncoding::INT32);
+            out.extend(v.to_le_bytes());
+        }
+        KVVal::LIST(l) => write_str(out, &ziplist_to_bytes(l)),
+        KVVal::STREAM(s) => write_stream(out, s),
+    }
+}
+
+fn write_lp_entry(lp: &mut Vec<u8>, encoded: &[u8]) {
+    lp.extend(encoded);
+    // backlen: the entry length in 7 bit groups, most significant first; all bytes but the
+    // first have the high bit set
+    let len = encoded.len();
+    let n = backlen_size(len);
+    for i in (0..n).rev() {
+        let group = ((len >> (7 * i)) & 0x7F) as u8;
+        lp.push(if i == n - 1 { group } else { group | 0x80 });
+    }
+}
+
+fn lp_int(v: i64) -> Vec<u8> {
+    if (0..=127).contains(&v) {
+        vec![v as u8]
+    } else if (-4096..4096).contains(&v) {
+        let v = (v as u16) & 0x1FFF;
+        vec![0xC0 | (v >> 8) as u8, v as u8]
+    } else if let Ok(v) = i16::try_from(v) {
+        let mut e = vec![0xF1];
+        e.extend(v.to_le_bytes());
+        e
+    } else if (-(1 << 23)..(1 << 23)).contains(&v) {
+        let mut e = vec![0xF2];
+        e.extend(&(v as i32).to_le_bytes()[..3]);
+        e
+    } else if let Ok(v) = i32::try_from(v) {
+        let mut e = vec![0xF3];
+        e.extend(v.to_le_bytes());
+        e
+    } else {
+        let mut e = vec![0xF4];
+        e.extend(v.to_le_bytes());
+        e
+    }
+}
+
+fn lp_str(s: &[u8]) -> Vec<u8> {
+    let mut e = if s.len() < 1 << 6 {
+        vec![0x80 | s.len() as u8]
+    } else if s.len() < 1 << 12 {
+        vec![0xE0 | (s.len() >> 8) as u8, s.len() as u8]
+    } else {
+        let mut e = vec![0xF0];
+        e.extend((s.len() as u32).to_le_bytes());
+        e
+    };
+    e.extend(s);
+    e
+}
+
+fn listpack_to_bytes(values: &[RedisValue]) -> Vec<u8> {
+    let mut entries = vec![];
+    for v in values {
+        match v {
+            RedisValue::STR(s) => write_lp_entry(&mut entries, &lp_str(s)),
+            RedisValue::I8(v) => write_lp_entry(&mut entries, &lp_int(*v as i64)),
+            RedisValue::I16(v) => write_lp_entry(&mut entries, &lp_int(*v as i64)),
+            RedisValue::I32(v) => write_lp_entry(&mut entries, &lp_int(*v as i64)),
+            RedisValue::I64(v) => write_lp_entry(&mut entries, &lp_int(*v)),
+        }
+    }
+    // total bytes + number of elements
+    let header_len = 4 + 2;
+    let mut lp = Vec::with_capacity(header_len + entries.len() + 1);
+    lp.extend(((header_len + entries.len() + 1) as u32).to_le_bytes());
+    lp.extend((values.len().min(u16::MAX as usize) as u16).to_le_bytes());
+    lp.extend(entries);
+    lp.push(0xFF);
+    lp
+}
+
+/// Lays out a rax node the way `streamAppendItem` does, with the first entry as master entry
+fn stream_node_to_bytes(entries: &[StreamEntry]) -> Vec<u8> {
+    let master = &entries[0];
+    let master_fields: Vec<&Vec<u8>> = master.fields.iter().map(|(f, _)| f).collect();
+
+    let mut values = vec![
+        RedisValue::I64(entries.len() as i64),
+        RedisValue::I64(0), // deleted
+        RedisValue::I64(master_fields.len() as i64),
+    ];
+    values.extend(master_fields.iter().map(|&f| RedisValue::STR(f.clone())));
+    values.push(RedisValue::I64(0)); // master entry terminator
+
+    for e in entries {
+        let same_fields = e.fields.len() == master_fields.len()
+            && e.fields
+                .iter()
+                .zip(&master_fields)
+                .all(|((f, _), &m)| f == m);
+        values.push(RedisValue::I64(if same_fields {
+            STREAM_ITEM_FLAG_SAMEFIELDS
+        } else {
+            0
+        }));
+        values.push(RedisValue::I64(e.id.ms.wrapping_sub(master.id.ms) as i64));
+        values.push(RedisValue::I64(e.id.seq.wrapping_sub(master.id.seq) as i64));
+        let lp_count = if same_fields {
+            values.extend(e.fields.iter().map(|(_, v)| RedisValue::STR(v.clone())));
+            e.fields.len() + 3
+        } else {
+            values.push(RedisValue::I64(e.fields.len() as i64));
+            for (f, v) in &e.fields {
+                values.push(RedisValue::STR(f.clone()));
+                values.push(RedisValue::STR(v.clone()));
+            }
+            e.fields.len() * 2 + 4
+        };
+        values.push(RedisValue::I64(lp_count as i64));
+    }
+    listpack_to_bytes(&values)
+}
+
+fn write_stream_id(out: &mut Vec<u8>, id: StreamId) {
+    write_length(out, id.ms);
+    write_length(out, id.seq);
+}
+
+fn write_stream(out: &mut Vec<u8>, s: &Stream) {
+    let enc = s.encoding();
+    let nodes: Vec<&[StreamEntry]> = s.entries.chunks(STREAM_NODE_MAX_ENTRIES).collect();
+    write_length(out, nodes.len() as u64);
+    for node in nodes {
+        write_str(out, &node[0].id.to_raw());
+        write_str(out, &stream_node_to_bytes(node));
+    }
+    write_length(out, s.length);
+    write_stream_id(out, s.last_id);
+    if let Some(v2) = &s.v2 {
+        write_stream_id(out, v2.first_id);
+        write_stream_id(out, v2.max_deleted_id);
+        write_length(out, v2.entries_added);
+    }
+    write_length(out, s.groups.len() as u64);
+    for g in &s.groups {
+        write_str(out, &g.name);
+        write_stream_id(out, g.last_id);
+        if s.v2.is_some() {
+            // redis saves SCG_INVALID_ENTRIES_READ (-1) when it doesn't know
+            write_length(out, g.entries_read.unwrap_or(u64::MAX));
+        }
+        write_length(out, g.pending.len() as u64);
+        for p in &g.pending {
+            out.extend(p.id.to_raw());
+            out.extend(p.delivery_time.to_le_bytes());
+            write_length(out, p.delivery_count);
+        }
+        write_length(out, g.consumers.len() as u64);
+        for c in &g.consumers {
+            write_str(out, &c.name);
+            out.extend(c.seen_time.to_le_bytes());
+            if let ValueTypeEncoding::StreamListpacks3 = enc {
+                out.extend(c.active_time.unwrap_or(c.seen_time).to_le_bytes());
+            }
+            write_length(out, c.pending.len() as u64);
+            for id in &c.pending {
+                out.extend(id.to_raw());
+            }
+        }
+    }
+}
+
+impl KVPair {
+    fn to_bytes(&self, out: &mut Vec<u8>) {
+        out.push(match &self.val {
+            KVVal::LIST(_) => ValueTypeEncoding::HashmapZe,
+            KVVal::STREAM(s) => s.encoding(),
+            _ => ValueTypeEncoding::STR,
+        } as u8);
+        write_str(out, &self.key);
+        write_value(out, &self.val);
+    }
+}
+
+impl AuxHeader {
+    fn to_bytes(&self, out: &mut Vec<u8>) {
+        for e in &self.entries {
+            out.push(OpCodes::Aux as u8);
+            write_str(out, &e.key);
+            write_value(out, &e.val);
+        }
+    }
+}
+
+impl Database {
+    fn to_bytes(&self, out: &mut Vec<u8>) {
+        out.push(OpCodes::SelectDB as u8);
+        write_length(out, self.id);
+        out.push(OpCodes::ResizeDB as u8);
+        write_length(out, self.entries.len() as u64);
+        write_length(
+            out,
+            self.expiries.iter().filter(|&&e| e != 0).count() as u64,
+        );
+        for (entry, &expiry) in self.entries.iter().zip(&self.expiries) {
+            if expiry != 0 {
+                out.push(OpCodes::ExpireTimeMs as u8);
+                out.extend(expiry.to_le_bytes());
+            }
+            entry.to_bytes(out);
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // newer stream encodings came with newer RDB versions
+        let version = self
+            .dbs
+            .iter()
+            .flat_map(|db| &db.entries)
+            .map(|e| match &e.val {
+                KVVal::STREAM(s) => match s.encoding() {
+                    ValueTypeEncoding::StreamListpacks3 => 11,
+                    ValueTypeEncoding::StreamListpacks2 => 10,
+                    _ => 9,
+                },
+                _ => 9,
+            })
+            .max()
+            .unwrap_or(9);
+        let mut out = format!("REDIS{:04}", version).into_bytes();
+        self.header.to_bytes(&mut out);
+        for db in &self.dbs {
+            db.to_bytes(&mut out);
+        }
+        out.push(OpCodes::EOF as u8);
+        // checksum, 0 means it was disabled when saving
+        out.extend(0u64.to_le_bytes());
+        out
+    }
+}
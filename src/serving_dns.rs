@@ -2,19 +2,63 @@
 // https://datatracker.ietf.org/doc/html/rfc1034
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::process;
 use std::str::FromStr;
-use std::time::Instant;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use std::{error::Error, net::UdpSocket};
 
+use native_tls::{Identity, TlsAcceptor};
 use serde::Deserialize;
 
+pub mod bench;
+pub mod client;
+mod cookie;
+mod dnssec;
+mod dnstap;
+mod doh;
+mod edns;
+mod resolver;
+mod rrl;
+mod stats;
+mod store;
+mod transfer;
+mod tsig;
+mod update;
+mod wire;
+mod zone;
+use cookie::Cookies;
+use dnssec::{Algorithm, Signer};
+use dnstap::Dnstap;
+use edns::{Edns, EdnsOption};
+use resolver::Resolver;
+use rrl::{Limits, RateLimiter, Verdict};
+use serde_json::json;
+use stats::Stats;
+use store::{name_key, Answer, Store};
+use transfer::Journal;
+use tsig::Signing;
+use wire::{data_as_sized_labels, Reader, WireError, Writer};
+
 #[derive(Deserialize, Debug)]
 struct Entry {
     name: String,
     #[serde(rename = "type")]
     etype: String,
     data: String,
+    #[serde(default = "default_ttl")]
+    ttl: u32,
+}
+
+fn default_ttl() -> u32 {
+    999
 }
 
 #[derive(Deserialize, Debug)]
@@ -22,19 +66,98 @@ struct ProblemData {
     records: Vec<Entry>,
 }
 
-#[derive(FromPrimitive, Debug, Clone, Copy)]
-enum QuestionClass {
-    IN = 1,
+/// 16 bit (or smaller) enums from the wire, where values we have no variant for still have
+/// to be carried around. Those are written as `$unknown` followed by the number, like RFC 3597's
+/// `TYPE1234` and `CLASS1234`.
+macro_rules! wire_enum {
+    ($repr:ty, $name:ident, $unknown:literal { $($variant:ident = $val:expr,)* }) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #[allow(clippy::upper_case_acronyms)]
+        enum $name {
+            $($variant,)*
+            Unknown($repr),
+        }
+
+        impl From<$repr> for $name {
+            fn from(v: $repr) -> $name {
+                match v {
+                    $($val => $name::$variant,)*
+                    v => $name::Unknown(v),
+                }
+            }
+        }
+
+        impl From<$name> for $repr {
+            fn from(v: $name) -> $repr {
+                match v {
+                    $($name::$variant => $val,)*
+                    $name::Unknown(v) => v,
+                }
+            }
+        }
+
+        /// The mnemonic, as used in master files
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    $($name::$variant => write!(f, "{}", stringify!($variant)),)*
+                    $name::Unknown(v) => write!(f, "{}{}", $unknown, v),
+                }
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = String;
+            fn from_str(s: &str) -> Result<$name, String> {
+                match s {
+                    $(stringify!($variant) => Ok($name::$variant),)*
+                    _ => s
+                        .strip_prefix($unknown)
+                        .and_then(|n| n.parse::<$repr>().ok())
+                        .map($name::from)
+                        .ok_or_else(|| format!("unknown {} {}", stringify!($name), s)),
+                }
+            }
+        }
+    };
 }
 
-#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq)]
-enum QuestionType {
+wire_enum!(u16, QuestionClass, "CLASS" {
+    IN = 1,
+    // only in UPDATE, and on TSIG records
+    // https://datatracker.ietf.org/doc/html/rfc2136#section-2.4
+    NONE = 254,
+    ANY = 255,
+});
+
+wire_enum!(u16, QuestionType, "TYPE" {
     A = 1,
+    NS = 2,
     CNAME = 5,
-    AAAA = 28,
-    RP = 17,
+    SOA = 6,
+    PTR = 12,
+    MX = 15,
     TXT = 16,
-}
+    RP = 17,
+    AAAA = 28,
+    SRV = 33,
+    OPT = 41,
+    DS = 43,
+    RRSIG = 46,
+    NSEC = 47,
+    DNSKEY = 48,
+    // only ever in NSEC type bitmaps, for names that don't exist
+    // https://datatracker.ietf.org/doc/html/rfc9824#section-3.2
+    NXNAME = 128,
+    // https://datatracker.ietf.org/doc/html/rfc8945
+    TSIG = 250,
+    // only in questions, asking for zone transfers
+    IXFR = 251,
+    AXFR = 252,
+    // every type, in questions and in UPDATE
+    ANY = 255,
+    CAA = 257,
+});
 
 #[derive(FromPrimitive, Debug, Clone, Copy)]
 enum QueryType {
@@ -42,98 +165,137 @@ enum QueryType {
     Reply,
 }
 
-#[derive(FromPrimitive, Debug, Clone, Copy)]
-enum Opcode {
+wire_enum!(u8, Opcode, "OPCODE" {
     Query = 0,
-    IQuery,
-    Status,
-}
+    IQuery = 1,
+    Status = 2,
+    // https://datatracker.ietf.org/doc/html/rfc1996
+    Notify = 4,
+    // https://datatracker.ietf.org/doc/html/rfc2136
+    Update = 5,
+});
 
-#[derive(FromPrimitive, Debug, Clone, Copy)]
-enum ResponseCode {
+wire_enum!(u16, ResponseCode, "RCODE" {
     NoError = 0,
-    FormatError,
-    ServFail,
-    NxDomain,
-    // ...
-}
+    FormatError = 1,
+    ServFail = 2,
+    NxDomain = 3,
+    NotImp = 4,
+    Refused = 5,
+    // the rest are for UPDATE, and for failed TSIG checks
+    YXDomain = 6,
+    YXRRSet = 7,
+    NXRRSet = 8,
+    NotAuth = 9,
+    NotZone = 10,
+    // needs EDNS, as it takes more than the 4 bits in the header
+    BadVers = 16,
+    // https://datatracker.ietf.org/doc/html/rfc7873#section-8
+    BadCookie = 23,
+});
 
 #[derive(Debug, Clone, Copy)]
 struct Flags {
-    #[allow(dead_code)]
     query_type: QueryType, // query (0) || reply (1)
-    opcode: Opcode, // query (0) || iquery (1) || status (2)
-    // authoritative_answer: bool,
-    #[allow(dead_code)]
+    opcode: Opcode,        // query (0) || iquery (1) || status (2)
+    authoritative_answer: bool,
     truncated: bool,
     recursion_desired: bool,
-    #[allow(dead_code)]
+    recursion_available: bool,
     ad_bit: bool,
-    #[allow(dead_code)]
     unauthenticated_ok: bool,
-    #[allow(dead_code)]
     response_code: ResponseCode,
 }
 
 impl Flags {
     fn from_bytes(b: &'_ [u8]) -> Flags {
         Flags {
-            query_type: QueryType::from_u8(b[0] & 0b1000_0000).unwrap(),
-            opcode: Opcode::from_u8(b[0] & 0b0111_1000).unwrap(),
-            // blank ?
+            query_type: QueryType::from_u8(b[0] >> 7).unwrap(),
+            opcode: Opcode::from((b[0] >> 3) & 0b1111),
+            authoritative_answer: b[0] & 0b0000_0100 != 0,
             truncated: b[0] & 0b0000_0010 != 0,
             recursion_desired: b[0] & 0b0000_0001 != 0,
-            // blank first 2 bits
+            recursion_available: b[1] & 0b1000_0000 != 0,
+            // blank Z bit
             ad_bit: b[1] & 0b0010_0000 != 0,
             unauthenticated_ok: b[1] & 0b0001_0000 != 0,
-            // not part of request
-            response_code: ResponseCode::from_u8(b[1] & 0b0000_1111).unwrap(),
+            response_code: ResponseCode::from((b[1] & 0b0000_1111) as u16),
+        }
+    }
+    fn to_bytes(self) -> Vec<u8> {
+        let v = ((self.query_type as u16) << 15)
+            | ((u8::from(self.opcode) as u16) << 11)
+            | ((self.authoritative_answer as u16) << 10)
+            | ((self.truncated as u16) << 9)
+            | ((self.recursion_desired as u16) << 8)
+            | ((self.recursion_available as u16) << 7)
+            | ((self.ad_bit as u16) << 5)
+            | ((self.unauthenticated_ok as u16) << 4)
+            | (u16::from(self.response_code) & 0b1111);
+        v.to_be_bytes().to_vec()
+    }
+    /// Flags of a standard query
+    fn query(recursion_desired: bool) -> Flags {
+        Flags {
+            query_type: QueryType::Query,
+            opcode: Opcode::Query,
+            authoritative_answer: false,
+            truncated: false,
+            recursion_desired,
+            recursion_available: false,
+            ad_bit: false,
+            unauthenticated_ok: false,
+            response_code: ResponseCode::NoError,
         }
     }
-    fn to_bytes(&self, r: ResponseCode) -> Vec<u8> {
-        let v = ((QueryType::Reply as u16) << 15) | ((self.opcode as u16) << 11) | // skip AA and truncation == always 0
-            ((self.recursion_desired as u16) << 8) |// << 8 is recursion available, but that's also 0
-            (r as u16);
-        // z is also 0
-        let res = v.to_be_bytes().to_vec();
-        res
+    /// Flags of the reply to a query with these flags
+    fn reply(&self, r: ResponseCode) -> Flags {
+        Flags {
+            query_type: QueryType::Reply,
+            opcode: self.opcode,
+            authoritative_answer: false,
+            truncated: false,
+            recursion_desired: self.recursion_desired,
+            recursion_available: false,
+            ad_bit: false,
+            unauthenticated_ok: false,
+            response_code: r,
+        }
     }
 }
 
 #[derive(Debug)]
-struct Message {
-    identification: u16, // TBD
+struct Header {
+    identification: u16,
     flags: Flags,
+}
+
+/// The section counts, which are only needed while parsing: when writing they are
+/// whatever the sections hold.
+struct Counts {
     question_len: u16,
     answer_len: u16,
-    #[allow(dead_code)]
     auth_rr_len: u16,
-    #[allow(dead_code)]
     additional_rr_len: u16,
 }
 
-impl Message {
-    fn from_bytes(b: &'_ [u8]) -> Message {
-        Message {
-            identification: u16::from_be_bytes([b[0], b[1]]),
-            flags: Flags::from_bytes(&b[2..4]),
-            question_len: u16::from_be_bytes([b[4], b[5]]),
-            answer_len: u16::from_be_bytes([b[6], b[7]]),
-            auth_rr_len: u16::from_be_bytes([b[8], b[9]]),
-            additional_rr_len: u16::from_be_bytes([b[10], b[11]]),
-        }
-    }
-    fn to_bytes(&self, r: ResponseCode) -> Vec<u8> {
-        let mut response: Vec<u8> = Vec::with_capacity(4);
-        response.append(&mut self.identification.to_be_bytes().to_vec());
-        response.append(&mut self.flags.to_bytes(r));
-        response.append(&mut self.question_len.to_be_bytes().to_vec());
-        response.append(&mut self.answer_len.to_be_bytes().to_vec());
-        response.push(0);
-        response.push(self.auth_rr_len as u8);
-        response.push(0);
-        response.push(self.additional_rr_len as u8);
-        response
+impl Header {
+    fn read(r: &mut Reader) -> Result<(Header, Counts), WireError> {
+        let identification = r.u16()?;
+        let flags = Flags::from_bytes(r.bytes(2)?);
+        let counts = Counts {
+            question_len: r.u16()?,
+            answer_len: r.u16()?,
+            auth_rr_len: r.u16()?,
+            additional_rr_len: r.u16()?,
+        };
+        Ok((
+            Header {
+                identification,
+                flags,
+            },
+            counts,
+        ))
     }
 }
 
@@ -144,97 +306,736 @@ struct Question {
     class: QuestionClass,
 }
 
-fn data_as_sized_labels(buf: &'_ str) -> Vec<u8> {
-    let mut ret = Vec::with_capacity(16);
-    for part in buf.split(".") {
-        let size = part.len() as u8;
-        if size > 0 {
-            ret.push(size);
-            ret.append(&mut part.as_bytes().to_vec());
-        }
+impl Question {
+    fn read(r: &mut Reader) -> Result<Question, WireError> {
+        Ok(Question {
+            domain: r.name()?,
+            qtype: QuestionType::from(r.u16()?),
+            class: QuestionClass::from(r.u16()?),
+        })
+    }
+    fn write(&self, w: &mut Writer) -> Result<(), WireError> {
+        w.name(&self.domain, true)?;
+        w.u16(self.qtype.into());
+        w.u16(self.class.into());
+        Ok(())
     }
-    ret.push(0);
-    ret
 }
-impl Question {
-    fn from_bytes(mut buf: &mut [u8]) -> Question {
-        let mut labels: Vec<String> = Vec::with_capacity(2);
-        loop {
-            let label_len: usize = buf[0] as usize;
-            if label_len == 0 {
-                break;
+
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+enum RData {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    CNAME(String),
+    NS(String),
+    PTR(String),
+    MX {
+        preference: u16,
+        exchange: String,
+    },
+    SOA {
+        mname: String,
+        /// The mailbox of whoever is responsible for the zone, `@` replaced by a dot
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        /// TTL of negative answers
+        minimum: u32,
+    },
+    // https://datatracker.ietf.org/doc/html/rfc2782
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    // https://datatracker.ietf.org/doc/html/rfc8659
+    CAA {
+        flags: u8,
+        tag: Vec<u8>,
+        value: Vec<u8>,
+    },
+    /// The character-strings, which are split in 255 byte pieces when written if longer
+    TXT(Vec<Vec<u8>>),
+    // https://datatracker.ietf.org/doc/html/rfc1183#section-2.2
+    RP {
+        /// The mailbox of whoever is responsible, `@` replaced by a dot
+        mbox: String,
+        /// Where TXT records with more about them are, or the root if nowhere
+        txt: String,
+    },
+    /// Only in the OPT pseudo-record, which `Message` turns into its `edns`
+    OPT(Vec<EdnsOption>),
+    // https://datatracker.ietf.org/doc/html/rfc4034#section-2
+    DNSKEY {
+        flags: u16,
+        /// Always 3
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+    },
+    // https://datatracker.ietf.org/doc/html/rfc4034#section-3
+    RRSIG {
+        type_covered: QuestionType,
+        algorithm: u8,
+        /// Labels of the owner, not counting a leading `*`
+        labels: u8,
+        original_ttl: u32,
+        /// Seconds since the epoch, modulo 2^32
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        signer: String,
+        signature: Vec<u8>,
+    },
+    // https://datatracker.ietf.org/doc/html/rfc4034#section-4
+    NSEC {
+        /// The next name in the zone, in canonical order
+        next: String,
+        /// The types at the owner
+        types: Vec<QuestionType>,
+    },
+    // https://datatracker.ietf.org/doc/html/rfc4034#section-5
+    DS {
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+    },
+    // https://datatracker.ietf.org/doc/html/rfc8945#section-4.2
+    TSIG {
+        algorithm: String,
+        /// Seconds since the epoch, in 48 bits
+        time_signed: u64,
+        /// How many seconds off `time_signed` can be
+        fudge: u16,
+        mac: Vec<u8>,
+        original_id: u16,
+        /// One of the TSIG errors, not an RCODE
+        error: u16,
+        other: Vec<u8>,
+    },
+    /// Anything we don't know the format of, as is. Also the empty data of records in UPDATE
+    /// that stand for whole RRsets.
+    Unknown(Vec<u8>),
+}
+
+impl RData {
+    fn read(r: &mut Reader, rtype: QuestionType, len: usize) -> Result<RData, WireError> {
+        let end = r.pos() + len;
+        let data = match rtype {
+            _ if len == 0 && rtype != QuestionType::OPT => RData::Unknown(vec![]),
+            QuestionType::A if len == 4 => {
+                RData::A(Ipv4Addr::from(<[u8; 4]>::try_from(r.bytes(4)?).unwrap()))
+            }
+            QuestionType::AAAA if len == 16 => {
+                RData::AAAA(Ipv6Addr::from(<[u8; 16]>::try_from(r.bytes(16)?).unwrap()))
+            }
+            QuestionType::CNAME => RData::CNAME(r.name()?),
+            QuestionType::NS => RData::NS(r.name()?),
+            QuestionType::PTR => RData::PTR(r.name()?),
+            QuestionType::MX => RData::MX {
+                preference: r.u16()?,
+                exchange: r.name()?,
+            },
+            QuestionType::SOA => RData::SOA {
+                mname: r.name()?,
+                rname: r.name()?,
+                serial: r.u32()?,
+                refresh: r.u32()?,
+                retry: r.u32()?,
+                expire: r.u32()?,
+                minimum: r.u32()?,
+            },
+            QuestionType::SRV => RData::SRV {
+                priority: r.u16()?,
+                weight: r.u16()?,
+                port: r.u16()?,
+                target: r.name()?,
+            },
+            QuestionType::CAA => {
+                let flags = r.u8()?;
+                let tag_len = r.u8()? as usize;
+                let tag = r.bytes(tag_len)?.to_vec();
+                let value = r.bytes(end.saturating_sub(r.pos()))?.to_vec();
+                RData::CAA { flags, tag, value }
+            }
+            QuestionType::OPT => RData::OPT(edns::read_options(r, len)?),
+            QuestionType::TXT => {
+                let mut strings = vec![];
+                while r.pos() < end {
+                    let len = r.u8()? as usize;
+                    strings.push(r.bytes(len)?.to_vec());
+                }
+                RData::TXT(strings)
+            }
+            QuestionType::RP => RData::RP {
+                mbox: r.name()?,
+                txt: r.name()?,
+            },
+            QuestionType::DNSKEY => RData::DNSKEY {
+                flags: r.u16()?,
+                protocol: r.u8()?,
+                algorithm: r.u8()?,
+                public_key: r.bytes(end.saturating_sub(r.pos()))?.to_vec(),
+            },
+            QuestionType::RRSIG => RData::RRSIG {
+                type_covered: QuestionType::from(r.u16()?),
+                algorithm: r.u8()?,
+                labels: r.u8()?,
+                original_ttl: r.u32()?,
+                expiration: r.u32()?,
+                inception: r.u32()?,
+                key_tag: r.u16()?,
+                signer: r.name()?,
+                signature: r.bytes(end.saturating_sub(r.pos()))?.to_vec(),
+            },
+            QuestionType::NSEC => RData::NSEC {
+                next: r.name()?,
+                types: dnssec::read_type_bitmap(r, end)?,
+            },
+            QuestionType::DS => RData::DS {
+                key_tag: r.u16()?,
+                algorithm: r.u8()?,
+                digest_type: r.u8()?,
+                digest: r.bytes(end.saturating_sub(r.pos()))?.to_vec(),
+            },
+            QuestionType::TSIG => {
+                let algorithm = r.name()?;
+                let time_signed = (r.u16()? as u64) << 32 | r.u32()? as u64;
+                let fudge = r.u16()?;
+                let mac_len = r.u16()? as usize;
+                let mac = r.bytes(mac_len)?.to_vec();
+                let original_id = r.u16()?;
+                let error = r.u16()?;
+                let other_len = r.u16()? as usize;
+                RData::TSIG {
+                    algorithm,
+                    time_signed,
+                    fudge,
+                    mac,
+                    original_id,
+                    error,
+                    other: r.bytes(other_len)?.to_vec(),
+                }
+            }
+            _ => RData::Unknown(r.bytes(len)?.to_vec()),
+        };
+        if r.pos() != end {
+            return Err(WireError::Malformed(format!(
+                "{:?} data is not {} bytes long",
+                rtype, len
+            )));
+        }
+        Ok(data)
+    }
+    /// The data in canonical form, names lowercased and uncompressed, to compare and sign
+    fn to_canonical(&self) -> Result<Vec<u8>, WireError> {
+        let mut w = Writer::canonical();
+        self.write(&mut w)?;
+        Ok(w.into_bytes())
+    }
+    fn write(&self, w: &mut Writer) -> Result<(), WireError> {
+        match self {
+            RData::A(ip) => w.bytes(&ip.octets()),
+            RData::AAAA(ip) => w.bytes(&ip.octets()),
+            RData::CNAME(name) | RData::NS(name) | RData::PTR(name) => w.name(name, true)?,
+            RData::MX {
+                preference,
+                exchange,
+            } => {
+                w.u16(*preference);
+                w.name(exchange, true)?;
+            }
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                w.name(mname, true)?;
+                w.name(rname, true)?;
+                for v in [serial, refresh, retry, expire, minimum] {
+                    w.u32(*v);
+                }
+            }
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                w.u16(*priority);
+                w.u16(*weight);
+                w.u16(*port);
+                // RFC 2782 forbids compressing the target
+                w.name(target, false)?;
+            }
+            RData::CAA { flags, tag, value } => {
+                w.u8(*flags);
+                w.u8(tag.len() as u8);
+                w.bytes(tag);
+                w.bytes(value);
             }
-            let end: usize = label_len as usize + 1;
-            let label = &buf[1..end];
-            labels.push(String::from_utf8(label.to_vec()).unwrap());
-            buf = &mut buf[label_len + 1..];
+            RData::TXT(strings) => {
+                for s in strings {
+                    if s.is_empty() {
+                        w.u8(0);
+                    }
+                    for piece in s.chunks(255) {
+                        w.u8(piece.len() as u8);
+                        w.bytes(piece);
+                    }
+                }
+            }
+            RData::RP { mbox, txt } => {
+                w.name(mbox, true)?;
+                w.name(txt, true)?;
+            }
+            RData::OPT(options) => edns::write_options(options, w),
+            RData::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+            } => {
+                w.u16(*flags);
+                w.u8(*protocol);
+                w.u8(*algorithm);
+                w.bytes(public_key);
+            }
+            RData::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer,
+                signature,
+            } => {
+                w.u16(u16::from(*type_covered));
+                w.u8(*algorithm);
+                w.u8(*labels);
+                w.u32(*original_ttl);
+                w.u32(*expiration);
+                w.u32(*inception);
+                w.u16(*key_tag);
+                // RFC 4034 forbids compressing the names in DNSSEC records
+                w.name(signer, false)?;
+                w.bytes(signature);
+            }
+            RData::NSEC { next, types } => {
+                w.name(next, false)?;
+                dnssec::write_type_bitmap(types, w);
+            }
+            RData::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+            } => {
+                w.u16(*key_tag);
+                w.u8(*algorithm);
+                w.u8(*digest_type);
+                w.bytes(digest);
+            }
+            RData::TSIG {
+                algorithm,
+                time_signed,
+                fudge,
+                mac,
+                original_id,
+                error,
+                other,
+            } => {
+                w.name(algorithm, false)?;
+                w.u16((time_signed >> 32) as u16);
+                w.u32(*time_signed as u32);
+                w.u16(*fudge);
+                w.u16(mac.len() as u16);
+                w.bytes(mac);
+                w.u16(*original_id);
+                w.u16(*error);
+                w.u16(other.len() as u16);
+                w.bytes(other);
+            }
+            RData::Unknown(data) => w.bytes(data),
         }
+        Ok(())
+    }
+}
 
-        buf = &mut buf[1..];
-        let qtype = QuestionType::from_u16(u16::from_be_bytes([buf[0], buf[1]])).unwrap();
-        let class = QuestionClass::from_u16(u16::from_be_bytes([buf[2], buf[3]])).unwrap();
-        // FIXME: should run this but doesn't mutate outside buffer
-        // buf = &mut buf[4..];
+/// A name in presentation format, absolute
+fn fqdn(name: &str) -> String {
+    format!("{}.", name)
+}
 
-        Question {
-            domain: labels.join("."),
-            qtype,
-            class,
+/// A character-string in presentation format: quoted, with quotes, backslashes and anything
+/// that isn't printable ASCII escaped
+fn quoted(s: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &c in s {
+        match c {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(c as char);
+            }
+            0x20..=0x7E => out.push(c as char),
+            _ => out.push_str(&format!("\\{:03}", c)),
         }
     }
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut response = Vec::with_capacity(16);
-        for label in self.domain.split(".") {
-            response.push(label.len() as u8);
-            response.append(&mut label.as_bytes().to_vec());
+    out.push('"');
+    out
+}
+
+fn hex(b: &[u8]) -> String {
+    b.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// The data as in master files. Types we don't know, and data we couldn't make sense of, in
+/// RFC 3597 generic format.
+impl fmt::Display for RData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RData::A(ip) => write!(f, "{}", ip),
+            RData::AAAA(ip) => write!(f, "{}", ip),
+            RData::CNAME(name) | RData::NS(name) | RData::PTR(name) => {
+                write!(f, "{}", fqdn(name))
+            }
+            RData::MX {
+                preference,
+                exchange,
+            } => write!(f, "{} {}", preference, fqdn(exchange)),
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                fqdn(mname),
+                fqdn(rname),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => write!(f, "{} {} {} {}", priority, weight, port, fqdn(target)),
+            RData::CAA { flags, tag, value } => write!(
+                f,
+                "{} {} {}",
+                flags,
+                String::from_utf8_lossy(tag),
+                quoted(value)
+            ),
+            RData::TXT(strings) => {
+                let strings: Vec<String> = strings.iter().map(|s| quoted(s)).collect();
+                write!(f, "{}", strings.join(" "))
+            }
+            RData::RP { mbox, txt } => write!(f, "{} {}", fqdn(mbox), fqdn(txt)),
+            RData::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+            } => write!(
+                f,
+                "{} {} {} {}",
+                flags,
+                protocol,
+                algorithm,
+                base64::encode(public_key)
+            ),
+            // times as seconds since the epoch, which RFC 4034 allows besides YYYYMMDDHHmmSS
+            RData::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer,
+                signature,
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {} {} {}",
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                fqdn(signer),
+                base64::encode(signature)
+            ),
+            RData::NSEC { next, types } => {
+                write!(f, "{}", fqdn(next))?;
+                for t in types {
+                    write!(f, " {}", t)?;
+                }
+                Ok(())
+            }
+            RData::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+            } => write!(
+                f,
+                "{} {} {} {}",
+                key_tag,
+                algorithm,
+                digest_type,
+                hex(digest)
+            ),
+            // as dig has it, as TSIG never goes in master files
+            RData::TSIG {
+                algorithm,
+                time_signed,
+                fudge,
+                mac,
+                original_id,
+                error,
+                other,
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {} {} {}",
+                fqdn(algorithm),
+                time_signed,
+                fudge,
+                mac.len(),
+                base64::encode(mac),
+                original_id,
+                error,
+                other.len(),
+                hex(other)
+            ),
+            RData::OPT(_) | RData::Unknown(_) => {
+                let mut w = Writer::new();
+                // only names can fail to encode, and neither has any
+                let _ = self.write(&mut w);
+                let data = w.into_bytes();
+                write!(f, "\\# {}", data.len())?;
+                if !data.is_empty() {
+                    write!(f, " {}", hex(&data))?;
+                }
+                Ok(())
+            }
         }
-        response.push(0); // end
-        response.append(&mut (self.qtype as u16).to_be_bytes().to_vec());
-        response.append(&mut (self.class as u16).to_be_bytes().to_vec());
-        response
     }
 }
 
-#[derive(Debug)]
-struct Answer {
+#[derive(Debug, Clone)]
+struct Record {
     name: String,
-    atype: QuestionType,
+    rtype: QuestionType,
     class: QuestionClass,
     ttl: u32,
-    data: Vec<u8>,
-    glob: bool,
-}
-
-impl Answer {
-    fn to_bytes(&self, m: &'_ Message, q: &'_ Question, r: ResponseCode) -> Vec<u8> {
-        let mut response: Vec<u8> = Vec::with_capacity(16);
-        response.append(&mut m.to_bytes(r));
-        response.append(&mut q.to_bytes());
-        // TODO send message ))
-        response.push(0xc0); // marker for offset ??
-        response.push(0x0c); // hardcoded 12 bytes from start, points to the first name on the query
-                             // these are _not_ necessarily correct
-        response.append(&mut (self.atype as u16).to_be_bytes().to_vec());
-        response.append(&mut (self.class as u16).to_be_bytes().to_vec());
-        response.append(&mut self.ttl.to_be_bytes().to_vec());
-        if q.qtype == QuestionType::TXT {
-            // data len
-            response.append(&mut u16::to_be_bytes((self.data.len() + 1) as u16).to_vec());
-            // txt len TODO: deal with >255 len
-            response.push(self.data.len() as u8);
-            response.append(self.data.clone().as_mut());
-        } else if q.qtype == QuestionType::RP {
-            let mut email = data_as_sized_labels("");
-            let mut data =
-                data_as_sized_labels(String::from_utf8_lossy(&self.data).to_owned().as_ref());
-            response.append(&mut u16::to_be_bytes(email.len() as u16 + data.len() as u16).to_vec());
-            response.append(&mut email);
-            response.append(&mut data);
-        } else {
-            response.append(&mut u16::to_be_bytes(self.data.len() as u16).to_vec());
-            response.append(self.data.clone().as_mut());
+    data: RData,
+}
+
+impl Record {
+    fn read(r: &mut Reader) -> Result<Record, WireError> {
+        let name = r.name()?;
+        let rtype = QuestionType::from(r.u16()?);
+        let class = QuestionClass::from(r.u16()?);
+        let ttl = r.u32()?;
+        let len = r.u16()? as usize;
+        Ok(Record {
+            name,
+            rtype,
+            class,
+            ttl,
+            data: RData::read(r, rtype, len)?,
+        })
+    }
+    fn write(&self, w: &mut Writer) -> Result<(), WireError> {
+        w.name(&self.name, true)?;
+        w.u16(self.rtype.into());
+        w.u16(self.class.into());
+        w.u32(self.ttl);
+        let len_pos = w.len();
+        w.u16(0); // data len, known once the data is written
+        self.data.write(w)?;
+        let len = u16::try_from(w.len() - len_pos - 2)
+            .map_err(|_| WireError::Malformed(format!("{} data too long", self.rtype)))?;
+        w.set_u16(len_pos, len);
+        Ok(())
+    }
+}
+
+/// A master file line, every field explicit
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            fqdn(&self.name),
+            self.ttl,
+            self.class,
+            self.rtype,
+            self.data
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Message {
+    header: Header,
+    questions: Vec<Question>,
+    answers: Vec<Record>,
+    authorities: Vec<Record>,
+    additionals: Vec<Record>,
+    /// The OPT pseudo-record, which isn't in `additionals` but goes after them on the wire
+    edns: Option<Edns>,
+    /// The TSIG record of a message we got, which has to be the last one, and how many bytes
+    /// came before it, as its MAC covers them. We sign what we send separately.
+    tsig: Option<(Record, usize)>,
+}
+
+impl Message {
+    fn from_bytes(b: &'_ [u8]) -> Result<Message, WireError> {
+        let r = &mut Reader::new(b);
+        let (mut header, counts) = Header::read(r)?;
+        // every entry takes a few bytes at least, so these can't allocate much on bogus counts
+        let questions = (0..counts.question_len)
+            .map(|_| Question::read(r))
+            .collect::<Result<_, _>>()?;
+        let mut records = |n| (0..n).map(|_| Record::read(r)).collect::<Result<_, _>>();
+        let answers = records(counts.answer_len)?;
+        let authorities = records(counts.auth_rr_len)?;
+        let mut additionals: Vec<Record> = vec![];
+        let mut last_start = 0;
+        for _ in 0..counts.additional_rr_len {
+            last_start = r.pos();
+            additionals.push(Record::read(r)?);
+        }
+
+        let mut tsig = None;
+        if let Some(i) = additionals
+            .iter()
+            .position(|a| a.rtype == QuestionType::TSIG)
+        {
+            // https://datatracker.ietf.org/doc/html/rfc8945#section-5.1
+            if i != additionals.len() - 1 {
+                return Err(WireError::Malformed(
+                    "TSIG is not the last record".to_string(),
+                ));
+            }
+            tsig = Some((additionals.remove(i), last_start));
+        }
+
+        let mut edns = None;
+        if let Some(i) = additionals
+            .iter()
+            .position(|a| a.rtype == QuestionType::OPT)
+        {
+            let opt = additionals.remove(i);
+            // there can only be one, owned by the root
+            // https://datatracker.ietf.org/doc/html/rfc6891#section-6.1.1
+            if !opt.name.is_empty() || additionals.iter().any(|a| a.rtype == QuestionType::OPT) {
+                return Err(WireError::Malformed("bad OPT record".to_string()));
+            }
+            let RData::OPT(options) = opt.data else {
+                unreachable!("OPT without options")
+            };
+            let e = Edns::from_record(opt.class.into(), opt.ttl, options);
+            let rcode = u16::from(header.flags.response_code) | (e.extended_rcode as u16) << 4;
+            header.flags.response_code = ResponseCode::from(rcode);
+            edns = Some(e);
+        }
+        Ok(Message {
+            header,
+            questions,
+            answers,
+            authorities,
+            additionals,
+            edns,
+            tsig,
+        })
+    }
+    fn to_bytes(&self) -> Result<Vec<u8>, WireError> {
+        let mut w = Writer::new();
+        w.u16(self.header.identification);
+        w.bytes(&self.header.flags.to_bytes());
+        w.u16(self.questions.len() as u16);
+        w.u16(self.answers.len() as u16);
+        w.u16(self.authorities.len() as u16);
+        w.u16((self.additionals.len() + self.edns.is_some() as usize) as u16);
+        for q in &self.questions {
+            q.write(&mut w)?;
+        }
+        for r in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            r.write(&mut w)?;
+        }
+        if let Some(edns) = &self.edns {
+            let mut edns = edns.clone();
+            edns.extended_rcode = (u16::from(self.header.flags.response_code) >> 4) as u8;
+            let opt = Record {
+                name: String::new(),
+                rtype: QuestionType::OPT,
+                class: QuestionClass::from(edns.payload_size),
+                ttl: edns.ttl(),
+                data: RData::OPT(edns.options),
+            };
+            opt.write(&mut w)?;
+        }
+        Ok(w.into_bytes())
+    }
+    /// A standard query with a single question
+    fn query(identification: u16, question: Question) -> Message {
+        Message {
+            header: Header {
+                identification,
+                flags: Flags::query(false),
+            },
+            questions: vec![question],
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+            edns: None,
+            tsig: None,
+        }
+    }
+    /// A reply with no records yet, echoing the question, and with EDNS if the query had it
+    fn reply(&self, r: ResponseCode) -> Message {
+        Message {
+            header: Header {
+                identification: self.header.identification,
+                flags: self.header.flags.reply(r),
+            },
+            questions: self.questions.clone(),
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+            edns: self.edns.as_ref().map(Edns::reply),
+            tsig: None,
         }
-        response
     }
 }
 
@@ -244,41 +1045,17 @@ pub fn solve(parsed_data: String, url: String) -> Result<String, Box<dyn Error>>
 
     for e in json_data.records {
         println!("{:?}", e);
-        let glob = e.name.clone().starts_with("*");
-        answers.push(Answer {
-            name: if glob {
-                // drop the `*.` prefix
-                e.name.clone()[2..].to_owned()
-            } else {
-                e.name.clone()
-            },
-            data: match e.etype.as_str() {
-                "A" => Ipv4Addr::from_str(e.data.as_str())
-                    .unwrap()
-                    .octets()
-                    .to_vec(),
-                "AAAA" => Ipv6Addr::from_str(e.data.as_str())
-                    .unwrap()
-                    .octets()
-                    .to_vec(),
-                "RP" => e.data.into_bytes(),
-                "TXT" => e.data.into_bytes(),
-                _ => panic!(),
-            },
-            atype: match e.etype.as_str() {
-                "A" => QuestionType::A,
-                "AAAA" => QuestionType::AAAA,
-                "RP" => QuestionType::RP,
-                "TXT" => QuestionType::TXT,
-                _ => panic!(),
-            },
-            class: QuestionClass::IN,
-            ttl: 999,
-            glob,
-        });
+        let atype = QuestionType::from_str(&e.etype)?;
+        data_as_sized_labels(&e.name).map_err(|err| format!("{}: {}", e.name, err))?;
+        let data = match atype {
+            // given as is, rather than in master file syntax
+            QuestionType::TXT => RData::TXT(vec![e.data.into_bytes()]),
+            _ => zone::rdata(atype, &e.data)
+                .map_err(|err| format!("{} {}: {}", e.name, atype, err))?,
+        };
+        answers.push(Answer::new(e.name, atype, QuestionClass::IN, e.ttl, data));
     }
 
-    let socket = UdpSocket::bind("0.0.0.0:15353")?;
     /*
     let handler = thread::spawn(move || {
         let r = crate::submit_result(
@@ -290,53 +1067,1068 @@ pub fn solve(parsed_data: String, url: String) -> Result<String, Box<dyn Error>>
         println!("{:?}", r.unwrap().into_string());
     });
     */
-    loop {
-        println!("Waiting for DNS req..");
-        let mut buf = [0; 1440];
-        let (_, addr) = socket.recv_from(&mut buf)?;
-        let start = Instant::now();
-        //println!("{:?}", &addr);
-        //println!("{:?} {}", &buf[..len], len);
-        let mut message = Message::from_bytes(&buf[..12]);
-        message.additional_rr_len = 0;
-        message.answer_len = 1;
+    Server::new(
+        Store::new(answers),
+        None,
+        None,
+        None,
+        None,
+        Options::default(),
+    )
+    .serve()
+}
+
+/// A master file to serve, and the origin it starts with
+#[derive(Debug)]
+struct ZoneFile {
+    path: String,
+    origin: String,
+}
+
+/// The records in `files`
+fn load_zones(files: &[ZoneFile]) -> Result<Vec<Answer>, String> {
+    let mut answers = vec![];
+    for file in files {
+        let text = fs::read_to_string(&file.path).map_err(|e| format!("{}: {}", file.path, e))?;
+        let zone = zone::parse(&text, &file.origin).map_err(|e| format!("{}: {}", file.path, e))?;
+        answers.extend(zone);
+    }
+    Ok(answers)
+}
+
+#[derive(Debug)]
+struct Options {
+    /// Address to bind, for both UDP and TCP
+    listen: String,
+    /// Rotate the records of every RRset by one on each response, so clients that just take the
+    /// first record spread over all of them
+    round_robin: bool,
+    /// How many threads answer UDP queries
+    threads: usize,
+    /// Where the records come from, if from master files
+    zones: Vec<ZoneFile>,
+    /// How often to check whether the zone files changed, to serve them again if they did
+    reload: Option<Duration>,
+    /// Secondaries to send NOTIFY to when a zone changes
+    notify: Vec<String>,
+    /// Keys to sign messages with, and the only way to get UPDATEs accepted
+    tsig_keys: Vec<tsig::Key>,
+    /// Where the root servers are, to resolve names outside our zones for clients that ask
+    root_hints: Option<String>,
+    /// The port servers are asked on when resolving
+    upstream_port: u16,
+    /// Clients that get recursion. Only this host's own if none.
+    recursion_clients: Vec<Network>,
+    /// Where to take DNS over TLS and DNS over HTTPS, with the certificate and key in PEM files
+    dot: Option<String>,
+    doh: Option<String>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    /// Print a line of JSON for every query answered
+    query_log: bool,
+    /// Where to serve the counters of the queries answered, over HTTP
+    stats: Option<String>,
+    /// Where to send dnstap, a file or `unix:PATH`
+    dnstap: Option<String>,
+    /// How many UDP replies clients get before they are dropped or slipped, if there's a limit
+    rate_limit: Option<Limits>,
+    /// Makes the server cookies, so servers sharing it accept each other's
+    cookie_secret: Option<[u8; 16]>,
+    /// UDP queries without a server cookie of ours get BADCOOKIE, or TC if they have no cookie
+    /// at all, rather than an answer
+    require_cookies: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            listen: "0.0.0.0:15353".to_string(),
+            round_robin: false,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            zones: vec![],
+            reload: None,
+            notify: vec![],
+            tsig_keys: vec![],
+            root_hints: None,
+            upstream_port: client::DNS_PORT,
+            recursion_clients: vec![],
+            dot: None,
+            doh: None,
+            tls_cert: None,
+            tls_key: None,
+            query_log: false,
+            stats: None,
+            dnstap: None,
+            rate_limit: None,
+            cookie_secret: None,
+            require_cookies: false,
+        }
+    }
+}
+
+const USAGE: &str =
+    "usage: dns-serve --zone FILE... [--origin NAME] [--listen ADDR] [--round-robin] [--threads N]
+                 [--dnssec KEYDIR [--dnssec-algorithm ed25519|p256]] [--reload SECS]
+                 [--notify ADDR]... [--tsig-key NAME:SECRET]...
+                 [--root-hints FILE [--allow-recursion NET]... [--upstream-port PORT]]
+                 [--tls-cert FILE --tls-key FILE [--dot ADDR] [--doh ADDR]]
+                 [--query-log] [--stats ADDR] [--dnstap FILE|unix:PATH]
+                 [--rrl RATE [--rrl-slip N] [--rrl-prefix V4/V6]]
+                 [--cookie-secret HEX] [--require-cookies]
+--origin applies to the --zone files after it, until they set their own $ORIGIN
+--dnssec signs every zone with a key from KEYDIR, generated if missing, and prints their DS
+--reload checks the zone files for changes every SECS seconds, serving them again if there are
+--notify sends NOTIFY to ADDR when a zone changes, for secondaries to transfer it over TCP.
+         Only they, and TSIG signed requests, get zone transfers
+--tsig-key takes UPDATEs signed with the key, its secret in base64 (HMAC-SHA256)
+--root-hints resolves names outside our zones for clients that ask for recursion, starting from
+             the root servers in FILE, a master file like named.root. No --zone is needed then
+--allow-recursion lets clients in NET, an address or ADDR/PREFIX, have recursion. Only this host
+                  gets it unless given, the rest only get answers from our zones
+--upstream-port asks other servers on PORT rather than 53 when resolving, for testing
+--dot takes DNS over TLS at ADDR, port 853 if it has none
+--doh takes DNS over HTTPS at https://ADDR/dns-query, port 443 if it has none
+--tls-cert and --tls-key are the PEM certificate chain and PKCS #8 key for both
+--query-log prints the client, name, type, response code and latency of every query as JSON
+--stats serves counters of the queries by protocol, type and response code at http://ADDR/stats
+--dnstap writes every query and reply to FILE as dnstap, or to a collector listening on PATH
+--rrl limits UDP replies to RATE a second for each client network, name and response code.
+      Every Nth reply over it (2 by default, 0 for none) goes out truncated, the rest are
+      dropped. Networks are /24 and /56 unless --rrl-prefix says otherwise. Clients with a
+      valid cookie aren't limited
+--cookie-secret is 16 bytes of hex making the server cookies, for servers that have to accept
+                each other's. Random otherwise
+--require-cookies only answers UDP queries with a server cookie of ours, sending BADCOOKIE or
+                  TC to the rest";
+
+/// `dns-serve` subcommand: serves the records from the given master files
+pub fn main(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut options = Options::default();
+    let mut origin = String::new();
+    let mut key_dir = None;
+    let mut algorithm = Algorithm::Ed25519;
+    let mut limits = Limits {
+        rate: 0,
+        slip: 2,
+        ipv4_prefix: 24,
+        ipv6_prefix: 56,
+    };
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", flag));
+        match flag.as_str() {
+            "--zone" => options.zones.push(ZoneFile {
+                path: value()?.clone(),
+                origin: origin.clone(),
+            }),
+            "--origin" => origin = value()?.clone(),
+            "--listen" => options.listen = value()?.clone(),
+            "--round-robin" => options.round_robin = true,
+            "--threads" => options.threads = value()?.parse()?,
+            "--dnssec" => key_dir = Some(value()?.clone()),
+            "--dnssec-algorithm" => algorithm = value()?.parse()?,
+            "--reload" => options.reload = Some(Duration::from_secs(value()?.parse()?)),
+            "--notify" => options.notify.push(value()?.clone()),
+            "--tsig-key" => options.tsig_keys.push(value()?.parse()?),
+            "--root-hints" => options.root_hints = Some(value()?.clone()),
+            "--allow-recursion" => options.recursion_clients.push(value()?.parse()?),
+            "--upstream-port" => options.upstream_port = value()?.parse()?,
+            "--dot" => options.dot = Some(with_port(value()?, DOT_PORT)),
+            "--doh" => options.doh = Some(with_port(value()?, DOH_PORT)),
+            "--tls-cert" => options.tls_cert = Some(value()?.clone()),
+            "--tls-key" => options.tls_key = Some(value()?.clone()),
+            "--query-log" => options.query_log = true,
+            "--stats" => options.stats = Some(value()?.clone()),
+            "--dnstap" => options.dnstap = Some(value()?.clone()),
+            "--rrl" => limits.rate = value()?.parse()?,
+            "--rrl-slip" => limits.slip = value()?.parse()?,
+            "--rrl-prefix" => {
+                let prefixes = value()?;
+                let (v4, v6) = prefixes
+                    .split_once('/')
+                    .ok_or_else(|| format!("--rrl-prefix {} isn't V4/V6", prefixes))?;
+                (limits.ipv4_prefix, limits.ipv6_prefix) = (v4.parse()?, v6.parse()?);
+                if limits.ipv4_prefix > 32 || limits.ipv6_prefix > 128 {
+                    return Err(format!("--rrl-prefix {} is too long", prefixes).into());
+                }
+            }
+            "--cookie-secret" => options.cookie_secret = Some(cookie::parse_secret(value()?)?),
+            "--require-cookies" => options.require_cookies = true,
+            _ => return Err(format!("unknown flag {}\n{}", flag, USAGE).into()),
+        }
+    }
+    if limits.rate > 0 {
+        options.rate_limit = Some(limits);
+    }
+    let mut answers = load_zones(&options.zones)?;
+    if (answers.is_empty() && options.root_hints.is_none()) || options.threads == 0 {
+        return Err(USAGE.into());
+    }
+    let signer = match key_dir {
+        Some(dir) => {
+            let signer = Signer::new(&dir, algorithm)?;
+            for ds in signer.add_keys(&mut answers)? {
+                println!("{}", ds);
+            }
+            Some(signer)
+        }
+        None => None,
+    };
+    // half the UDP threads at most wait on other servers, so the rest keep answering
+    let max_resolving = (options.threads / 2).max(1);
+    let resolver = options
+        .root_hints
+        .as_deref()
+        .map(|hints| Resolver::from_hints(hints, options.upstream_port, max_resolving))
+        .transpose()?;
+    let tls = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert), Some(key)) => {
+            let identity = Identity::from_pkcs8(&fs::read(cert)?, &fs::read(key)?)?;
+            Some(TlsAcceptor::new(identity)?)
+        }
+        (None, None) => None,
+        _ => return Err("--tls-cert and --tls-key go together".into()),
+    };
+    if tls.is_none() && (options.dot.is_some() || options.doh.is_some()) {
+        return Err("--dot and --doh need --tls-cert and --tls-key".into());
+    }
+    let dnstap = options
+        .dnstap
+        .as_deref()
+        .map(|target| Dnstap::open(target, resolver.is_some()))
+        .transpose()
+        .map_err(|e| format!("--dnstap: {}", e))?;
+    Server::new(Store::new(answers), signer, resolver, tls, dnstap, options)
+        .serve()
+        .map(|_| ())
+}
+
+/// Replies over UDP longer than this get truncated, unless the client uses EDNS to take more
+/// https://datatracker.ietf.org/doc/html/rfc1035#section-4.2.1
+const MAX_UDP_REPLY: usize = 512;
+/// How many times in a row receiving over UDP can fail before the socket counts as broken.
+/// Failures that come and go, like ENOBUFS, are over long before that.
+const MAX_UDP_FAILURES: usize = 100;
+/// How long a TCP connection can stay idle between queries
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Most connections served at once, over TCP, TLS and HTTPS and for the stats all together.
+/// Each takes a thread, so past this new ones get closed right away.
+const MAX_CONNECTIONS: usize = 512;
+// https://datatracker.ietf.org/doc/html/rfc7858#section-3.1
+const DOT_PORT: u16 = 853;
+const DOH_PORT: u16 = 443;
+
+/// `addr` with `port` if it's only an address
+fn with_port(addr: &str, port: u16) -> String {
+    match addr.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port).to_string(),
+        Err(_) => addr.to_string(),
+    }
+}
+
+/// Addresses sharing their first `prefix` bits with `addr`
+#[derive(Debug, Clone, Copy)]
+struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    /// `ADDR/PREFIX`, or just an address for only that one
+    fn from_str(s: &str) -> Result<Network, String> {
+        let (addr, prefix) = s.split_once('/').unwrap_or((s, ""));
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("{} isn't an address", addr))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => max,
+            p => p
+                .parse()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(|| format!("{} isn't a prefix length for {}", p, addr))?,
+        };
+        Ok(Network { addr, prefix })
+    }
+}
+
+/// How queries come
+#[derive(Debug, Clone, Copy)]
+enum Protocol {
+    /// Each in a datagram
+    Udp,
+    /// Each prefixed by its length
+    Tcp,
+    /// The same as TCP, inside TLS
+    Tls,
+    /// In HTTP requests, inside TLS
+    Https,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Protocol::Udp => "UDP",
+            Protocol::Tcp => "TCP",
+            Protocol::Tls => "DoT",
+            Protocol::Https => "DoH",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A query answered, as the query log, the counters and dnstap see it
+struct Served<'a> {
+    client: SocketAddr,
+    protocol: Protocol,
+    query: &'a [u8],
+    /// The first of them, for zone transfers
+    reply: &'a [u8],
+    /// `None` if the query made no sense
+    question: Option<Question>,
+    recursion_desired: bool,
+    code: ResponseCode,
+    /// Whether the reply was slipped or dropped, if it was over the rate
+    limited: Option<Verdict>,
+    received: SystemTime,
+    /// Until the reply was ready to go
+    latency: Duration,
+}
+
+/// A connection being served, counted in `Server::connections` until dropped
+struct Connection<'a>(&'a AtomicUsize);
+
+impl<'a> Connection<'a> {
+    /// `None` if there are `MAX_CONNECTIONS` already
+    fn open(count: &'a AtomicUsize) -> Option<Connection<'a>> {
+        count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < MAX_CONNECTIONS).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Connection(count))
+    }
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Whether `e` is only the client going away, or staying idle for too long
+fn client_gone(e: &(dyn Error + 'static)) -> bool {
+    e.downcast_ref::<io::Error>().is_some_and(|e| {
+        matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::BrokenPipe
+        )
+    })
+}
+
+/// What goes back for a message
+struct Reply {
+    /// More than one only for zone transfers, which go over TCP
+    messages: Vec<Message>,
+    /// Longest it can be over UDP
+    max_udp_len: usize,
+    /// Signs the messages, if the query was signed
+    tsig: Option<Signing>,
+    /// The query came with a server cookie of ours, so its source address is real
+    cookie_ok: bool,
+}
+
+/// A message on the wire. If it's longer than `max_len` it goes without records, and with the
+/// TC bit set so the client knows to ask again over TCP. Messages we can't encode become
+/// SERVFAIL.
+fn encode(mut reply: Message, max_len: usize, tsig: Option<&mut Signing>) -> Vec<u8> {
+    let max_len = max_len - tsig.as_ref().map_or(0, |t| t.overhead());
+    let mut bytes = reply.to_bytes();
+    if let Err(e) = &bytes {
+        println!("Can't encode reply: {}", e);
+        reply.header.flags.response_code = ResponseCode::ServFail;
+    }
+    if bytes.as_ref().map_or(true, |b| b.len() > max_len) {
+        reply.header.flags.truncated = bytes.is_ok();
+        reply.answers.clear();
+        reply.authorities.clear();
+        reply.additionals.clear();
+        bytes = reply.to_bytes();
+    }
+    // all that's left came off the wire already
+    let mut bytes = bytes.expect("reply without records can't be encoded");
+    if let Some(tsig) = tsig {
+        tsig.sign(&mut bytes);
+    }
+    bytes
+}
+
+/// What the threads answering queries share
+struct Server {
+    /// Replaced as a whole when the records change, so every reply sees a single version
+    store: RwLock<Arc<Store>>,
+    /// Signs replies to clients that set the DO bit, if serving with DNSSEC
+    signer: Option<Signer>,
+    /// Answers what isn't in our zones, if we do recursion
+    resolver: Option<Resolver>,
+    /// For DNS over TLS and HTTPS
+    tls: Option<TlsAcceptor>,
+    stats: Stats,
+    dnstap: Option<Dnstap>,
+    rate_limiter: Option<RateLimiter>,
+    cookies: Cookies,
+    /// The addresses of the secondaries in `options.notify`, which get to transfer zones
+    secondaries: Vec<IpAddr>,
+    options: Options,
+    /// How far to rotate the next RRset, with `options.round_robin`
+    rotation: AtomicUsize,
+    /// How many connections are being served
+    connections: AtomicUsize,
+    journal: Mutex<Journal>,
+    /// Held while working out new records and committing them, so changes made at the same
+    /// time don't undo each other
+    updating: Mutex<()>,
+}
+
+impl Server {
+    fn new(
+        store: Store,
+        signer: Option<Signer>,
+        resolver: Option<Resolver>,
+        tls: Option<TlsAcceptor>,
+        dnstap: Option<Dnstap>,
+        options: Options,
+    ) -> Server {
+        Server {
+            store: RwLock::new(Arc::new(store)),
+            signer,
+            resolver,
+            tls,
+            stats: Stats::new(),
+            dnstap,
+            rate_limiter: options.rate_limit.map(RateLimiter::new),
+            cookies: Cookies::new(options.cookie_secret),
+            secondaries: options
+                .notify
+                .iter()
+                .filter_map(|s| s.to_socket_addrs().ok())
+                .flatten()
+                .map(|addr| addr.ip())
+                .collect(),
+            options,
+            rotation: AtomicUsize::new(0),
+            connections: AtomicUsize::new(0),
+            journal: Mutex::new(Journal::default()),
+            updating: Mutex::new(()),
+        }
+    }
+
+    /// The records as they are now
+    fn store(&self) -> Arc<Store> {
+        self.store.read().unwrap().clone()
+    }
+
+    /// Whether `client` gets names outside our zones resolved, rather than only our answers
+    fn recurses_for(&self, client: IpAddr) -> bool {
+        if self.resolver.is_none() {
+            return false;
+        }
+        match self.options.recursion_clients.as_slice() {
+            [] => client.is_loopback(),
+            networks => networks.iter().any(|n| n.contains(client)),
+        }
+    }
+
+    /// Serves `store` from now on, keeping what changed in the journal and telling the
+    /// secondaries about it
+    fn commit(&self, store: Store) {
+        let mut journal = self.journal.lock().unwrap();
+        let changed = journal.record(&self.store(), &store);
+        *self.store.write().unwrap() = Arc::new(store);
+        drop(journal);
+        for soa in changed {
+            println!("{} changed", soa.name);
+            transfer::notify(soa, &self.options.notify);
+        }
+    }
+
+    /// `answers` with the DNSKEYs of their zones if we sign them, keys made for zones new since
+    /// we started
+    fn with_keys(&self, mut answers: Vec<Answer>) -> Result<Vec<Answer>, Box<dyn Error>> {
+        if let Some(signer) = &self.signer {
+            for ds in signer.add_keys(&mut answers)? {
+                println!("Signing a new zone, its DS: {}", ds);
+            }
+        }
+        Ok(answers)
+    }
+
+    /// Reads the zone files again whenever one of them changes, checking every `every`. What
+    /// UPDATEs changed is lost then.
+    fn watch(&self, every: Duration) {
+        let modified = || -> Vec<_> {
+            self.options
+                .zones
+                .iter()
+                .map(|z| fs::metadata(&z.path).and_then(|m| m.modified()).ok())
+                .collect()
+        };
+        let mut last = modified();
+        loop {
+            thread::sleep(every);
+            let now = modified();
+            if now == last {
+                continue;
+            }
+            last = now;
+            let _updating = self.updating.lock().unwrap();
+            let loaded = load_zones(&self.options.zones).map_err(Box::<dyn Error>::from);
+            match loaded.and_then(|answers| self.with_keys(answers)) {
+                Ok(answers) => self.commit(Store::new(answers)),
+                Err(e) => println!("Not reloading the zones: {}", e),
+            }
+        }
+    }
+
+    /// Applies the UPDATE in `message`, all of it or none of it
+    fn update(&self, message: &Message) -> Message {
+        let _updating = self.updating.lock().unwrap();
+        let code = match update::apply(&self.store(), message) {
+            Ok(Some(answers)) => match self.with_keys(answers) {
+                Ok(answers) => {
+                    self.commit(Store::new(answers));
+                    ResponseCode::NoError
+                }
+                Err(e) => {
+                    println!("Can't sign the zones after the UPDATE: {}", e);
+                    ResponseCode::ServFail
+                }
+            },
+            Ok(None) => ResponseCode::NoError,
+            Err(code) => code,
+        };
+        if let Some(zone) = message.questions.first() {
+            println!("UPDATE of {}: {}", zone.domain, code);
+        }
+        message.reply(code)
+    }
+
+    /// The replies to a zone transfer, asked for in `message`. IXFR clients send the SOA they
+    /// have in the authority section.
+    /// https://datatracker.ietf.org/doc/html/rfc1995#section-3
+    fn transfer(&self, message: &Message) -> Vec<Message> {
+        let q = &message.questions[0];
+        // the journal has to match the records
+        let journal = self.journal.lock().unwrap();
+        let store = self.store();
+        let Some(soa) = store.soa(&q.domain) else {
+            return vec![message.reply(ResponseCode::NotAuth)];
+        };
+        let records = if q.qtype == QuestionType::AXFR {
+            transfer::axfr(&store, soa)
+        } else {
+            let serial = message.authorities.iter().find_map(|r| match r.data {
+                RData::SOA { serial, .. } => Some(serial),
+                _ => None,
+            });
+            let Some(serial) = serial else {
+                return vec![message.reply(ResponseCode::FormatError)];
+            };
+            journal.ixfr(&store, soa, serial)
+        };
+        drop(journal);
+        println!("{} of {}: {} records", q.qtype, soa.name, records.len());
+        transfer::messages(message, records)
+    }
+
+    /// What goes back for the message in `buf` from `client`, if it deserves a reply
+    fn respond(&self, buf: &[u8], client: IpAddr, protocol: Protocol) -> Option<Reply> {
+        //println!("{:?} {}", buf, buf.len());
+        let message = match Message::from_bytes(buf) {
+            Ok(m) => m,
+            Err(e) => {
+                println!("Bad query: {}", e);
+                // answer with what little we could make sense of, if anything
+                let (header, _) = Header::read(&mut Reader::new(buf)).ok()?;
+                if let QueryType::Reply = header.flags.query_type {
+                    return None;
+                }
+                let reply = Message {
+                    header: Header {
+                        identification: header.identification,
+                        flags: header.flags.reply(ResponseCode::FormatError),
+                    },
+                    questions: vec![],
+                    answers: vec![],
+                    authorities: vec![],
+                    additionals: vec![],
+                    edns: None,
+                    tsig: None,
+                };
+                return Some(Reply {
+                    messages: vec![reply],
+                    max_udp_len: MAX_UDP_REPLY,
+                    tsig: None,
+                    cookie_ok: false,
+                });
+            }
+        };
         //println!("{:?}", message);
-        assert_eq!(message.question_len, 1);
-        let q = Question::from_bytes(&mut buf[12..]);
+        if let QueryType::Reply = message.header.flags.query_type {
+            return None; // never answer answers
+        }
+        let max_udp_len = message
+            .edns
+            .as_ref()
+            .map_or(MAX_UDP_REPLY, Edns::max_udp_reply);
+        let tsig = message
+            .tsig
+            .as_ref()
+            .map(|(record, len)| tsig::verify(&self.options.tsig_keys, buf, record, *len));
+        let cookie = message.edns.as_ref().and_then(|e| {
+            e.options.iter().find_map(|o| match o {
+                EdnsOption::Cookie { client, server } => Some((client, server)),
+                _ => None,
+            })
+        });
+        let cookie_ok = cookie.is_some_and(|(c, s)| self.cookies.valid(c, s, client));
+        let tcp = matches!(protocol, Protocol::Tcp | Protocol::Tls);
+        let mut messages = match &tsig {
+            // https://datatracker.ietf.org/doc/html/rfc8945#section-5.2
+            Some(t) if !t.ok() => vec![message.reply(ResponseCode::NotAuth)],
+            // clients with a client cookie get one of ours to try again with, the rest get to
+            // come back over TCP
+            // https://datatracker.ietf.org/doc/html/rfc7873#section-5.2.3
+            _ if self.options.require_cookies
+                && matches!(protocol, Protocol::Udp)
+                && !cookie_ok =>
+            {
+                let mut reply = message.reply(ResponseCode::BadCookie);
+                if cookie.is_none() {
+                    reply.header.flags.response_code = ResponseCode::NoError;
+                    reply.header.flags.truncated = true;
+                }
+                vec![reply]
+            }
+            _ => self.answer(&message, client, tsig.is_some(), tcp),
+        };
+        // a fresh server cookie on every reply, whether the one we got was good or not
+        // https://datatracker.ietf.org/doc/html/rfc7873#section-5.2
+        let cookie = cookie.map(|(c, _)| EdnsOption::Cookie {
+            client: *c,
+            server: self.cookies.make(c, client),
+        });
+        for m in &mut messages {
+            m.header.flags.recursion_available = self.recurses_for(client);
+            if let (Some(cookie), Some(edns)) = (&cookie, &mut m.edns) {
+                edns.options.push(cookie.clone());
+            }
+        }
+        Some(Reply {
+            messages,
+            max_udp_len,
+            tsig,
+            cookie_ok,
+        })
+    }
+
+    /// The replies to `message` from `client`, `signed` if it came with a TSIG that checked out
+    fn answer(&self, message: &Message, client: IpAddr, signed: bool, tcp: bool) -> Vec<Message> {
+        if message.edns.as_ref().is_some_and(|e| e.version > 0) {
+            // we only speak version 0
+            return vec![message.reply(ResponseCode::BadVers)];
+        }
+        match message.header.flags.opcode {
+            Opcode::Query => (),
+            Opcode::Update if signed => return vec![self.update(message)],
+            // only from whoever has a key
+            Opcode::Update => return vec![message.reply(ResponseCode::Refused)],
+            _ => return vec![message.reply(ResponseCode::NotImp)],
+        }
+        if message.questions.len() != 1 {
+            return vec![message.reply(ResponseCode::FormatError)];
+        }
+        let q = &message.questions[0];
         // println!("{:?}", q);
 
-        let mut found = false;
-        for a in &answers {
-            if a.name != q.domain && (!a.glob || !q.domain.ends_with(a.name.as_str())) {
-                // println!("Request domain {} is not {}", q.domain, a.name);
-                continue;
+        let store = self.store();
+        if q.qtype == QuestionType::AXFR || q.qtype == QuestionType::IXFR {
+            // zones only go to our secondaries, and to whoever has a key
+            if !signed && !self.secondaries.contains(&client) {
+                return vec![message.reply(ResponseCode::Refused)];
             }
-            if a.atype != q.qtype {
-                // println!("Request type {:?} is not {:?}", q.qtype, a.atype);
-                continue;
+            if tcp {
+                return self.transfer(message);
+            }
+            // transfers only go over TCP. IXFR clients get the SOA, which tells them whether
+            // they are up to date, and to come back over TCP if not.
+            // https://datatracker.ietf.org/doc/html/rfc1995#section-2
+            let Some(soa) = store
+                .soa(&q.domain)
+                .filter(|_| q.qtype == QuestionType::IXFR)
+            else {
+                return vec![message.reply(ResponseCode::Refused)];
+            };
+            let mut reply = message.reply(ResponseCode::NoError);
+            reply.header.flags.authoritative_answer = true;
+            reply.answers.push(soa.to_record(&soa.name));
+            return vec![reply];
+        }
+        if let Some(resolver) = self.resolver.as_ref().filter(|_| self.recurses_for(client)) {
+            let ours = store.zone_of(&name_key(&q.domain)).is_some();
+            if message.header.flags.recursion_desired && !ours {
+                let found = resolver.resolve(q);
+                let mut reply = message.reply(found.code);
+                reply.answers = found.answers;
+                reply.authorities = found.authorities;
+                return vec![reply];
+            }
+        }
+        let rotate = if self.options.round_robin {
+            self.rotation.fetch_add(1, Ordering::Relaxed)
+        } else {
+            0
+        };
+        let mut found = store.lookup(q, rotate);
+        let signer = match &self.signer {
+            Some(signer) if message.edns.as_ref().is_some_and(|e| e.dnssec_ok) => Some(signer),
+            _ => None,
+        };
+        if let Some(signer) = signer {
+            signer.deny(&store, &mut found);
+        }
+        let mut reply = message.reply(found.code);
+        reply.header.flags.authoritative_answer = found.authoritative;
+        reply.answers = found.answers;
+        reply.authorities = found.authorities;
+        reply.additionals = found.additionals;
+        if let Some(signer) = signer {
+            if let Err(e) = signer.sign(&mut reply) {
+                println!("Can't sign reply: {}", e);
+                return vec![message.reply(ResponseCode::ServFail)];
+            }
+        }
+        vec![reply]
+    }
+
+    /// The replies to the query in `buf` from `client`, ready to go back over `protocol`. The
+    /// query goes into the counters, and the query log and dnstap if there are.
+    fn handle(&self, buf: &[u8], client: SocketAddr, protocol: Protocol) -> Vec<Vec<u8>> {
+        let received = SystemTime::now();
+        let start = Instant::now();
+        let stream = matches!(protocol, Protocol::Tcp | Protocol::Tls);
+        let Some(Reply {
+            mut messages,
+            max_udp_len,
+            mut tsig,
+            cookie_ok,
+        }) = self.respond(buf, client.ip(), protocol)
+        else {
+            return vec![];
+        };
+        let max_len = match protocol {
+            Protocol::Udp => max_udp_len,
+            _ => u16::MAX as usize,
+        };
+        // only transfers take more than one message, and only over TCP. HTTP responses take a
+        // single message.
+        if !stream {
+            messages.truncate(1);
+        }
+        let question = messages[0].questions.first().cloned();
+        let code = messages[0].header.flags.response_code;
+        // only UDP can be spoofed, and not by clients that got a cookie of ours back
+        let limited = match &self.rate_limiter {
+            Some(limiter) if matches!(protocol, Protocol::Udp) && !cookie_ok => {
+                Some(limiter.check(client.ip(), &messages[0]))
             }
-            let to_calc = Instant::now().duration_since(start);
-            socket.send_to(&a.to_bytes(&message, &q, ResponseCode::NoError), addr)?;
-            let to_send = Instant::now().duration_since(start) - to_calc;
-            println!("Took {:?} to calculate", to_calc);
-            println!("Took {:?} to send", to_send);
-            found = true;
-            break;
-        }
-        if !found {
-            println!("Not found!");
-            socket.send_to(
-                &Answer {
-                    name: q.domain.clone(),
-                    atype: q.qtype,
-                    class: q.class,
-                    ttl: 1,
-                    data: vec![],
-                    glob: false,
+            _ => None,
+        }
+        .filter(|&verdict| verdict != Verdict::Send);
+        match limited {
+            Some(Verdict::Slip) => {
+                let reply = &mut messages[0];
+                reply.header.flags.truncated = true;
+                reply.answers.clear();
+                reply.authorities.clear();
+                reply.additionals.clear();
+            }
+            Some(Verdict::Drop) => messages.clear(),
+            _ => (),
+        }
+        let replies: Vec<_> = messages
+            .into_iter()
+            .map(|m| encode(m, max_len, tsig.as_mut()))
+            .collect();
+        let recursion_desired = Header::read(&mut Reader::new(buf))
+            .is_ok_and(|(header, _)| header.flags.recursion_desired);
+        self.served(&Served {
+            client,
+            protocol,
+            query: buf,
+            reply: replies.first().map_or(&[], |r| r),
+            question,
+            recursion_desired,
+            code,
+            limited,
+            received,
+            latency: start.elapsed(),
+        });
+        replies
+    }
+
+    fn served(&self, served: &Served) {
+        self.stats.count(served);
+        if self.options.query_log {
+            let (qname, qtype) = match &served.question {
+                Some(q) => (fqdn(&q.domain), q.qtype.to_string()),
+                None => ("-".to_string(), "-".to_string()),
+            };
+            let time = served
+                .received
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            let line = json!({
+                "time": time.as_secs_f64(),
+                "client": served.client.to_string(),
+                "protocol": served.protocol.to_string(),
+                "qname": qname,
+                "qtype": qtype,
+                "rcode": served.code.to_string(),
+                "latency_us": served.latency.as_micros() as u64,
+                "rate_limited": served.limited.map(|v| v.to_string()),
+            });
+            println!("{}", line);
+        }
+        if let Some(dnstap) = &self.dnstap {
+            dnstap.log(served);
+        }
+    }
+
+    /// Reads length prefixed queries off `stream` from `client` and answers them, until the
+    /// client is done
+    /// https://datatracker.ietf.org/doc/html/rfc7766#section-8
+    fn serve_stream<S: Read + Write>(
+        &self,
+        mut stream: S,
+        client: SocketAddr,
+        protocol: Protocol,
+    ) -> io::Result<()> {
+        loop {
+            let mut len = [0; 2];
+            match stream.read_exact(&mut len) {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                r => r?,
+            }
+            let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut buf)?;
+            for reply in self.handle(&buf, client, protocol) {
+                let mut framed = Vec::with_capacity(reply.len() + 2);
+                framed.extend((reply.len() as u16).to_be_bytes());
+                framed.extend(reply);
+                stream.write_all(&framed)?;
+            }
+        }
+    }
+
+    /// Answers the queries on a connection until the client closes it, or stays idle for too
+    /// long
+    fn serve_connection(
+        &self,
+        stream: TcpStream,
+        protocol: Protocol,
+    ) -> Result<(), Box<dyn Error>> {
+        stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+        let client = stream.peer_addr()?;
+        let tls = || self.tls.as_ref().ok_or("no TLS certificate");
+        match protocol {
+            Protocol::Udp => unreachable!("UDP has no connections"),
+            Protocol::Tcp => self.serve_stream(stream, client, protocol)?,
+            Protocol::Tls => self.serve_stream(tls()?.accept(stream)?, client, protocol)?,
+            Protocol::Https => doh::serve_connection(tls()?.accept(stream)?, |query| {
+                self.handle(query, client, protocol).pop()
+            })?,
+        }
+        Ok(())
+    }
+
+    /// Answers datagrams off `socket` until it breaks. Several of these share the socket, each
+    /// taking whichever datagram comes next.
+    fn serve_udp(&self, socket: &UdpSocket) -> io::Result<()> {
+        // as big as a datagram gets, so long queries aren't cut short
+        let mut buf = vec![0; u16::MAX as usize];
+        let mut failures = 0;
+        loop {
+            let (len, addr) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    failures += 1;
+                    if failures == MAX_UDP_FAILURES {
+                        return Err(e);
+                    }
+                    println!("Can't receive over UDP: {}", e);
+                    continue;
+                }
+            };
+            failures = 0;
+            // the client may be unreachable, or made up, which is no reason to stop
+            if let Some(reply) = self.handle(&buf[..len], addr, Protocol::Udp).pop() {
+                if let Err(e) = socket.send_to(&reply, addr) {
+                    println!("Can't reply to {}: {}", addr, e);
                 }
-                .to_bytes(&message, &q, ResponseCode::NxDomain),
-                addr,
-            )?;
+            }
+        }
+    }
+
+    /// Serves over both UDP and TCP on `options.listen`, with `options.threads` threads on UDP
+    /// and one per TCP connection, up to `MAX_CONNECTIONS`. UDP replies that don't fit in a
+    /// datagram go out truncated, with the TC bit set so clients retry over TCP.
+    fn serve(&self) -> Result<String, Box<dyn Error>> {
+        let socket = UdpSocket::bind(&self.options.listen)?;
+        let mut listeners = vec![(TcpListener::bind(&self.options.listen)?, Protocol::Tcp)];
+        if let Some(addr) = &self.options.dot {
+            listeners.push((TcpListener::bind(addr)?, Protocol::Tls));
+        }
+        if let Some(addr) = &self.options.doh {
+            listeners.push((TcpListener::bind(addr)?, Protocol::Https));
+        }
+        let stats = self
+            .options
+            .stats
+            .as_ref()
+            .map(TcpListener::bind)
+            .transpose()?;
+        thread::scope(|s| {
+            if let Some(every) = self.options.reload {
+                s.spawn(move || self.watch(every));
+            }
+            if let Some(listener) = &stats {
+                s.spawn(move || {
+                    for stream in listener.incoming() {
+                        let Ok(stream) = stream else { continue };
+                        let Some(connection) = Connection::open(&self.connections) else {
+                            continue;
+                        };
+                        s.spawn(move || {
+                            let _connection = connection;
+                            let _ = stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT));
+                            match self.stats.serve_connection(stream) {
+                                Err(e) if !client_gone(&e) => {
+                                    println!("Stats connection failed: {}", e)
+                                }
+                                _ => (),
+                            }
+                        });
+                    }
+                });
+            }
+            for (listener, protocol) in &listeners {
+                s.spawn(move || {
+                    for stream in listener.incoming() {
+                        let Ok(stream) = stream else { continue };
+                        let Some(connection) = Connection::open(&self.connections) else {
+                            continue;
+                        };
+                        s.spawn(move || {
+                            let _connection = connection;
+                            match self.serve_connection(stream, *protocol) {
+                                Err(e) if !client_gone(e.as_ref()) => {
+                                    println!("{:?} connection failed: {}", protocol, e)
+                                }
+                                _ => (),
+                            }
+                        });
+                    }
+                });
+            }
+            let workers: Vec<_> = (0..self.options.threads)
+                .map(|_| s.spawn(|| self.serve_udp(&socket)))
+                .collect();
+            // they only ever stop once the socket is broken. The TCP listeners would keep the
+            // scope open, so it takes ending the process to be noticed.
+            for worker in workers {
+                if let Err(e) = worker.join().expect("UDP worker panicked") {
+                    println!("UDP stopped: {}", e);
+                    process::exit(1);
+                }
+            }
+            Err("UDP workers stopped".into())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn udp_workers_carry_on_after_bad_queries() {
+        let socket = UdpSocket::bind("127.0.0.41:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let text = "$TTL 300\n@ SOA ns hm 1 2 3 4 5\n@ NS ns\nwww A 192.0.2.1\n";
+        let store = Store::new(zone::parse(text, "example.com").unwrap());
+        let server = Server::new(store, None, None, None, None, Options::default());
+        let server: &'static Server = Box::leak(Box::new(server));
+        let socket: &'static UdpSocket = Box::leak(Box::new(socket));
+        // two of them on the one socket, as `serve` has it
+        for _ in 0..2 {
+            thread::spawn(move || server.serve_udp(socket));
+        }
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let mut buf = [0; 512];
+        // too short for a header, so there's nothing to reply to
+        client.send(b"\x12\x34\x01").unwrap();
+        // a header promising a question that isn't there
+        client
+            .send(b"\x12\x35\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00")
+            .unwrap();
+        let len = client.recv(&mut buf).unwrap();
+        let reply = Message::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(reply.header.identification, 0x1235);
+        assert_eq!(reply.header.flags.response_code, ResponseCode::FormatError);
+
+        let q = Question {
+            domain: "www.example.com".to_string(),
+            qtype: QuestionType::A,
+            class: QuestionClass::IN,
+        };
+        for id in 0..4 {
+            let query = Message::query(id, q.clone());
+            client.send(&query.to_bytes().unwrap()).unwrap();
+            let len = client.recv(&mut buf).unwrap();
+            let reply = Message::from_bytes(&buf[..len]).unwrap();
+            assert_eq!(reply.header.identification, id);
+            assert_eq!(reply.header.flags.response_code, ResponseCode::NoError);
+            assert_eq!(reply.answers.len(), 1);
+            assert_eq!(reply.answers[0].data.to_string(), "192.0.2.1");
         }
     }
 }
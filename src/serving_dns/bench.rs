@@ -0,0 +1,167 @@
+// Load test for a DNS server: several clients firing queries over UDP, each waiting for the
+// reply before sending the next one.
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{Message, Question, QuestionClass, QuestionType};
+
+/// What a client saw
+#[derive(Default)]
+struct Results {
+    latencies: Vec<Duration>,
+    rcodes: BTreeMap<String, usize>,
+    /// Queries without a reply in time
+    lost: usize,
+}
+
+fn client(
+    server: SocketAddr,
+    questions: &[Question],
+    queries: usize,
+    timeout: Duration,
+) -> Result<Results, String> {
+    let local = if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).map_err(|e| e.to_string())?;
+    socket.connect(server).map_err(|e| e.to_string())?;
+    socket
+        .set_read_timeout(Some(timeout))
+        .map_err(|e| e.to_string())?;
+    let mut results = Results::default();
+    let mut buf = vec![0; u16::MAX as usize];
+    for i in 0..queries {
+        let id = i as u16;
+        let query = Message::query(id, questions[i % questions.len()].clone());
+        let start = Instant::now();
+        let query = query.to_bytes().map_err(|e| e.to_string())?;
+        socket.send(&query).map_err(|e| e.to_string())?;
+        // replies to queries that timed out earlier may still show up
+        let reply = loop {
+            let Ok(len) = socket.recv(&mut buf) else {
+                break None;
+            };
+            match Message::from_bytes(&buf[..len]) {
+                Ok(m) if m.header.identification == id => break Some(m),
+                _ if start.elapsed() > timeout => break None,
+                _ => continue,
+            }
+        };
+        match reply {
+            Some(m) => {
+                results.latencies.push(start.elapsed());
+                let rcode = m.header.flags.response_code.to_string();
+                *results.rcodes.entry(rcode).or_default() += 1;
+            }
+            None => results.lost += 1,
+        }
+    }
+    Ok(results)
+}
+
+/// The latency `p` percent of the replies were faster than
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    sorted[(sorted.len() - 1) * p / 100]
+}
+
+const USAGE: &str = "usage: dns-bench ADDR NAME... [--type TYPE] [--queries N] [--clients N] \
+[--timeout MS]
+the names are asked for in turn, all with the same type";
+
+/// `dns-bench` subcommand: reports queries/s and latency percentiles of the server at ADDR
+pub fn main(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut args = args.iter();
+    let server = args
+        .next()
+        .ok_or(USAGE)?
+        .to_socket_addrs()?
+        .next()
+        .ok_or("no address to send to")?;
+    let mut names = vec![];
+    let mut qtype = QuestionType::A;
+    let mut queries: usize = 10_000;
+    let mut clients: usize = 8;
+    let mut timeout = Duration::from_secs(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--type" => qtype = QuestionType::from_str(&value()?.to_ascii_uppercase())?,
+            "--queries" => queries = value()?.parse()?,
+            "--clients" => clients = value()?.parse()?,
+            "--timeout" => timeout = Duration::from_millis(value()?.parse()?),
+            _ if arg.starts_with("--") => {
+                return Err(format!("unknown flag {}\n{}", arg, USAGE).into())
+            }
+            _ => names.push(arg.clone()),
+        }
+    }
+    if names.is_empty() || clients == 0 {
+        return Err(USAGE.into());
+    }
+    let questions: Vec<Question> = names
+        .into_iter()
+        .map(|domain| Question {
+            domain,
+            qtype,
+            class: QuestionClass::IN,
+        })
+        .collect();
+
+    let start = Instant::now();
+    let results = thread::scope(|s| {
+        let handles: Vec<_> = (0..clients)
+            .map(|i| {
+                // spread the remainder over the first clients
+                let n = queries / clients + usize::from(i < queries % clients);
+                let questions = &questions;
+                s.spawn(move || client(server, questions, n, timeout))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("client panicked"))
+            .collect::<Result<Vec<_>, _>>()
+    })?;
+    let elapsed = start.elapsed();
+
+    let mut total = Results::default();
+    for r in results {
+        total.latencies.extend(r.latencies);
+        total.lost += r.lost;
+        for (rcode, n) in r.rcodes {
+            *total.rcodes.entry(rcode).or_default() += n;
+        }
+    }
+    let answered = total.latencies.len();
+    println!(
+        "{} queries in {:.2?}: {:.0} queries/s, {} lost",
+        answered + total.lost,
+        elapsed,
+        answered as f64 / elapsed.as_secs_f64(),
+        total.lost
+    );
+    if answered == 0 {
+        return Err("no replies".into());
+    }
+    total.latencies.sort();
+    println!(
+        "latency: p50 {:.2?}, p90 {:.2?}, p99 {:.2?}, max {:.2?}",
+        percentile(&total.latencies, 50),
+        percentile(&total.latencies, 90),
+        percentile(&total.latencies, 99),
+        total.latencies[answered - 1]
+    );
+    let rcodes: Vec<String> = total
+        .rcodes
+        .iter()
+        .map(|(rcode, n)| format!("{} {}", rcode, n))
+        .collect();
+    println!("rcodes: {}", rcodes.join(", "));
+    Ok(())
+}
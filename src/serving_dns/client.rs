@@ -0,0 +1,571 @@
+// A stub resolver and `dig`-like client: sends a query to a server over UDP, or TCP when asked
+// to or when the reply comes back truncated, and prints every section of the reply.
+use std::error::Error;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::edns::{Edns, EdnsOption, PAYLOAD_SIZE};
+use super::store::name_key;
+use super::{
+    fqdn, Flags, Message, QueryType, Question, QuestionClass, QuestionType, RData, Record,
+    ResponseCode,
+};
+
+pub(super) const DNS_PORT: u16 = 53;
+
+/// How to get a query across
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Transport {
+    /// Straight over TCP, instead of only after a truncated reply over UDP
+    pub(super) tcp: bool,
+    /// How long to wait for each reply
+    pub(super) timeout: Duration,
+    /// How many times to send over UDP without a reply before giving up
+    pub(super) tries: u32,
+}
+
+impl Default for Transport {
+    fn default() -> Transport {
+        Transport {
+            tcp: false,
+            timeout: Duration::from_secs(2),
+            tries: 3,
+        }
+    }
+}
+
+/// The replies to a query, and what it took to get them
+pub(super) struct Exchange {
+    /// More than one only for zone transfers
+    pub(super) replies: Vec<Message>,
+    /// Bytes received, all replies together
+    pub(super) size: usize,
+    /// Whether they came over TCP
+    pub(super) tcp: bool,
+    pub(super) elapsed: Duration,
+}
+
+/// A reply to `query`: same ID, same question
+/// https://datatracker.ietf.org/doc/html/rfc5452#section-9.1
+fn answers(reply: &Message, query: &Message) -> bool {
+    let same_question = |a: &Question, b: &Question| {
+        a.qtype == b.qtype && a.class == b.class && name_key(&a.domain) == name_key(&b.domain)
+    };
+    reply.header.identification == query.header.identification
+        && matches!(reply.header.flags.query_type, QueryType::Reply)
+        // errors may come without the question
+        && (reply.questions.is_empty()
+            || reply.questions.len() == query.questions.len()
+                && reply
+                    .questions
+                    .iter()
+                    .zip(&query.questions)
+                    .all(|(a, b)| same_question(a, b)))
+}
+
+fn udp(
+    server: SocketAddr,
+    query: &Message,
+    transport: Transport,
+) -> Result<(Message, usize), String> {
+    let bytes = query.to_bytes().map_err(|e| e.to_string())?;
+    let local = if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).map_err(|e| e.to_string())?;
+    socket.connect(server).map_err(|e| e.to_string())?;
+    socket
+        .set_read_timeout(Some(transport.timeout))
+        .map_err(|e| e.to_string())?;
+    let mut buf = vec![0; u16::MAX as usize];
+    for _ in 0..transport.tries.max(1) {
+        socket.send(&bytes).map_err(|e| e.to_string())?;
+        // whatever isn't the reply is ignored, until the time is up
+        while let Ok(len) = socket.recv(&mut buf) {
+            match Message::from_bytes(&buf[..len]) {
+                Ok(m) if answers(&m, query) => return Ok((m, len)),
+                _ => continue,
+            }
+        }
+    }
+    Err(format!("no reply from {}", server))
+}
+
+/// Whether `records`, the answers so far of a zone transfer, are all of them. It's over once
+/// the SOA it started with shows up again: a second time for AXFR, a third for IXFR, which has
+/// it once more before the last changes. An IXFR reply with just the SOA means we are up to
+/// date.
+/// https://datatracker.ietf.org/doc/html/rfc1995#section-4
+fn transfer_done(records: &[Record], ixfr: bool) -> bool {
+    let serial = |r: &Record| match r.data {
+        RData::SOA { serial, .. } => Some(serial),
+        _ => None,
+    };
+    let Some(first) = records.first().and_then(serial) else {
+        return true; // not a transfer we can follow
+    };
+    let seen = records.iter().filter(|r| serial(r) == Some(first)).count();
+    let incremental = ixfr && records.get(1).and_then(serial).is_some();
+    match records.len() {
+        1 => ixfr,
+        _ if incremental => seen >= 3,
+        _ => seen >= 2,
+    }
+}
+
+/// Sends `query` over TCP, reading replies until the zone transfer is over if it asks for one
+fn tcp(
+    server: SocketAddr,
+    query: &Message,
+    transport: Transport,
+) -> Result<(Vec<Message>, usize), String> {
+    let bytes = query.to_bytes().map_err(|e| e.to_string())?;
+    let mut stream =
+        TcpStream::connect_timeout(&server, transport.timeout).map_err(|e| e.to_string())?;
+    stream
+        .set_read_timeout(Some(transport.timeout))
+        .map_err(|e| e.to_string())?;
+    let mut framed = (bytes.len() as u16).to_be_bytes().to_vec();
+    framed.extend(bytes);
+    stream.write_all(&framed).map_err(|e| e.to_string())?;
+
+    let qtype = query.questions.first().map(|q| q.qtype);
+    let transfer = matches!(qtype, Some(QuestionType::AXFR | QuestionType::IXFR));
+    let mut replies = vec![];
+    let mut records = vec![];
+    let mut size = 0;
+    loop {
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).map_err(|e| e.to_string())?;
+        let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut buf).map_err(|e| e.to_string())?;
+        let reply = Message::from_bytes(&buf).map_err(|e| e.to_string())?;
+        if !answers(&reply, query) {
+            return Err("reply doesn't match the query".to_string());
+        }
+        size += buf.len();
+        let failed = reply.header.flags.response_code != ResponseCode::NoError;
+        records.extend(reply.answers.iter().cloned());
+        replies.push(reply);
+        let ixfr = qtype == Some(QuestionType::IXFR);
+        if !transfer || failed || transfer_done(&records, ixfr) {
+            return Ok((replies, size));
+        }
+    }
+}
+
+/// Sends `query` to `server` and waits for the replies. Over UDP a truncated reply means
+/// asking again over TCP.
+pub(super) fn exchange(
+    server: SocketAddr,
+    query: &Message,
+    transport: Transport,
+) -> Result<Exchange, String> {
+    let start = Instant::now();
+    if !transport.tcp {
+        let (reply, size) = udp(server, query, transport)?;
+        if !reply.header.flags.truncated {
+            return Ok(Exchange {
+                replies: vec![reply],
+                size,
+                tcp: false,
+                elapsed: start.elapsed(),
+            });
+        }
+    }
+    let (replies, size) = tcp(server, query, transport)?;
+    Ok(Exchange {
+        replies,
+        size,
+        tcp: true,
+        elapsed: start.elapsed(),
+    })
+}
+
+/// `ADDR`, `ADDR:PORT`, `[ADDR]:PORT` or a host name, port 53 if none is given
+fn server_address(s: &str) -> Result<SocketAddr, String> {
+    if let Ok(ip) = IpAddr::from_str(s) {
+        return Ok(SocketAddr::new(ip, DNS_PORT));
+    }
+    if let Ok(addr) = SocketAddr::from_str(s) {
+        return Ok(addr);
+    }
+    let with_port = if s.contains(':') {
+        s.to_string()
+    } else {
+        format!("{}:{}", s, DNS_PORT)
+    };
+    with_port
+        .to_socket_addrs()
+        .map_err(|e| format!("{}: {}", s, e))?
+        .next()
+        .ok_or_else(|| format!("{}: no address", s))
+}
+
+/// The first name server in /etc/resolv.conf, as any stub resolver would use
+fn system_server() -> Option<SocketAddr> {
+    let conf = fs::read_to_string("/etc/resolv.conf").ok()?;
+    conf.lines()
+        .filter_map(|l| l.strip_prefix("nameserver"))
+        .find_map(|ip| IpAddr::from_str(ip.trim()).ok())
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+}
+
+fn print_flags(flags: &Flags) {
+    let names = [
+        (matches!(flags.query_type, QueryType::Reply), "qr"),
+        (flags.authoritative_answer, "aa"),
+        (flags.truncated, "tc"),
+        (flags.recursion_desired, "rd"),
+        (flags.recursion_available, "ra"),
+        (flags.ad_bit, "ad"),
+        (flags.unauthenticated_ok, "cd"),
+    ];
+    let set: Vec<&str> = names
+        .iter()
+        .filter(|(on, _)| *on)
+        .map(|(_, n)| *n)
+        .collect();
+    print!(";; flags: {}", set.join(" "));
+}
+
+fn print_edns(edns: &Edns) {
+    println!(";; OPT PSEUDOSECTION:");
+    let flags = if edns.dnssec_ok { " do" } else { "" };
+    println!(
+        "; EDNS: version: {}, flags:{}; udp: {}",
+        edns.version, flags, edns.payload_size
+    );
+    for option in &edns.options {
+        match option {
+            EdnsOption::Cookie { client, server } => {
+                println!("; COOKIE: {}{}", super::hex(client), super::hex(server))
+            }
+            EdnsOption::ClientSubnet {
+                family,
+                source_prefix,
+                scope_prefix,
+                address,
+            } => {
+                let ip = match family {
+                    1 => {
+                        let mut octets = [0; 4];
+                        octets[..address.len()].copy_from_slice(address);
+                        IpAddr::from(octets)
+                    }
+                    _ => {
+                        let mut octets = [0; 16];
+                        octets[..address.len()].copy_from_slice(address);
+                        IpAddr::from(octets)
+                    }
+                };
+                println!("; CLIENT-SUBNET: {}/{}/{}", ip, source_prefix, scope_prefix);
+            }
+            EdnsOption::Unknown(code, data) => println!("; OPT={}: {}", code, super::hex(data)),
+        }
+    }
+}
+
+fn print_section(title: &str, records: &[Record]) {
+    if records.is_empty() {
+        return;
+    }
+    println!("\n;; {} SECTION:", title);
+    for r in records {
+        println!("{}", r);
+    }
+}
+
+/// Every section of `reply`, as dig prints them
+fn print_reply(reply: &Message) {
+    let header = &reply.header;
+    println!(
+        ";; ->>HEADER<<- opcode: {}, status: {}, id: {}",
+        header.flags.opcode.to_string().to_ascii_uppercase(),
+        header.flags.response_code.to_string().to_ascii_uppercase(),
+        header.identification
+    );
+    print_flags(&header.flags);
+    println!(
+        "; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+        reply.questions.len(),
+        reply.answers.len(),
+        reply.authorities.len(),
+        reply.additionals.len() + reply.edns.is_some() as usize + reply.tsig.is_some() as usize
+    );
+    if let Some(edns) = &reply.edns {
+        println!();
+        print_edns(edns);
+    }
+    if !reply.questions.is_empty() {
+        println!("\n;; QUESTION SECTION:");
+        for q in &reply.questions {
+            println!(";{}\t\t{}\t{}", fqdn(&q.domain), q.class, q.qtype);
+        }
+    }
+    print_section("ANSWER", &reply.answers);
+    print_section("AUTHORITY", &reply.authorities);
+    print_section("ADDITIONAL", &reply.additionals);
+    if let Some((tsig, _)) = &reply.tsig {
+        print_section("TSIG PSEUDO", std::slice::from_ref(tsig));
+    }
+}
+
+const USAGE: &str = "usage: dns-query [@SERVER] NAME [TYPE] [--tcp] [--norecurse] [--dnssec] \
+[--noedns] [--bufsize N] [--timeout MS] [--tries N] [--serial N]
+SERVER is an address, with a port if not 53, or a host name; the first nameserver in \
+/etc/resolv.conf if missing
+AXFR and IXFR go over TCP, IXFR from the zone's --serial";
+
+/// `dns-query` subcommand: asks SERVER about NAME and prints the reply as dig does
+pub fn main(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut server = None;
+    let mut positional = vec![];
+    let mut transport = Transport::default();
+    let mut recursion_desired = true;
+    let mut dnssec_ok = false;
+    let mut edns = true;
+    let mut payload_size = PAYLOAD_SIZE;
+    let mut serial = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--tcp" => transport.tcp = true,
+            "--norecurse" => recursion_desired = false,
+            "--dnssec" => dnssec_ok = true,
+            "--noedns" => edns = false,
+            "--bufsize" => payload_size = value()?.parse()?,
+            "--timeout" => transport.timeout = Duration::from_millis(value()?.parse()?),
+            "--tries" => transport.tries = value()?.parse()?,
+            "--serial" => serial = Some(value()?.parse::<u32>()?),
+            _ if arg.starts_with("--") => {
+                return Err(format!("unknown flag {}\n{}", arg, USAGE).into())
+            }
+            _ => match arg.strip_prefix('@') {
+                Some(s) => server = Some(server_address(s)?),
+                None => positional.push(arg.clone()),
+            },
+        }
+    }
+    let (domain, qtype) = match &positional[..] {
+        [name] => (name, QuestionType::A),
+        [name, t] => (name, QuestionType::from_str(&t.to_ascii_uppercase())?),
+        _ => return Err(USAGE.into()),
+    };
+    let server = server
+        .or_else(system_server)
+        .ok_or("no server given, and none in /etc/resolv.conf")?;
+
+    let mut id = [0; 2];
+    SystemRandom::new()
+        .fill(&mut id)
+        .map_err(|_| "no random id")?;
+    let question = Question {
+        domain: super::wire::canonical_name(domain),
+        qtype,
+        class: QuestionClass::IN,
+    };
+    let mut query = Message::query(u16::from_be_bytes(id), question);
+    query.header.flags.recursion_desired = recursion_desired;
+    if edns || dnssec_ok {
+        query.edns = Some(Edns {
+            payload_size,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok,
+            options: vec![],
+        });
+    }
+    match qtype {
+        QuestionType::AXFR => transport.tcp = true,
+        QuestionType::IXFR => {
+            // the version we have goes in the authority section
+            let serial = serial.ok_or("IXFR needs --serial")?;
+            query.authorities.push(Record {
+                name: query.questions[0].domain.clone(),
+                rtype: QuestionType::SOA,
+                class: QuestionClass::IN,
+                ttl: 0,
+                data: RData::SOA {
+                    mname: String::new(),
+                    rname: String::new(),
+                    serial,
+                    refresh: 0,
+                    retry: 0,
+                    expire: 0,
+                    minimum: 0,
+                },
+            });
+            transport.tcp = true;
+        }
+        _ => (),
+    }
+
+    println!("; <<>> dns-query <<>> {}", positional.join(" "));
+    let exchange = exchange(server, &query, transport)?;
+    if exchange.tcp && !transport.tcp {
+        println!(";; Truncated, retried in TCP mode.");
+    }
+    let transfer = matches!(qtype, QuestionType::AXFR | QuestionType::IXFR);
+    if transfer && exchange.replies[0].header.flags.response_code == ResponseCode::NoError {
+        // just the records, as they'd go in a master file
+        let mut records = 0;
+        for reply in &exchange.replies {
+            for r in &reply.answers {
+                println!("{}", r);
+            }
+            records += reply.answers.len();
+        }
+        println!(
+            ";; XFR size: {} records (messages {}, bytes {})",
+            records,
+            exchange.replies.len(),
+            exchange.size
+        );
+    } else {
+        for reply in &exchange.replies {
+            print_reply(reply);
+        }
+    }
+    println!("\n;; Query time: {} msec", exchange.elapsed.as_millis());
+    let protocol = if exchange.tcp { "TCP" } else { "UDP" };
+    println!(";; SERVER: {} ({})", server, protocol);
+    println!(";; MSG SIZE  rcvd: {}", exchange.size);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::super::{store::Store, zone, Options, Protocol, Server};
+    use super::*;
+
+    /// Serves `zone` over UDP and TCP at `ip`, returning the address
+    fn serve(ip: &str, zone: &str) -> SocketAddr {
+        let socket = UdpSocket::bind((ip, 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let listener = TcpListener::bind(addr).unwrap();
+        let store = Store::new(zone::parse(zone, "example.com").unwrap());
+        let server = Server::new(store, None, None, None, None, Options::default());
+        let server: &'static Server = Box::leak(Box::new(server));
+        thread::spawn(move || server.serve_udp(&socket));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let _ = server.serve_connection(stream.unwrap(), Protocol::Tcp);
+            }
+        });
+        addr
+    }
+
+    fn query(name: &str, qtype: QuestionType) -> Message {
+        let q = Question {
+            domain: name.to_string(),
+            qtype,
+            class: QuestionClass::IN,
+        };
+        Message::query(4321, q)
+    }
+
+    fn transport() -> Transport {
+        Transport {
+            timeout: Duration::from_millis(200),
+            ..Transport::default()
+        }
+    }
+
+    #[test]
+    fn truncated_replies_are_asked_again_over_tcp() {
+        let mut zone = "$TTL 300\n@ SOA ns hm 1 2 3 4 5\n@ NS ns\nns A 192.0.2.53\n".to_string();
+        for i in 0..40 {
+            zone.push_str(&format!(
+                "big TXT \"record number {} of a set too big for UDP\"\n",
+                i
+            ));
+        }
+        let server = serve("127.0.0.31", &zone);
+
+        let small = query("ns.example.com", QuestionType::A);
+        let small = exchange(server, &small, transport()).unwrap();
+        assert!(!small.tcp);
+        assert_eq!(small.replies[0].answers.len(), 1);
+
+        let big = query("big.example.com", QuestionType::TXT);
+        let big = exchange(server, &big, transport()).unwrap();
+        assert!(big.tcp);
+        assert_eq!(big.replies.len(), 1);
+        assert!(!big.replies[0].header.flags.truncated);
+        assert_eq!(big.replies[0].answers.len(), 40);
+        assert!(big.size > 512);
+    }
+
+    /// A server at `ip` that answers every query with each of the replies `reply` makes of it
+    fn serve_replies(ip: &str, reply: fn(&Message) -> Vec<Message>) -> SocketAddr {
+        let socket = UdpSocket::bind((ip, 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((len, client)) = socket.recv_from(&mut buf) {
+                let query = Message::from_bytes(&buf[..len]).unwrap();
+                for m in reply(&query) {
+                    socket.send_to(&m.to_bytes().unwrap(), client).unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn replies_to_other_queries_are_ignored() {
+        let server = serve_replies("127.0.0.32", |query| {
+            let mut other_id = query.reply(ResponseCode::Refused);
+            other_id.header.identification ^= 1;
+            let mut other_question = query.reply(ResponseCode::Refused);
+            other_question.questions[0].domain = "example.net".to_string();
+            let mut not_a_reply = query.reply(ResponseCode::Refused);
+            not_a_reply.header.flags.query_type = QueryType::Query;
+            vec![
+                other_id,
+                other_question,
+                not_a_reply,
+                query.reply(ResponseCode::NoError),
+            ]
+        });
+        // any case will do for the name, as in 0x20 encoding
+        let query = query("ExAmple.COM", QuestionType::A);
+        let exchange = exchange(server, &query, transport()).unwrap();
+        let reply = &exchange.replies[0];
+        assert_eq!(reply.header.identification, 4321);
+        assert_eq!(reply.header.flags.response_code, ResponseCode::NoError);
+    }
+
+    #[test]
+    fn timeouts() {
+        let silent = UdpSocket::bind("127.0.0.33:0").unwrap();
+        let server = silent.local_addr().unwrap();
+        let transport = Transport {
+            timeout: Duration::from_millis(100),
+            tries: 2,
+            ..Transport::default()
+        };
+        let start = Instant::now();
+        let e = exchange(server, &query("example.com", QuestionType::A), transport).err();
+        assert_eq!(e, Some(format!("no reply from {}", server)));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        // it tried as many times as it was told to
+        silent.set_nonblocking(true).unwrap();
+        let mut buf = [0; 512];
+        assert!(silent.recv(&mut buf).is_ok());
+        assert!(silent.recv(&mut buf).is_ok());
+        assert!(silent.recv(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+// DNS cookies: clients send a random client cookie, we send back a server cookie made from it,
+// their address and a secret. A client that brings it back proves it gets our replies, so the
+// address isn't spoofed.
+// https://datatracker.ietf.org/doc/html/rfc7873
+// https://datatracker.ietf.org/doc/html/rfc9018
+#![allow(deprecated)] // SipHasher, which is SipHash-2-4 as RFC 9018 has it
+
+use std::hash::{Hasher, SipHasher};
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+const VERSION: u8 = 1;
+/// How old a server cookie can be and still get accepted
+/// https://datatracker.ietf.org/doc/html/rfc9018#section-4.3
+const MAX_AGE: u32 = 3600;
+/// How far ahead of our clock, for servers that share the secret
+const MAX_AHEAD: u32 = 300;
+
+pub struct Cookies {
+    secret: [u8; 16],
+}
+
+impl Cookies {
+    /// With `secret`, for servers that have to accept each other's cookies, or else a random one
+    pub fn new(secret: Option<[u8; 16]>) -> Cookies {
+        let secret = secret.unwrap_or_else(|| {
+            let mut secret = [0; 16];
+            SystemRandom::new()
+                .fill(&mut secret)
+                .expect("no randomness for the cookie secret");
+            secret
+        });
+        Cookies { secret }
+    }
+
+    /// The server cookie for `client` at `ip`, made at `time`: version, 3 reserved bytes, the
+    /// time and a hash of all of that
+    fn server_cookie(&self, client: &[u8; 8], ip: IpAddr, time: u32) -> Vec<u8> {
+        let mut cookie = vec![VERSION, 0, 0, 0];
+        cookie.extend(time.to_be_bytes());
+        let mut hasher = SipHasher::new_with_keys(
+            u64::from_le_bytes(self.secret[..8].try_into().unwrap()),
+            u64::from_le_bytes(self.secret[8..].try_into().unwrap()),
+        );
+        hasher.write(client);
+        hasher.write(&cookie);
+        match ip {
+            IpAddr::V4(ip) => hasher.write(&ip.octets()),
+            IpAddr::V6(ip) => hasher.write(&ip.octets()),
+        }
+        cookie.extend(hasher.finish().to_le_bytes());
+        cookie
+    }
+
+    /// A fresh server cookie for `client` at `ip`
+    pub fn make(&self, client: &[u8; 8], ip: IpAddr) -> Vec<u8> {
+        self.server_cookie(client, ip, now())
+    }
+
+    /// Whether `server` is a cookie we made for `client` at `ip`, recently enough
+    pub fn valid(&self, client: &[u8; 8], server: &[u8], ip: IpAddr) -> bool {
+        if server.len() != 16 || server[0] != VERSION {
+            return false;
+        }
+        let time = u32::from_be_bytes(server[4..8].try_into().unwrap());
+        // serial number arithmetic, as the time wraps in 2106
+        let age = now().wrapping_sub(time);
+        if age > MAX_AGE && age.wrapping_neg() > MAX_AHEAD {
+            return false;
+        }
+        self.server_cookie(client, ip, time) == server
+    }
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32
+}
+
+/// A secret from its hex, as `--cookie-secret` takes it
+pub fn parse_secret(hex: &str) -> Result<[u8; 16], String> {
+    let bytes: Option<Vec<u8>> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect();
+    bytes
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| format!("cookie secret {} isn't 32 hex digits", hex))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: &[u8; 8] = b"\x01\x02\x03\x04\x05\x06\x07\x08";
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn fresh_cookies_are_valid() {
+        let cookies = Cookies::new(None);
+        let server = cookies.make(CLIENT, ip("192.0.2.1"));
+        assert_eq!(server.len(), 16);
+        assert!(cookies.valid(CLIENT, &server, ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn cookies_are_for_one_client_at_one_address() {
+        let cookies = Cookies::new(None);
+        let server = cookies.make(CLIENT, ip("192.0.2.1"));
+        assert!(!cookies.valid(CLIENT, &server, ip("192.0.2.2")));
+        assert!(!cookies.valid(
+            b"\x00\x02\x03\x04\x05\x06\x07\x08",
+            &server,
+            ip("192.0.2.1")
+        ));
+        // nor are they any good to servers with another secret
+        assert!(!Cookies::new(None).valid(CLIENT, &server, ip("192.0.2.1")));
+        let mut forged = server.clone();
+        forged[15] ^= 1;
+        assert!(!cookies.valid(CLIENT, &forged, ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn servers_with_the_same_secret_share_cookies() {
+        let secret = parse_secret("000102030405060708090a0b0c0d0e0f").unwrap();
+        let server = Cookies::new(Some(secret)).make(CLIENT, ip("2001:db8::1"));
+        assert!(Cookies::new(Some(secret)).valid(CLIENT, &server, ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn cookies_expire() {
+        let cookies = Cookies::new(None);
+        let made = |age: i64| {
+            let time = (now() as i64 - age) as u32;
+            cookies.server_cookie(CLIENT, ip("192.0.2.1"), time)
+        };
+        let valid = |server: Vec<u8>| cookies.valid(CLIENT, &server, ip("192.0.2.1"));
+        assert!(valid(made(MAX_AGE as i64 - 10)));
+        assert!(!valid(made(MAX_AGE as i64 + 10)));
+        // from a server whose clock is a little ahead, but not too far
+        assert!(valid(made(-(MAX_AHEAD as i64) + 10)));
+        assert!(!valid(made(-(MAX_AHEAD as i64) - 10)));
+    }
+
+    #[test]
+    fn rejects_other_versions_and_lengths() {
+        let cookies = Cookies::new(None);
+        let mut server = cookies.make(CLIENT, ip("192.0.2.1"));
+        assert!(!cookies.valid(CLIENT, &server[..8], ip("192.0.2.1")));
+        server[0] = 2;
+        assert!(!cookies.valid(CLIENT, &server, ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn parses_secrets() {
+        let secret = parse_secret("000102030405060708090A0B0C0D0E0F").unwrap();
+        assert_eq!(secret, std::array::from_fn(|i| i as u8));
+        assert!(parse_secret("000102").is_err());
+        assert!(parse_secret("000102030405060708090a0b0c0d0e0f00").is_err());
+        assert!(parse_secret("zz0102030405060708090a0b0c0d0e0f").is_err());
+    }
+}
@@ -0,0 +1,776 @@
+// DNSSEC online signing: one key per zone, used both to sign the DNSKEY RRset and everything
+// else (a combined signing key), with RRSIGs made as replies go out. Names that don't exist, or
+// lack the type asked for, are denied with compact answers ("black lies"): an NSEC covering just
+// the name asked for, so we never need to know the names around it.
+// https://datatracker.ietf.org/doc/html/rfc4034
+// https://datatracker.ietf.org/doc/html/rfc9824
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use sha2::{Digest, Sha256};
+
+use super::store::{name_key, Answer, Lookup, Store};
+use super::wire::{canonical_name, labels, Reader, WireError, Writer};
+use super::{Message, QuestionClass, QuestionType, RData, Record, ResponseCode};
+
+/// DNSKEY flags of a zone key that is also a secure entry point, so it goes in the DS
+const KEY_FLAGS: u16 = 257;
+/// Signatures are valid from a bit in the past, for clients with slow clocks, to a week from now
+const INCEPTION_OFFSET: u32 = 60 * 60;
+const VALIDITY: u32 = 7 * 24 * 60 * 60;
+
+// https://www.iana.org/assignments/dns-sec-alg-numbers/dns-sec-alg-numbers.xhtml
+#[derive(Debug, Clone, Copy)]
+pub enum Algorithm {
+    /// ECDSA P-256 with SHA-256, number 13
+    EcdsaP256,
+    /// Ed25519, number 15
+    Ed25519,
+}
+
+impl Algorithm {
+    fn number(self) -> u8 {
+        match self {
+            Algorithm::EcdsaP256 => 13,
+            Algorithm::Ed25519 => 15,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::EcdsaP256 => "p256",
+            Algorithm::Ed25519 => "ed25519",
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Algorithm, String> {
+        match s {
+            "p256" => Ok(Algorithm::EcdsaP256),
+            "ed25519" => Ok(Algorithm::Ed25519),
+            _ => Err(format!(
+                "unknown DNSSEC algorithm {}, use ed25519 or p256",
+                s
+            )),
+        }
+    }
+}
+
+enum SigningKey {
+    EcdsaP256(EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+impl SigningKey {
+    /// Reads the PKCS#8 key at `path`, first generating it if there's none
+    fn load(path: &Path, algorithm: Algorithm) -> Result<SigningKey, Box<dyn Error>> {
+        let rng = SystemRandom::new();
+        let pkcs8 = match fs::read(path) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let pkcs8 = match algorithm {
+                    Algorithm::EcdsaP256 => {
+                        EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                    }
+                    Algorithm::Ed25519 => Ed25519KeyPair::generate_pkcs8(&rng),
+                }
+                .map_err(|_| "can't generate a key")?;
+                // it's a private key, so only for us to read
+                let mut file = fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(path)?;
+                io::Write::write_all(&mut file, pkcs8.as_ref())?;
+                println!("Generated {}", path.display());
+                pkcs8.as_ref().to_vec()
+            }
+            Err(e) => return Err(format!("{}: {}", path.display(), e).into()),
+        };
+        let rejected = |e: ring::error::KeyRejected| format!("{}: {}", path.display(), e);
+        Ok(match algorithm {
+            Algorithm::EcdsaP256 => SigningKey::EcdsaP256(
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8)
+                    .map_err(rejected)?,
+            ),
+            Algorithm::Ed25519 => {
+                SigningKey::Ed25519(Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(rejected)?)
+            }
+        })
+    }
+
+    /// The key as it goes in a DNSKEY
+    /// https://datatracker.ietf.org/doc/html/rfc6605#section-4
+    /// https://datatracker.ietf.org/doc/html/rfc8080#section-3
+    fn public_key(&self) -> Vec<u8> {
+        match self {
+            // without the leading 0x04 that marks an uncompressed point
+            SigningKey::EcdsaP256(k) => k.public_key().as_ref()[1..].to_vec(),
+            SigningKey::Ed25519(k) => k.public_key().as_ref().to_vec(),
+        }
+    }
+
+    fn sign(&self, rng: &SystemRandom, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(match self {
+            // r and s, 32 bytes each, as RFC 6605 wants them
+            SigningKey::EcdsaP256(k) => k
+                .sign(rng, data)
+                .map_err(|_| "ECDSA signing failed")?
+                .as_ref()
+                .to_vec(),
+            SigningKey::Ed25519(k) => k.sign(data).as_ref().to_vec(),
+        })
+    }
+}
+
+struct ZoneKey {
+    /// Owner of the zone's SOA
+    apex: String,
+    /// TTL of the DNSKEY, the same as the SOA's
+    ttl: u32,
+    algorithm: Algorithm,
+    key: SigningKey,
+    dnskey: RData,
+    key_tag: u16,
+}
+
+impl ZoneKey {
+    /// The DS record to put in the parent zone, in master file format, with a SHA-256 digest
+    /// https://datatracker.ietf.org/doc/html/rfc4509
+    fn ds_record(&self) -> Result<String, WireError> {
+        let mut w = Writer::canonical();
+        w.name(&self.apex, false)?;
+        self.dnskey.write(&mut w)?;
+        let digest: String = Sha256::digest(w.into_bytes())
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        Ok(format!(
+            "{}. {} IN DS {} {} 2 {}",
+            self.apex,
+            self.ttl,
+            self.key_tag,
+            self.algorithm.number(),
+            digest
+        ))
+    }
+}
+
+/// Signs the replies for every zone it has a key for
+pub struct Signer {
+    /// Where the keys are, one PKCS#8 file per zone
+    dir: String,
+    algorithm: Algorithm,
+    /// By `name_key` of the apex. Zones that show up later, from reloads, get theirs then.
+    zones: RwLock<HashMap<Vec<Vec<u8>>, Arc<ZoneKey>>>,
+    rng: SystemRandom,
+}
+
+impl Signer {
+    /// Signs with the keys in `dir`, of `algorithm`, which `add_keys` loads
+    pub fn new(dir: &str, algorithm: Algorithm) -> Result<Signer, Box<dyn Error>> {
+        fs::create_dir_all(dir)?;
+        Ok(Signer {
+            dir: dir.to_string(),
+            algorithm,
+            zones: RwLock::new(HashMap::new()),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Loads a key for every zone (SOA) in `answers` we don't have one for yet, generating the
+    /// ones missing from the directory, and adds the DNSKEY of every zone in `answers` that
+    /// isn't there already. Returns the DS records of the zones it loaded keys for, sorted.
+    pub fn add_keys(&self, answers: &mut Vec<Answer>) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut lines = vec![];
+        let mut dnskeys = vec![];
+        let mut zones = self.zones.write().unwrap();
+        for soa in answers.iter().filter(|a| a.atype == QuestionType::SOA) {
+            let zone = match zones.get(soa.key()) {
+                Some(zone) => zone.clone(),
+                None => {
+                    let zone = Arc::new(self.load_key(soa)?);
+                    lines.push(zone.ds_record()?);
+                    zones.insert(soa.key().to_vec(), zone.clone());
+                    zone
+                }
+            };
+            dnskeys.push(Answer::new(
+                zone.apex.clone(),
+                QuestionType::DNSKEY,
+                QuestionClass::IN,
+                zone.ttl,
+                zone.dnskey.clone(),
+            ));
+        }
+        drop(zones);
+        for dnskey in dnskeys {
+            let data = dnskey.data.to_canonical().ok();
+            let served = answers.iter().any(|a| {
+                a.key() == dnskey.key()
+                    && a.atype == QuestionType::DNSKEY
+                    && a.data.to_canonical().ok() == data
+            });
+            if !served {
+                answers.push(dnskey);
+            }
+        }
+        lines.sort();
+        Ok(lines)
+    }
+
+    /// The key of the zone of `soa`, from its file in the directory or else a new one
+    fn load_key(&self, soa: &Answer) -> Result<ZoneKey, Box<dyn Error>> {
+        let apex = canonical_name(&soa.name).to_ascii_lowercase();
+        let file = format!(
+            "{}.{}.pk8",
+            if apex.is_empty() { "root" } else { &apex },
+            self.algorithm.name()
+        );
+        let key = SigningKey::load(&Path::new(&self.dir).join(file), self.algorithm)?;
+        let dnskey = RData::DNSKEY {
+            flags: KEY_FLAGS,
+            protocol: 3,
+            algorithm: self.algorithm.number(),
+            public_key: key.public_key(),
+        };
+        let key_tag = key_tag(&dnskey.to_canonical()?);
+        Ok(ZoneKey {
+            apex,
+            ttl: soa.ttl,
+            algorithm: self.algorithm,
+            key,
+            dnskey,
+            key_tag,
+        })
+    }
+
+    /// The key of the closest zone `name` is in, if we sign it
+    fn zone_of(&self, name: &str) -> Option<Arc<ZoneKey>> {
+        let key = name_key(name);
+        let zones = self.zones.read().unwrap();
+        (0..=key.len())
+            .rev()
+            .find_map(|n| zones.get(&key[..n]).cloned())
+    }
+
+    /// Turns a negative answer from one of our signed zones into a compact one: NOERROR with an
+    /// NSEC from the name to the name right after it, so it denies nothing else. Its types are
+    /// what the name has, or NXNAME if it doesn't exist.
+    /// https://datatracker.ietf.org/doc/html/rfc9824#section-3
+    pub fn deny(&self, store: &Store, found: &mut Lookup) {
+        // referrals aren't denials
+        let Some(soa) = found
+            .authorities
+            .first()
+            .filter(|r| r.rtype == QuestionType::SOA)
+        else {
+            return;
+        };
+        if self.zone_of(&found.name).is_none() {
+            return;
+        }
+        let mut types = store
+            .types_at(&found.name)
+            .unwrap_or_else(|| vec![QuestionType::NXNAME]);
+        types.extend([QuestionType::RRSIG, QuestionType::NSEC]);
+        let name = canonical_name(&found.name).to_ascii_lowercase();
+        let next = if name.is_empty() {
+            "\\000".to_string()
+        } else {
+            format!("\\000.{}", name)
+        };
+        let nsec = Record {
+            name: found.name.clone(),
+            rtype: QuestionType::NSEC,
+            class: QuestionClass::IN,
+            // the negative TTL, which the SOA already carries
+            ttl: soa.ttl,
+            data: RData::NSEC { next, types },
+        };
+        found.authorities.push(nsec);
+        found.code = ResponseCode::NoError;
+    }
+
+    /// Adds an RRSIG after every RRset in the answer and authority sections that is in a zone
+    /// we sign
+    pub fn sign(&self, reply: &mut Message) -> Result<(), Box<dyn Error>> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()) as u32;
+        reply.answers = self.sign_section(&reply.answers, now)?;
+        reply.authorities = self.sign_section(&reply.authorities, now)?;
+        Ok(())
+    }
+
+    fn sign_section(&self, records: &[Record], now: u32) -> Result<Vec<Record>, Box<dyn Error>> {
+        let mut signed = vec![];
+        let mut rest = records;
+        while let Some(first) = rest.first() {
+            let n = rest
+                .iter()
+                .take_while(|r| r.rtype == first.rtype && r.name.eq_ignore_ascii_case(&first.name))
+                .count();
+            let (rrset, tail) = rest.split_at(n);
+            signed.extend_from_slice(rrset);
+            // the NS records of a delegation are the child's, and only signed there
+            let delegation = |zone: &ZoneKey| {
+                first.rtype == QuestionType::NS && name_key(&first.name) != name_key(&zone.apex)
+            };
+            match self.zone_of(&first.name) {
+                Some(zone) if !delegation(&zone) => signed.push(self.rrsig(&zone, rrset, now)?),
+                _ => (),
+            }
+            rest = tail;
+        }
+        Ok(signed)
+    }
+
+    /// The RRSIG over `rrset`, signing the RRSIG data without the signature followed by the
+    /// records in canonical form and order
+    /// https://datatracker.ietf.org/doc/html/rfc4034#section-3.1.8.1
+    fn rrsig(&self, zone: &ZoneKey, rrset: &[Record], now: u32) -> Result<Record, Box<dyn Error>> {
+        let first = &rrset[0];
+        let ttl = rrset.iter().map(|r| r.ttl).min().unwrap_or(first.ttl);
+        let owner = labels(&first.name);
+        // answers from wildcards carry the name asked for, so they are signed as if it existed
+        let wildcard = owner.first().is_some_and(|l| l == b"*");
+        let mut rrsig = RData::RRSIG {
+            type_covered: first.rtype,
+            algorithm: zone.algorithm.number(),
+            labels: (owner.len() - usize::from(wildcard)) as u8,
+            original_ttl: ttl,
+            expiration: now.wrapping_add(VALIDITY),
+            inception: now.wrapping_sub(INCEPTION_OFFSET),
+            key_tag: zone.key_tag,
+            signer: zone.apex.clone(),
+            signature: vec![],
+        };
+        let mut data = rrsig.to_canonical()?;
+
+        let mut rdatas = rrset
+            .iter()
+            .map(|r| r.data.to_canonical())
+            .collect::<Result<Vec<_>, _>>()?;
+        rdatas.sort();
+        rdatas.dedup();
+        let mut w = Writer::canonical();
+        for rdata in rdatas {
+            w.name(&first.name, false)?;
+            w.u16(u16::from(first.rtype));
+            w.u16(u16::from(first.class));
+            w.u32(ttl);
+            w.u16(rdata.len() as u16);
+            w.bytes(&rdata);
+        }
+        data.extend(w.into_bytes());
+
+        if let RData::RRSIG { signature, .. } = &mut rrsig {
+            *signature = zone.key.sign(&self.rng, &data)?;
+        }
+        Ok(Record {
+            name: first.name.clone(),
+            rtype: QuestionType::RRSIG,
+            class: first.class,
+            ttl,
+            data: rrsig,
+        })
+    }
+}
+
+/// The key tag of a DNSKEY, a checksum of its data
+/// https://datatracker.ietf.org/doc/html/rfc4034#appendix-B
+fn key_tag(dnskey: &[u8]) -> u16 {
+    let mut ac: u32 = 0;
+    for (i, &b) in dnskey.iter().enumerate() {
+        ac += if i % 2 == 0 {
+            (b as u32) << 8
+        } else {
+            b as u32
+        };
+    }
+    ac += ac >> 16;
+    ac as u16
+}
+
+/// Reads the type bitmap of an NSEC record, which runs up to `end`
+/// https://datatracker.ietf.org/doc/html/rfc4034#section-4.1.2
+pub fn read_type_bitmap(r: &mut Reader, end: usize) -> Result<Vec<QuestionType>, WireError> {
+    let mut types = vec![];
+    while r.pos() < end {
+        let window = r.u8()? as u16;
+        let len = r.u8()? as usize;
+        if !(1..=32).contains(&len) {
+            return Err(WireError::Malformed("bad type bitmap length".to_string()));
+        }
+        for (i, &b) in r.bytes(len)?.iter().enumerate() {
+            for bit in 0..8 {
+                if b & (0x80 >> bit) != 0 {
+                    types.push(QuestionType::from(window << 8 | (i * 8 + bit) as u16));
+                }
+            }
+        }
+    }
+    Ok(types)
+}
+
+/// Writes `types` as a type bitmap: a block per window of 256 types that has any, each only as
+/// long as its last type needs
+pub fn write_type_bitmap(types: &[QuestionType], w: &mut Writer) {
+    let mut types: Vec<u16> = types.iter().map(|&t| u16::from(t)).collect();
+    types.sort();
+    types.dedup();
+    for block in types.chunk_by(|a, b| a >> 8 == b >> 8) {
+        let last = *block.last().unwrap() as usize & 0xFF;
+        let mut bitmap = vec![0u8; last / 8 + 1];
+        for &t in block {
+            let t = t as usize & 0xFF;
+            bitmap[t / 8] |= 0x80 >> (t % 8);
+        }
+        w.u8((block[0] >> 8) as u8);
+        w.u8(bitmap.len() as u8);
+        w.bytes(&bitmap);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED, ED25519};
+
+    use super::super::{zone, Question};
+    use super::*;
+
+    const ZONE: &str = "$TTL 300
+@ SOA ns hostmaster 1 3600 600 86400 30
+@ NS ns
+ns A 192.0.2.53
+www A 192.0.2.2
+www A 192.0.2.1
+*.wild TXT \"wild\"
+child NS ns.child
+ns.child A 192.0.2.54
+";
+
+    /// A signer for `zone` with keys in a directory of its own, and the records with the DNSKEYs
+    fn signer(name: &str, algorithm: Algorithm, zone: &str) -> (Signer, Store) {
+        let dir = std::env::temp_dir().join(format!("dnssec-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let signer = Signer::new(dir.to_str().unwrap(), algorithm).unwrap();
+        let mut answers = zone::parse(zone, "example.com").unwrap();
+        assert_eq!(signer.add_keys(&mut answers).unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+        (signer, Store::new(answers))
+    }
+
+    fn lookup(store: &Store, name: &str, qtype: QuestionType) -> (Message, Lookup) {
+        let q = Question {
+            domain: name.to_string(),
+            qtype,
+            class: QuestionClass::IN,
+        };
+        let found = store.lookup(&q, 0);
+        (Message::query(1, q).reply(ResponseCode::NoError), found)
+    }
+
+    /// The reply to `name` and `qtype`, as it goes out to clients with DO set
+    fn signed(signer: &Signer, store: &Store, name: &str, qtype: QuestionType) -> Message {
+        let (mut reply, mut found) = lookup(store, name, qtype);
+        signer.deny(store, &mut found);
+        reply.header.flags.response_code = found.code;
+        reply.answers = found.answers;
+        reply.authorities = found.authorities;
+        signer.sign(&mut reply).unwrap();
+        reply
+    }
+
+    /// What the RRSIG signs, worked out from the RFC rather than from `Signer::rrsig`
+    /// https://datatracker.ietf.org/doc/html/rfc4034#section-3.1.8.1
+    fn signing_input(rrsig: &Record, rrset: &[&Record]) -> Vec<u8> {
+        let RData::RRSIG {
+            type_covered,
+            algorithm,
+            labels,
+            original_ttl,
+            expiration,
+            inception,
+            key_tag,
+            signer,
+            ..
+        } = &rrsig.data
+        else {
+            panic!("not an RRSIG: {}", rrsig);
+        };
+        let mut w = Writer::canonical();
+        w.u16(u16::from(*type_covered));
+        w.u8(*algorithm);
+        w.u8(*labels);
+        w.u32(*original_ttl);
+        w.u32(*expiration);
+        w.u32(*inception);
+        w.u16(*key_tag);
+        w.name(signer, false).unwrap();
+        let mut rdatas: Vec<Vec<u8>> = rrset
+            .iter()
+            .map(|r| r.data.to_canonical().unwrap())
+            .collect();
+        rdatas.sort();
+        for rdata in rdatas {
+            w.name(&rrset[0].name, false).unwrap();
+            w.u16(u16::from(rrset[0].rtype));
+            w.u16(u16::from(rrset[0].class));
+            w.u32(*original_ttl);
+            w.u16(rdata.len() as u16);
+            w.bytes(&rdata);
+        }
+        w.into_bytes()
+    }
+
+    /// Checks the RRSIGs in `records` against the DNSKEY served at the apex, returning which
+    /// types they covered
+    fn verify(store: &Store, records: &[Record]) -> Vec<QuestionType> {
+        let dnskey = store
+            .owned_by("example.com")
+            .into_iter()
+            .find(|a| a.atype == QuestionType::DNSKEY)
+            .unwrap();
+        let RData::DNSKEY {
+            algorithm,
+            public_key,
+            ..
+        } = &dnskey.data
+        else {
+            unreachable!("DNSKEY without DNSKEY data")
+        };
+        let mut covered = vec![];
+        for rrsig in records.iter().filter(|r| r.rtype == QuestionType::RRSIG) {
+            let RData::RRSIG {
+                type_covered,
+                key_tag: tag,
+                signature,
+                signer,
+                ..
+            } = &rrsig.data
+            else {
+                unreachable!("RRSIG without RRSIG data")
+            };
+            assert_eq!(*tag, key_tag(&dnskey.data.to_canonical().unwrap()));
+            assert_eq!(signer, "example.com");
+            let rrset: Vec<&Record> = records
+                .iter()
+                .filter(|r| r.rtype == *type_covered && r.name == rrsig.name)
+                .collect();
+            let data = signing_input(rrsig, &rrset);
+            let verified = match algorithm {
+                13 => UnparsedPublicKey::new(
+                    &ECDSA_P256_SHA256_FIXED,
+                    [&[4][..], public_key].concat(),
+                )
+                .verify(&data, signature),
+                15 => UnparsedPublicKey::new(&ED25519, public_key).verify(&data, signature),
+                a => panic!("unexpected algorithm {}", a),
+            };
+            assert!(verified.is_ok(), "bad signature over {}", rrset[0]);
+            covered.push(*type_covered);
+        }
+        covered
+    }
+
+    #[test]
+    fn rrsigs_verify_with_the_dnskey() {
+        for (name, algorithm) in [
+            ("ed25519", Algorithm::Ed25519),
+            ("p256", Algorithm::EcdsaP256),
+        ] {
+            let (signer, store) = signer(name, algorithm, ZONE);
+            let reply = signed(&signer, &store, "www.example.com", QuestionType::A);
+            assert_eq!(verify(&store, &reply.answers), [QuestionType::A]);
+            let reply = signed(&signer, &store, "example.com", QuestionType::DNSKEY);
+            assert_eq!(verify(&store, &reply.answers), [QuestionType::DNSKEY]);
+        }
+    }
+
+    #[test]
+    fn wildcard_answers_are_signed_with_the_wildcard_labels() {
+        let (signer, store) = signer("wildcard", Algorithm::Ed25519, ZONE);
+        let (mut reply, found) = lookup(&store, "a.wild.example.com", QuestionType::TXT);
+        // as the store keeps it, owned by the wildcard, which the server then renames
+        reply.answers = store
+            .owned_by("*.wild.example.com")
+            .iter()
+            .map(|a| a.to_record(&a.name))
+            .collect();
+        assert_eq!(found.answers.len(), 1);
+        signer.sign(&mut reply).unwrap();
+        let RData::RRSIG { labels, .. } = reply.answers[1].data else {
+            panic!("no RRSIG")
+        };
+        assert_eq!(labels, 3);
+        verify(&store, &reply.answers);
+    }
+
+    #[test]
+    fn delegations_are_left_unsigned() {
+        let (signer, store) = signer("delegation", Algorithm::Ed25519, ZONE);
+        let reply = signed(&signer, &store, "www.child.example.com", QuestionType::A);
+        assert_eq!(reply.authorities.len(), 1);
+        assert_eq!(reply.authorities[0].rtype, QuestionType::NS);
+        // the apex's NS records are ours, though
+        let reply = signed(&signer, &store, "example.com", QuestionType::NS);
+        assert_eq!(verify(&store, &reply.answers), [QuestionType::NS]);
+    }
+
+    #[test]
+    fn nxdomain_becomes_nodata_with_nxname() {
+        let (signer, store) = signer("nxdomain", Algorithm::Ed25519, ZONE);
+        let reply = signed(&signer, &store, "nothere.example.com", QuestionType::A);
+        assert_eq!(reply.header.flags.response_code, ResponseCode::NoError);
+        assert!(reply.answers.is_empty());
+        let nsec = reply
+            .authorities
+            .iter()
+            .find(|r| r.rtype == QuestionType::NSEC)
+            .unwrap();
+        assert_eq!(nsec.name, "nothere.example.com");
+        assert_eq!(nsec.ttl, 30);
+        let RData::NSEC { next, types } = &nsec.data else {
+            unreachable!("NSEC without NSEC data")
+        };
+        assert_eq!(next, "\\000.nothere.example.com");
+        assert_eq!(
+            types,
+            &[
+                QuestionType::NXNAME,
+                QuestionType::RRSIG,
+                QuestionType::NSEC
+            ]
+        );
+        let mut covered = verify(&store, &reply.authorities);
+        covered.sort_by_key(|&t| u16::from(t));
+        assert_eq!(covered, [QuestionType::SOA, QuestionType::NSEC]);
+    }
+
+    #[test]
+    fn nodata_has_the_types_of_the_name() {
+        let (signer, store) = signer("nodata", Algorithm::Ed25519, ZONE);
+        let reply = signed(&signer, &store, "www.example.com", QuestionType::TXT);
+        assert_eq!(reply.header.flags.response_code, ResponseCode::NoError);
+        let nsec = reply
+            .authorities
+            .iter()
+            .find(|r| r.rtype == QuestionType::NSEC)
+            .unwrap();
+        let RData::NSEC { next, types } = &nsec.data else {
+            unreachable!("NSEC without NSEC data")
+        };
+        assert_eq!(next, "\\000.www.example.com");
+        assert_eq!(
+            types,
+            &[QuestionType::A, QuestionType::RRSIG, QuestionType::NSEC]
+        );
+    }
+
+    #[test]
+    fn keys_come_for_new_zones_only() {
+        let (signer, store) = signer("new-zones", Algorithm::Ed25519, ZONE);
+        let mut answers = store.answers().to_vec();
+        let before = answers.len();
+        // no new zone, no new key and no second DNSKEY
+        assert!(signer.add_keys(&mut answers).unwrap().is_empty());
+        assert_eq!(answers.len(), before);
+        let text = "$TTL 60\n@ SOA ns hm 1 2 3 4 5\n@ NS ns.example.com.\n";
+        answers.extend(zone::parse(text, "example.net").unwrap());
+        let dir = Path::new(&signer.dir);
+        fs::create_dir_all(dir).unwrap();
+        let ds = signer.add_keys(&mut answers).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        assert_eq!(ds.len(), 1);
+        assert!(ds[0].starts_with("example.net. 60 IN DS "));
+        let store = Store::new(answers);
+        assert_eq!(store.owned_by("example.net")[2].atype, QuestionType::DNSKEY);
+        assert!(signer.zone_of("www.example.net").is_some());
+    }
+
+    #[test]
+    fn key_tags() {
+        let tag = |flags: u16, algorithm: u8, key: &str| {
+            let dnskey = RData::DNSKEY {
+                flags,
+                protocol: 3,
+                algorithm,
+                public_key: base64::decode(key).unwrap(),
+            };
+            key_tag(&dnskey.to_canonical().unwrap())
+        };
+        // https://datatracker.ietf.org/doc/html/rfc4034#section-5.4
+        let example = "AQOeiiR0GOMYkDshWoSKz9XzfwJr1AYtsmx3TGkJaNXVbfi/2pHm822aJ5iI9BMzNXxeYCmZ\
+                       DRD99WYwYqUSdjMmmAphXdvxegXd/M5+X7OrzKBaMbCVdFLUUh6DhweJBjEVv5f2wwjM9Xzc\
+                       nOf+EPbtG9DMBmADjFDc2w/rljwvFw==";
+        assert_eq!(tag(256, 5, example), 60485);
+        // the root zone's KSK-2017
+        let root = "AwEAAaz/tAm8yTn4Mfeh5eyI96WSVexTBAvkMgJzkKTOiW1vkIbzxeF3+/4RgWOq7HrxRixH\
+                    lFlExOLAJr5emLvN7SWXgnLh4+B5xQlNVz8Og8kvArMtNROxVQuCaSnIDdD5LKyWbRd2n9WG\
+                    e2R8PzgCmr3EgVLrjyBxWezF0jLHwVN8efS3rCj/EWgvIWgb9tarpVUDK/b58Da+sqqls3eN\
+                    buv7pr+eoZG+SrDK6nWeL3c6H5Apxz7LjVc1uTIdsIXxuOLYA4/ilBmSVIzuDWfdRUfhHdY6\
+                    +cn8HFRm+2hM8AnXGXws9555KrUB5qihylGa8subX2Nn6UwNR1AkUTV74bU=";
+        assert_eq!(tag(257, 8, root), 20326);
+    }
+
+    #[test]
+    fn type_bitmaps() {
+        // https://datatracker.ietf.org/doc/html/rfc4034#section-4.3
+        let types = [
+            QuestionType::A,
+            QuestionType::MX,
+            QuestionType::RRSIG,
+            QuestionType::NSEC,
+            QuestionType::from(1234),
+        ];
+        let mut w = Writer::new();
+        write_type_bitmap(&types, &mut w);
+        let bytes = w.into_bytes();
+        let mut expected = vec![0x00, 0x06, 0x40, 0x01, 0x00, 0x00, 0x00, 0x03];
+        expected.extend([0x04, 0x1b]);
+        expected.extend([0; 26]);
+        expected.push(0x20);
+        assert_eq!(bytes, expected);
+        let read = read_type_bitmap(&mut Reader::new(&bytes), bytes.len()).unwrap();
+        assert_eq!(read, types);
+
+        // out of order and repeated, and in windows 0, 1 and 255
+        let types = [
+            QuestionType::from(65535),
+            QuestionType::CAA,
+            QuestionType::A,
+            QuestionType::NXNAME,
+            QuestionType::A,
+            QuestionType::from(256),
+        ];
+        let mut w = Writer::new();
+        write_type_bitmap(&types, &mut w);
+        let bytes = w.into_bytes();
+        let read = read_type_bitmap(&mut Reader::new(&bytes), bytes.len()).unwrap();
+        let read: Vec<u16> = read.into_iter().map(u16::from).collect();
+        assert_eq!(read, [1, 128, 256, 257, 65535]);
+    }
+
+    #[test]
+    fn bad_type_bitmaps() {
+        for bytes in [&[0x00, 0x00][..], &[0x00, 33], &[0x00, 0x02, 0x40]] {
+            let read = read_type_bitmap(&mut Reader::new(bytes), bytes.len());
+            assert!(read.is_err(), "{:?}", bytes);
+        }
+    }
+}
@@ -0,0 +1,427 @@
+// dnstap: every query and reply as a protobuf message, in a Frame Streams file or sent to a
+// collector listening on a unix socket, to be looked into afterwards
+// https://dnstap.info/
+// https://github.com/farsightsec/fstrm/blob/master/fstrm/control.h
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::IpAddr;
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{Protocol, Served};
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+/// How many frames can wait to be written, before we start dropping them rather than holding up
+/// replies
+const BACKLOG: usize = 10_000;
+
+// Frame Streams control frames, which come after a zero length where data frames have theirs
+const CONTROL_ACCEPT: u32 = 1;
+const CONTROL_START: u32 = 2;
+const CONTROL_STOP: u32 = 3;
+const CONTROL_READY: u32 = 4;
+const CONTROL_FINISH: u32 = 5;
+const FIELD_CONTENT_TYPE: u32 = 1;
+
+// from dnstap.proto
+const DNSTAP_MESSAGE: u64 = 1;
+const AUTH_QUERY: u64 = 1;
+const AUTH_RESPONSE: u64 = 2;
+const CLIENT_QUERY: u64 = 5;
+const CLIENT_RESPONSE: u64 = 6;
+const INET: u64 = 1;
+const INET6: u64 = 2;
+const UDP: u64 = 1;
+const TCP: u64 = 2;
+const DOT: u64 = 3;
+const DOH: u64 = 4;
+
+/// A control frame of `kind`, with our content type unless it's a STOP, which has no fields
+fn control(kind: u32) -> Vec<u8> {
+    let mut body = kind.to_be_bytes().to_vec();
+    if kind != CONTROL_STOP {
+        body.extend(FIELD_CONTENT_TYPE.to_be_bytes());
+        body.extend((CONTENT_TYPE.len() as u32).to_be_bytes());
+        body.extend(CONTENT_TYPE);
+    }
+    let mut frame = 0u32.to_be_bytes().to_vec();
+    frame.extend((body.len() as u32).to_be_bytes());
+    frame.extend(body);
+    frame
+}
+
+/// Reads a control frame off `stream`, returning its kind
+fn read_control<S: Read>(stream: &mut S) -> io::Result<u32> {
+    let mut word = [0; 4];
+    stream.read_exact(&mut word)?;
+    if word != [0; 4] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "expected a control frame",
+        ));
+    }
+    stream.read_exact(&mut word)?;
+    let mut body = vec![0; u32::from_be_bytes(word) as usize];
+    stream.read_exact(&mut body)?;
+    let kind = body.get(..4).ok_or(io::ErrorKind::InvalidData)?;
+    Ok(u32::from_be_bytes(kind.try_into().unwrap()))
+}
+
+/// Protocol Buffers encoding, just what dnstap needs of it
+/// https://protobuf.dev/programming-guides/encoding/
+#[derive(Default)]
+struct Protobuf(Vec<u8>);
+
+impl Protobuf {
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.0.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        self.0.push(v as u8);
+    }
+
+    fn key(&mut self, field: u64, wire_type: u64) {
+        self.varint(field << 3 | wire_type);
+    }
+
+    fn uint(&mut self, field: u64, v: u64) {
+        self.key(field, 0);
+        self.varint(v);
+    }
+
+    fn fixed32(&mut self, field: u64, v: u32) {
+        self.key(field, 5);
+        self.0.extend(v.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u64, b: &[u8]) {
+        self.key(field, 2);
+        self.varint(b.len() as u64);
+        self.0.extend(b);
+    }
+}
+
+/// The Dnstap message for one side of `served`
+fn encode(served: &Served, recursive: bool, response: bool) -> Vec<u8> {
+    let kind = match (recursive, response) {
+        (false, false) => AUTH_QUERY,
+        (false, true) => AUTH_RESPONSE,
+        (true, false) => CLIENT_QUERY,
+        (true, true) => CLIENT_RESPONSE,
+    };
+    let (family, address) = match served.client.ip() {
+        IpAddr::V4(ip) => (INET, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (INET6, ip.octets().to_vec()),
+    };
+    let protocol = match served.protocol {
+        Protocol::Udp => UDP,
+        Protocol::Tcp => TCP,
+        Protocol::Tls => DOT,
+        Protocol::Https => DOH,
+    };
+    let since_epoch = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let received = since_epoch(served.received);
+    let mut message = Protobuf::default();
+    message.uint(1, kind);
+    message.uint(2, family);
+    message.uint(3, protocol);
+    message.bytes(4, &address);
+    message.uint(6, served.client.port() as u64);
+    message.uint(8, received.as_secs());
+    message.fixed32(9, received.subsec_nanos());
+    if response {
+        let replied = since_epoch(served.received + served.latency);
+        message.uint(12, replied.as_secs());
+        message.fixed32(13, replied.subsec_nanos());
+        message.bytes(14, served.reply);
+    } else {
+        message.bytes(10, served.query);
+    }
+    let mut dnstap = Protobuf::default();
+    dnstap.bytes(
+        2,
+        concat!("hackattic ", env!("CARGO_PKG_VERSION")).as_bytes(),
+    );
+    dnstap.bytes(14, &message.0);
+    dnstap.uint(15, DNSTAP_MESSAGE);
+    dnstap.0
+}
+
+/// Writes the frames that come through `frames` to `stream`, flushing once there's none left
+/// waiting rather than after every one, and a STOP once the channel closes
+fn write_frames<W: Write>(mut stream: W, frames: Receiver<Vec<u8>>) -> io::Result<()> {
+    for first in &frames {
+        for frame in std::iter::once(first).chain(frames.try_iter()) {
+            stream.write_all(&(frame.len() as u32).to_be_bytes())?;
+            stream.write_all(&frame)?;
+        }
+        stream.flush()?;
+    }
+    stream.write_all(&control(CONTROL_STOP))?;
+    stream.flush()
+}
+
+/// Where the frames go, written by a thread of its own so replies don't wait on it
+pub struct Dnstap {
+    frames: SyncSender<Vec<u8>>,
+    /// Whether we answer for clients that ask for recursion, so their queries are logged as a
+    /// resolver's rather than an authoritative server's
+    recursive: bool,
+}
+
+impl Dnstap {
+    /// Starts writing to `target`, a file or `unix:PATH` for a collector's socket
+    pub fn open(target: &str, recursive: bool) -> io::Result<Dnstap> {
+        // and where the collector's FINISH comes back from, after our STOP
+        let mut finish = None;
+        let stream: Box<dyn Write + Send> = match target.strip_prefix("unix:") {
+            // bidirectional, so the collector gets to say it takes dnstap
+            Some(path) => {
+                let mut socket = UnixStream::connect(path)?;
+                socket.write_all(&control(CONTROL_READY))?;
+                if read_control(&mut socket)? != CONTROL_ACCEPT {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "collector didn't accept dnstap",
+                    ));
+                }
+                finish = Some(socket.try_clone()?);
+                Box::new(socket)
+            }
+            None => Box::new(File::create(target)?),
+        };
+        let mut stream = BufWriter::new(stream);
+        stream.write_all(&control(CONTROL_START))?;
+        stream.flush()?;
+        let (frames, received) = mpsc::sync_channel::<Vec<u8>>(BACKLOG);
+        let target = target.to_string();
+        thread::spawn(move || {
+            let stopped = write_frames(stream, received).and_then(|()| {
+                match finish.as_mut().map(read_control).transpose()? {
+                    Some(kind) if kind != CONTROL_FINISH => Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "collector didn't finish",
+                    )),
+                    _ => Ok(()),
+                }
+            });
+            if let Err(e) = stopped {
+                println!("dnstap to {} stopped: {}", target, e);
+            }
+        });
+        Ok(Dnstap { frames, recursive })
+    }
+
+    /// Logs the query and the reply in `served`, if one went back, unless too many are waiting
+    /// to be written
+    pub fn log(&self, served: &Served) {
+        let recursive = self.recursive && served.recursion_desired;
+        let _ = self.frames.try_send(encode(served, recursive, false));
+        // none if the rate limit dropped it
+        if !served.reply.is_empty() {
+            let _ = self.frames.try_send(encode(served, recursive, true));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::net::UnixListener;
+    use std::process;
+    use std::time::Duration;
+
+    use super::super::ResponseCode;
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Value {
+        Varint(u64),
+        Fixed32(u32),
+        Bytes(Vec<u8>),
+    }
+
+    /// The fields of a protobuf message, in order
+    fn decode(mut b: &[u8]) -> Vec<(u64, Value)> {
+        let varint = |b: &mut &[u8]| {
+            let mut v = 0;
+            for shift in (0..64).step_by(7) {
+                let byte = b[0];
+                *b = &b[1..];
+                v |= ((byte & 0x7f) as u64) << shift;
+                if byte < 0x80 {
+                    break;
+                }
+            }
+            v
+        };
+        let mut fields = vec![];
+        while !b.is_empty() {
+            let key = varint(&mut b);
+            let value = match key & 7 {
+                0 => Value::Varint(varint(&mut b)),
+                2 => {
+                    let len = varint(&mut b) as usize;
+                    let (value, rest) = b.split_at(len);
+                    b = rest;
+                    Value::Bytes(value.to_vec())
+                }
+                5 => {
+                    let (value, rest) = b.split_at(4);
+                    b = rest;
+                    Value::Fixed32(u32::from_le_bytes(value.try_into().unwrap()))
+                }
+                t => panic!("unexpected wire type {}", t),
+            };
+            fields.push((key >> 3, value));
+        }
+        fields
+    }
+
+    /// The fields of the Message inside a Dnstap, checking the rest of the Dnstap
+    fn message(dnstap: &[u8]) -> Vec<(u64, Value)> {
+        let mut fields = decode(dnstap);
+        let identity = format!("hackattic {}", env!("CARGO_PKG_VERSION"));
+        assert_eq!(fields[0], (2, Value::Bytes(identity.into_bytes())));
+        assert_eq!(fields[2], (15, Value::Varint(DNSTAP_MESSAGE)));
+        match fields.remove(1) {
+            (14, Value::Bytes(message)) => decode(&message),
+            field => panic!("not a message: {:?}", field),
+        }
+    }
+
+    fn served<'a>(
+        client: &str,
+        protocol: Protocol,
+        query: &'a [u8],
+        reply: &'a [u8],
+    ) -> Served<'a> {
+        Served {
+            client: client.parse().unwrap(),
+            protocol,
+            query,
+            reply,
+            question: None,
+            recursion_desired: true,
+            code: ResponseCode::NoError,
+            limited: None,
+            received: UNIX_EPOCH + Duration::new(1_700_000_000, 999_000_000),
+            latency: Duration::from_millis(2),
+        }
+    }
+
+    #[test]
+    fn queries() {
+        let served = served("192.0.2.1:5300", Protocol::Udp, b"query", b"reply");
+        assert_eq!(
+            message(&encode(&served, false, false)),
+            [
+                (1, Value::Varint(AUTH_QUERY)),
+                (2, Value::Varint(INET)),
+                (3, Value::Varint(UDP)),
+                (4, Value::Bytes(vec![192, 0, 2, 1])),
+                (6, Value::Varint(5300)),
+                (8, Value::Varint(1_700_000_000)),
+                (9, Value::Fixed32(999_000_000)),
+                (10, Value::Bytes(b"query".to_vec())),
+            ]
+        );
+    }
+
+    #[test]
+    fn responses() {
+        let tls = served("[2001:db8::1]:853", Protocol::Tls, b"query", b"reply");
+        let mut address = vec![0x20, 0x01, 0x0d, 0xb8];
+        address.extend([0; 11]);
+        address.push(1);
+        assert_eq!(
+            message(&encode(&tls, true, true)),
+            [
+                (1, Value::Varint(CLIENT_RESPONSE)),
+                (2, Value::Varint(INET6)),
+                (3, Value::Varint(DOT)),
+                (4, Value::Bytes(address)),
+                (6, Value::Varint(853)),
+                (8, Value::Varint(1_700_000_000)),
+                (9, Value::Fixed32(999_000_000)),
+                // the 2ms it took go into the next second
+                (12, Value::Varint(1_700_000_001)),
+                (13, Value::Fixed32(1_000_000)),
+                (14, Value::Bytes(b"reply".to_vec())),
+            ]
+        );
+        let https = served("192.0.2.1:5300", Protocol::Https, b"query", b"reply");
+        let fields = message(&encode(&https, false, true));
+        assert_eq!(fields[0], (1, Value::Varint(AUTH_RESPONSE)));
+        assert_eq!(fields[2], (3, Value::Varint(DOH)));
+        let fields = message(&encode(&https, true, false));
+        assert_eq!(fields[0], (1, Value::Varint(CLIENT_QUERY)));
+    }
+
+    #[test]
+    fn control_frames() {
+        let mut ready = vec![0, 0, 0, 0, 0, 0, 0, 34, 0, 0, 0, 4, 0, 0, 0, 1, 0, 0, 0, 22];
+        ready.extend(b"protobuf:dnstap.Dnstap");
+        assert_eq!(control(CONTROL_READY), ready);
+        for (kind, frame) in [(CONTROL_ACCEPT, 1), (CONTROL_START, 2)] {
+            let mut expected = ready.clone();
+            expected[11] = frame;
+            assert_eq!(control(kind), expected);
+        }
+        assert_eq!(control(CONTROL_STOP), [0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 3]);
+        assert_eq!(read_control(&mut &control(CONTROL_ACCEPT)[..]).unwrap(), 1);
+        // a data frame where a control frame should be
+        assert!(read_control(&mut &[0, 0, 0, 1, 0][..]).is_err());
+    }
+
+    #[test]
+    fn frames_then_stop() {
+        let (frames, received) = mpsc::sync_channel(2);
+        frames.send(b"one".to_vec()).unwrap();
+        frames.send(b"three".to_vec()).unwrap();
+        drop(frames);
+        let mut written = vec![];
+        write_frames(&mut written, received).unwrap();
+        let mut expected = b"\0\0\0\x03one\0\0\0\x05three".to_vec();
+        expected.extend(control(CONTROL_STOP));
+        assert_eq!(written, expected);
+    }
+
+    /// The length-prefixed frame on `stream`
+    fn read_frame(stream: &mut UnixStream) -> Vec<u8> {
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut frame).unwrap();
+        frame
+    }
+
+    #[test]
+    fn collectors() {
+        let path = std::env::temp_dir().join(format!("dnstap-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let collector = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_control(&mut stream).unwrap(), CONTROL_READY);
+            stream.write_all(&control(CONTROL_ACCEPT)).unwrap();
+            assert_eq!(read_control(&mut stream).unwrap(), CONTROL_START);
+            let query = read_frame(&mut stream);
+            let reply = read_frame(&mut stream);
+            assert_eq!(read_control(&mut stream).unwrap(), CONTROL_STOP);
+            stream.write_all(&control(CONTROL_FINISH)).unwrap();
+            (message(&query), message(&reply))
+        });
+        let dnstap = Dnstap::open(&format!("unix:{}", path.display()), false).unwrap();
+        dnstap.log(&served("192.0.2.1:5300", Protocol::Tcp, b"query", b"reply"));
+        drop(dnstap);
+        let (query, reply) = collector.join().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(query[0], (1, Value::Varint(AUTH_QUERY)));
+        assert_eq!(query[2], (3, Value::Varint(TCP)));
+        assert_eq!(reply[0], (1, Value::Varint(AUTH_RESPONSE)));
+    }
+}
@@ -0,0 +1,411 @@
+// DNS over HTTPS: queries come to /dns-query as the body of a POST, or base64url encoded in the
+// `dns` parameter of a GET, and replies go back as application/dns-message. HTTP/1.1 only, with
+// keep-alive, over whatever stream the TLS is on.
+// https://datatracker.ietf.org/doc/html/rfc8484
+use std::io::{self, Read, Write};
+
+use super::Message;
+
+const PATH: &str = "/dns-query";
+const CONTENT_TYPE: &str = "application/dns-message";
+/// Most headers a request can have
+const MAX_HEADERS: usize = 32;
+/// Longest a request can be without its body
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+pub(super) struct Request {
+    pub(super) method: String,
+    /// With the query string
+    pub(super) target: String,
+    content_type: Option<String>,
+    body: Vec<u8>,
+    /// The client wants the connection closed after the reply
+    pub(super) close: bool,
+}
+
+/// The next request on `stream`, `None` if the client closed it. `buf` keeps whatever came
+/// after the request, as clients may send the next one right away.
+pub(super) fn read_request<S: Read>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+) -> io::Result<Option<Request>> {
+    let invalid = |e: &str| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+    let mut chunk = [0; 4096];
+    loop {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        if let httparse::Status::Complete(head_len) =
+            req.parse(buf).map_err(|e| invalid(&e.to_string()))?
+        {
+            let header = |name: &str| {
+                req.headers
+                    .iter()
+                    .find(|h| h.name.eq_ignore_ascii_case(name))
+                    .map(|h| String::from_utf8_lossy(h.value).trim().to_ascii_lowercase())
+            };
+            // chunked bodies would need a parser of their own, and DNS messages are short
+            // enough that no client needs them
+            if header("transfer-encoding").is_some() {
+                return Err(invalid("Transfer-Encoding not supported"));
+            }
+            let content_length: usize = match header("content-length") {
+                Some(len) => len.parse().map_err(|_| invalid("bad Content-Length"))?,
+                None => 0,
+            };
+            if content_length > u16::MAX as usize {
+                return Err(invalid("request too long for a DNS message"));
+            }
+            // HTTP/1.0 closes unless asked not to, HTTP/1.1 the other way around
+            let close = match header("connection").as_deref() {
+                Some("close") => true,
+                Some("keep-alive") => false,
+                _ => req.version == Some(0),
+            };
+            let mut request = Request {
+                method: req.method.unwrap_or_default().to_string(),
+                target: req.path.unwrap_or_default().to_string(),
+                content_type: header("content-type"),
+                body: vec![],
+                close,
+            };
+            buf.drain(..head_len);
+            while buf.len() < content_length {
+                let n = stream.read(&mut chunk)?;
+                if n == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            request.body = buf.drain(..content_length).collect();
+            return Ok(Some(request));
+        }
+        if buf.len() > MAX_HEAD_LEN {
+            return Err(invalid("request head too long"));
+        }
+        let n = stream.read(&mut chunk)?;
+        if n == 0 {
+            return match buf.is_empty() {
+                true => Ok(None),
+                false => Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// The DNS message in `request`, or the status to reply with if there's none
+fn query(request: &Request) -> Result<Vec<u8>, (u16, &'static str)> {
+    let (path, params) = request
+        .target
+        .split_once('?')
+        .unwrap_or((&request.target, ""));
+    if path != PATH {
+        return Err((404, "Not Found"));
+    }
+    match request.method.as_str() {
+        "GET" => params
+            .split('&')
+            .find_map(|p| p.strip_prefix("dns="))
+            .and_then(|dns| base64::decode_config(dns, base64::URL_SAFE_NO_PAD).ok())
+            .ok_or((400, "Bad Request")),
+        "POST" if request.content_type.as_deref() != Some(CONTENT_TYPE) => {
+            Err((415, "Unsupported Media Type"))
+        }
+        "POST" => Ok(request.body.clone()),
+        _ => Err((405, "Method Not Allowed")),
+    }
+}
+
+/// How long the reply can be cached: as long as its records last
+/// https://datatracker.ietf.org/doc/html/rfc8484#section-5.1
+fn max_age(reply: &[u8]) -> Option<u32> {
+    let m = Message::from_bytes(reply).ok()?;
+    m.answers
+        .iter()
+        .chain(&m.authorities)
+        .chain(&m.additionals)
+        .map(|r| r.ttl)
+        .min()
+}
+
+fn write_response<S: Write>(
+    stream: &mut S,
+    (status, reason): (u16, &str),
+    body: &[u8],
+    close: bool,
+) -> io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n",
+        status,
+        reason,
+        body.len()
+    );
+    if status == 200 {
+        head.push_str(&format!("Content-Type: {}\r\n", CONTENT_TYPE));
+        if let Some(age) = max_age(body) {
+            head.push_str(&format!("Cache-Control: max-age={}\r\n", age));
+        }
+    }
+    if status == 405 {
+        head.push_str("Allow: GET, POST\r\n");
+    }
+    if close {
+        head.push_str("Connection: close\r\n");
+    }
+    head.push_str("\r\n");
+    let mut response = head.into_bytes();
+    response.extend_from_slice(body);
+    stream.write_all(&response)?;
+    stream.flush()
+}
+
+/// Answers the DoH requests on `stream` with `answer`, which gets the DNS message of each and
+/// returns the reply, if there's one
+pub fn serve_connection<S: Read + Write>(
+    mut stream: S,
+    answer: impl Fn(&[u8]) -> Option<Vec<u8>>,
+) -> io::Result<()> {
+    let mut buf = vec![];
+    loop {
+        let request = match read_request(&mut stream, &mut buf) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                return write_response(&mut stream, (400, "Bad Request"), &[], true);
+            }
+            Err(e) => return Err(e),
+        };
+        match query(&request).map(|q| answer(&q)) {
+            Ok(Some(reply)) => write_response(&mut stream, (200, "OK"), &reply, request.close)?,
+            // only answers don't get a reply
+            Ok(None) => write_response(&mut stream, (400, "Bad Request"), &[], request.close)?,
+            Err(status) => write_response(&mut stream, status, &[], request.close)?,
+        }
+        if request.close {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{zone, Question, QuestionClass, QuestionType, ResponseCode};
+    use super::*;
+
+    /// A connection with `input` from the client, keeping what goes back
+    struct Stream {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A query for example.com
+    fn dns_query() -> Vec<u8> {
+        let q = Question {
+            domain: "example.com".to_string(),
+            qtype: QuestionType::A,
+            class: QuestionClass::IN,
+        };
+        Message::query(0, q).to_bytes().unwrap()
+    }
+
+    /// The reply to a query, with records that last 300 and 60 seconds
+    fn dns_reply(query: &[u8]) -> Option<Vec<u8>> {
+        let query = Message::from_bytes(query).ok()?;
+        let mut reply = query.reply(ResponseCode::NoError);
+        let text = "@ 300 A 192.0.2.1\n@ 60 A 192.0.2.2\n";
+        reply.answers = zone::parse(text, "example.com")
+            .unwrap()
+            .iter()
+            .map(|a| a.to_record(&a.name))
+            .collect();
+        Some(reply.to_bytes().unwrap())
+    }
+
+    /// The status line and headers, lowercased, and the body of each response in `output`
+    fn responses(mut output: &[u8]) -> Vec<(Vec<String>, Vec<u8>)> {
+        let mut responses = vec![];
+        while !output.is_empty() {
+            let end = output.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+            let head: Vec<String> = String::from_utf8(output[..end].to_vec())
+                .unwrap()
+                .split("\r\n")
+                .map(|l| l.to_ascii_lowercase())
+                .collect();
+            let len: usize = head
+                .iter()
+                .find_map(|h| h.strip_prefix("content-length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            output = &output[end + 4..];
+            responses.push((head, output[..len].to_vec()));
+            output = &output[len..];
+        }
+        responses
+    }
+
+    fn serve(input: &[u8]) -> Vec<(Vec<String>, Vec<u8>)> {
+        let mut stream = Stream {
+            input: io::Cursor::new(input.to_vec()),
+            output: vec![],
+        };
+        serve_connection(&mut stream, dns_reply).unwrap();
+        responses(&stream.output)
+    }
+
+    fn get(path: &str, query: &[u8]) -> Vec<u8> {
+        let dns = base64::encode_config(query, base64::URL_SAFE_NO_PAD);
+        format!("GET {}?ct&dns={} HTTP/1.1\r\nHost: a\r\n\r\n", path, dns).into_bytes()
+    }
+
+    fn post(content_type: &str, query: &[u8]) -> Vec<u8> {
+        let mut request = format!(
+            "POST /dns-query HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+            content_type,
+            query.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(query);
+        request
+    }
+
+    #[test]
+    fn gets() {
+        // a query whose base64 would need padding, which DoH leaves out
+        let query = dns_query();
+        assert_ne!(query.len() % 3, 0);
+        let responses = serve(&get("/dns-query", &query));
+        assert_eq!(responses.len(), 1);
+        let (head, body) = &responses[0];
+        assert_eq!(head[0], "http/1.1 200 ok");
+        assert!(head.contains(&"content-type: application/dns-message".to_string()));
+        assert_eq!(body, &dns_reply(&query).unwrap());
+
+        let request = b"GET /dns-query?ct HTTP/1.1\r\n\r\n";
+        assert_eq!(serve(request)[0].0[0], "http/1.1 400 bad request");
+    }
+
+    #[test]
+    fn posts() {
+        let query = dns_query();
+        let responses = serve(&post("application/dns-message", &query));
+        assert_eq!(responses[0].0[0], "http/1.1 200 ok");
+        assert_eq!(responses[0].1, dns_reply(&query).unwrap());
+        let responses = serve(&post("application/octet-stream", &query));
+        assert_eq!(responses[0].0[0], "http/1.1 415 unsupported media type");
+    }
+
+    #[test]
+    fn other_paths_and_methods() {
+        let responses = serve(&get("/resolve", &dns_query()));
+        assert_eq!(responses[0].0[0], "http/1.1 404 not found");
+        let responses = serve(b"PUT /dns-query HTTP/1.1\r\nContent-Length: 0\r\n\r\n");
+        let (head, _) = &responses[0];
+        assert_eq!(head[0], "http/1.1 405 method not allowed");
+        assert!(head.contains(&"allow: get, post".to_string()));
+    }
+
+    #[test]
+    fn chunked_requests_are_refused() {
+        let request = b"POST /dns-query HTTP/1.1\r\nContent-Type: application/dns-message\r\n\
+                        Transfer-Encoding: chunked\r\n\r\n1c\r\n";
+        let responses = serve(request);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].0[0], "http/1.1 400 bad request");
+        assert!(responses[0].0.contains(&"connection: close".to_string()));
+    }
+
+    #[test]
+    fn pipelined_requests() {
+        let query = dns_query();
+        let mut input = post("application/dns-message", &query);
+        input.extend(get("/dns-query", &query));
+        input.extend(get("/nowhere", &query));
+
+        // one at a time, with the rest kept for later
+        let mut stream = io::Cursor::new(input.clone());
+        let mut buf = vec![];
+        let first = read_request(&mut stream, &mut buf).unwrap().unwrap();
+        assert_eq!((first.method.as_str(), first.body), ("POST", query.clone()));
+        let second = read_request(&mut stream, &mut buf).unwrap().unwrap();
+        assert_eq!(second.method, "GET");
+        assert!(!second.close);
+        assert!(!buf.is_empty());
+        let third = read_request(&mut stream, &mut buf).unwrap().unwrap();
+        assert!(third.target.starts_with("/nowhere?"));
+        assert!(buf.is_empty());
+        assert!(read_request(&mut stream, &mut buf).unwrap().is_none());
+
+        let statuses: Vec<String> = serve(&input)
+            .into_iter()
+            .map(|(h, _)| h[0].clone())
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                "http/1.1 200 ok",
+                "http/1.1 200 ok",
+                "http/1.1 404 not found"
+            ]
+        );
+    }
+
+    #[test]
+    fn closing() {
+        let query = dns_query();
+        let close = |version: &str, connection: &str| {
+            let dns = base64::encode_config(&query, base64::URL_SAFE_NO_PAD);
+            let request = format!(
+                "GET /dns-query?dns={} HTTP/{}\r\n{}\r\n",
+                dns, version, connection
+            );
+            let mut buf = vec![];
+            let mut stream = io::Cursor::new(request.into_bytes());
+            read_request(&mut stream, &mut buf).unwrap().unwrap().close
+        };
+        assert!(!close("1.1", ""));
+        assert!(close("1.1", "Connection: close\r\n"));
+        assert!(close("1.0", ""));
+        assert!(!close("1.0", "Connection: Keep-Alive\r\n"));
+
+        // nothing after a request that closes is answered
+        let mut input = get("/dns-query", &query);
+        input.splice(input.len() - 2.., *b"Connection: close\r\n\r\n");
+        input.extend(get("/dns-query", &query));
+        let responses = serve(&input);
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].0.contains(&"connection: close".to_string()));
+    }
+
+    #[test]
+    fn cache_lifetimes() {
+        let reply = dns_reply(&dns_query()).unwrap();
+        assert_eq!(max_age(&reply), Some(60));
+        let responses = serve(&get("/dns-query", &dns_query()));
+        assert!(responses[0]
+            .0
+            .contains(&"cache-control: max-age=60".to_string()));
+        // nothing in it, nothing to say how long it lasts
+        let empty = Message::from_bytes(&dns_query())
+            .unwrap()
+            .reply(ResponseCode::NxDomain)
+            .to_bytes()
+            .unwrap();
+        assert_eq!(max_age(&empty), None);
+    }
+}
@@ -0,0 +1,398 @@
+// EDNS(0): the OPT pseudo-record in the additional section, which carries a bigger UDP payload
+// size, 8 more bits of RCODE, the DO bit and options.
+// https://datatracker.ietf.org/doc/html/rfc6891#section-6
+use super::wire::{Reader, WireError, Writer};
+use super::MAX_UDP_REPLY;
+
+/// Largest UDP reply we send, whatever the client advertises. 1232 bytes keeps clear of IP
+/// fragmentation on pretty much any path.
+/// https://www.dnsflagday.net/2020/
+pub const PAYLOAD_SIZE: u16 = 1232;
+
+const OPTION_CLIENT_SUBNET: u16 = 8;
+const OPTION_COOKIE: u16 = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EdnsOption {
+    /// https://datatracker.ietf.org/doc/html/rfc7873#section-4
+    Cookie {
+        client: [u8; 8],
+        server: Vec<u8>,
+    },
+    /// https://datatracker.ietf.org/doc/html/rfc7871#section-6
+    ClientSubnet {
+        family: u16,
+        source_prefix: u8,
+        scope_prefix: u8,
+        /// Only as many bytes as `source_prefix` needs
+        address: Vec<u8>,
+    },
+    Unknown(u16, Vec<u8>),
+}
+
+impl EdnsOption {
+    fn read(r: &mut Reader) -> Result<EdnsOption, WireError> {
+        let code = r.u16()?;
+        let len = r.u16()? as usize;
+        let data = r.bytes(len)?;
+        let malformed = |what: &str| Err(WireError::Malformed(what.to_string()));
+        match code {
+            OPTION_COOKIE => {
+                // the server part, if any, is 8 to 32 bytes
+                if len != 8 && !(16..=40).contains(&len) {
+                    return malformed("bad cookie length");
+                }
+                Ok(EdnsOption::Cookie {
+                    client: data[..8].try_into().unwrap(),
+                    server: data[8..].to_vec(),
+                })
+            }
+            OPTION_CLIENT_SUBNET => {
+                if len < 4 {
+                    return malformed("client subnet option too short");
+                }
+                let family = u16::from_be_bytes([data[0], data[1]]);
+                let (source_prefix, scope_prefix) = (data[2], data[3]);
+                let max_prefix = match family {
+                    1 => 32,
+                    2 => 128,
+                    _ => return malformed("unknown client subnet family"),
+                };
+                let address = data[4..].to_vec();
+                if source_prefix > max_prefix
+                    || address.len() != (source_prefix as usize).div_ceil(8)
+                {
+                    return malformed("bad client subnet prefix");
+                }
+                Ok(EdnsOption::ClientSubnet {
+                    family,
+                    source_prefix,
+                    scope_prefix,
+                    address,
+                })
+            }
+            _ => Ok(EdnsOption::Unknown(code, data.to_vec())),
+        }
+    }
+
+    fn write(&self, w: &mut Writer) {
+        let (code, data) = match self {
+            EdnsOption::Cookie { client, server } => {
+                (OPTION_COOKIE, [&client[..], server].concat())
+            }
+            EdnsOption::ClientSubnet {
+                family,
+                source_prefix,
+                scope_prefix,
+                address,
+            } => {
+                let mut data = family.to_be_bytes().to_vec();
+                data.extend([*source_prefix, *scope_prefix]);
+                data.extend(address);
+                (OPTION_CLIENT_SUBNET, data)
+            }
+            EdnsOption::Unknown(code, data) => (*code, data.clone()),
+        };
+        w.u16(code);
+        w.u16(data.len() as u16);
+        w.bytes(&data);
+    }
+}
+
+/// The options in the data of an OPT record
+pub fn read_options(r: &mut Reader, len: usize) -> Result<Vec<EdnsOption>, WireError> {
+    let end = r.pos() + len;
+    let mut options = vec![];
+    while r.pos() < end {
+        options.push(EdnsOption::read(r)?);
+    }
+    Ok(options)
+}
+
+pub fn write_options(options: &[EdnsOption], w: &mut Writer) {
+    for o in options {
+        o.write(w);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Edns {
+    /// Largest UDP reply the sender takes
+    pub payload_size: u16,
+    /// Upper 8 bits of the 12 bit RCODE
+    pub extended_rcode: u8,
+    pub version: u8,
+    /// DNSSEC OK: the sender wants the DNSSEC records
+    /// https://datatracker.ietf.org/doc/html/rfc3225
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Edns {
+    /// From the fields of an OPT record, which reuses CLASS for the payload size and TTL for the
+    /// extended RCODE, version and flags
+    pub fn from_record(class: u16, ttl: u32, options: Vec<EdnsOption>) -> Edns {
+        Edns {
+            payload_size: class,
+            extended_rcode: (ttl >> 24) as u8,
+            version: (ttl >> 16) as u8,
+            dnssec_ok: ttl & 0x8000 != 0,
+            options,
+        }
+    }
+
+    /// The TTL field of the OPT record
+    pub fn ttl(&self) -> u32 {
+        (self.extended_rcode as u32) << 24
+            | (self.version as u32) << 16
+            | (self.dnssec_ok as u32) << 15
+    }
+
+    /// What goes in the reply to a query with this OPT: our own payload size and version, the
+    /// DO bit echoed, and only the options we know to answer. A client subnet is sent back with
+    /// a scope of 0, as the answers are the same for every client.
+    /// https://datatracker.ietf.org/doc/html/rfc7871#section-7.2.1
+    pub fn reply(&self) -> Edns {
+        let options = self
+            .options
+            .iter()
+            .filter_map(|o| match o {
+                EdnsOption::ClientSubnet {
+                    family,
+                    source_prefix,
+                    address,
+                    ..
+                } => Some(EdnsOption::ClientSubnet {
+                    family: *family,
+                    source_prefix: *source_prefix,
+                    scope_prefix: 0,
+                    address: address.clone(),
+                }),
+                _ => None,
+            })
+            .collect();
+        Edns {
+            payload_size: PAYLOAD_SIZE,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: self.dnssec_ok,
+            options,
+        }
+    }
+
+    /// Longest UDP reply for whoever sent this: what they take, up to what we send, and never
+    /// less than what everyone takes
+    pub fn max_udp_reply(&self) -> usize {
+        (self.payload_size as usize).clamp(MAX_UDP_REPLY, PAYLOAD_SIZE as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::super::{
+        Message, Options, Protocol, Question, QuestionClass, QuestionType, ResponseCode, Server,
+        Store,
+    };
+    use super::*;
+
+    /// The options in OPT data made of `(code, data)` pairs
+    fn options(options: &[(u16, &[u8])]) -> Result<Vec<EdnsOption>, WireError> {
+        let mut w = Writer::new();
+        for (code, data) in options {
+            EdnsOption::Unknown(*code, data.to_vec()).write(&mut w);
+        }
+        let bytes = w.into_bytes();
+        read_options(&mut Reader::new(&bytes), bytes.len())
+    }
+
+    fn edns(options: Vec<EdnsOption>) -> Edns {
+        Edns {
+            payload_size: 4096,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: true,
+            options,
+        }
+    }
+
+    #[test]
+    fn cookies() {
+        let read = options(&[(OPTION_COOKIE, &[1, 2, 3, 4, 5, 6, 7, 8])]).unwrap();
+        assert_eq!(
+            read,
+            [EdnsOption::Cookie {
+                client: [1, 2, 3, 4, 5, 6, 7, 8],
+                server: vec![],
+            }]
+        );
+        for len in [16, 24, 40] {
+            let data = vec![7; len];
+            let read = options(&[(OPTION_COOKIE, &data)]).unwrap();
+            assert_eq!(
+                read,
+                [EdnsOption::Cookie {
+                    client: [7; 8],
+                    server: vec![7; len - 8],
+                }]
+            );
+        }
+        for len in [0, 7, 9, 15, 41] {
+            assert!(
+                options(&[(OPTION_COOKIE, &vec![7; len])]).is_err(),
+                "{}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn client_subnets() {
+        let subnet = |family, source_prefix, address: &[u8]| {
+            vec![EdnsOption::ClientSubnet {
+                family,
+                source_prefix,
+                scope_prefix: 0,
+                address: address.to_vec(),
+            }]
+        };
+        assert_eq!(
+            options(&[(OPTION_CLIENT_SUBNET, &[0, 1, 24, 0, 192, 0, 2])]).unwrap(),
+            subnet(1, 24, &[192, 0, 2])
+        );
+        assert_eq!(
+            options(&[(OPTION_CLIENT_SUBNET, &[0, 1, 0, 0])]).unwrap(),
+            subnet(1, 0, &[])
+        );
+        assert_eq!(
+            options(&[(
+                OPTION_CLIENT_SUBNET,
+                &[0, 2, 33, 0, 0x20, 1, 0xd, 0xb8, 0x80]
+            )])
+            .unwrap(),
+            subnet(2, 33, &[0x20, 1, 0xd, 0xb8, 0x80])
+        );
+        for bad in [
+            &[0, 1, 24][..],
+            // no such family
+            &[0, 3, 8, 0, 10],
+            // longer than an IPv4 address
+            &[0, 1, 33, 0, 192, 0, 2, 1, 0],
+            // one byte too many and one too few
+            &[0, 1, 16, 0, 192, 0, 2],
+            &[0, 1, 17, 0, 192, 0],
+        ] {
+            assert!(
+                options(&[(OPTION_CLIENT_SUBNET, bad)]).is_err(),
+                "{:?}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn replies() {
+        let query = edns(vec![
+            EdnsOption::Unknown(65001, vec![1]),
+            EdnsOption::Cookie {
+                client: [1; 8],
+                server: vec![],
+            },
+            EdnsOption::ClientSubnet {
+                family: 1,
+                source_prefix: 24,
+                scope_prefix: 24,
+                address: vec![192, 0, 2],
+            },
+        ]);
+        let reply = query.reply();
+        assert_eq!(reply.payload_size, 1232);
+        assert_eq!(reply.version, 0);
+        assert!(reply.dnssec_ok);
+        // the cookie is the server's to add, once it knows whether the one it got was good
+        assert_eq!(
+            reply.options,
+            [EdnsOption::ClientSubnet {
+                family: 1,
+                source_prefix: 24,
+                scope_prefix: 0,
+                address: vec![192, 0, 2],
+            }]
+        );
+        let mut query = edns(vec![]);
+        query.dnssec_ok = false;
+        assert!(!query.reply().dnssec_ok);
+    }
+
+    #[test]
+    fn udp_payload_sizes() {
+        for (advertised, reply) in [(0, 512), (511, 512), (512, 512), (1000, 1000), (4096, 1232)] {
+            let mut e = edns(vec![]);
+            e.payload_size = advertised;
+            assert_eq!(e.max_udp_reply(), reply, "{}", advertised);
+        }
+    }
+
+    #[test]
+    fn extended_rcodes_and_versions() {
+        let e = Edns::from_record(1232, 0x0102_8000, vec![]);
+        assert_eq!((e.extended_rcode, e.version, e.dnssec_ok), (1, 2, true));
+        assert_eq!(e.ttl(), 0x0102_8000);
+
+        // BADVERS is 16, which only fits with the 8 more bits in the OPT record
+        let q = Question {
+            domain: "example.com".to_string(),
+            qtype: QuestionType::A,
+            class: QuestionClass::IN,
+        };
+        let mut query = Message::query(1, q);
+        query.edns = Some(edns(vec![]));
+        let reply = query.reply(ResponseCode::BadVers);
+        let bytes = reply.to_bytes().unwrap();
+        // the low 4 bits in the header, the high 8 at the top of the TTL of the OPT, which ends
+        // with the TTL and an empty RDLENGTH
+        assert_eq!(bytes[3] & 0xf, 0);
+        let ttl_at = bytes.len() - 6;
+        assert_eq!(bytes[ttl_at], 1);
+        let read = Message::from_bytes(&bytes).unwrap();
+        assert_eq!(read.header.flags.response_code, ResponseCode::BadVers);
+        assert_eq!(read.edns.unwrap().extended_rcode, 1);
+    }
+
+    #[test]
+    fn servers_answer_other_versions_with_badvers_and_bad_options_with_formerr() {
+        let server = Server::new(
+            Store::new(vec![]),
+            None,
+            None,
+            None,
+            None,
+            Options::default(),
+        );
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let q = Question {
+            domain: "example.com".to_string(),
+            qtype: QuestionType::A,
+            class: QuestionClass::IN,
+        };
+        let reply_to = |edns| {
+            let mut query = Message::query(1, q.clone());
+            query.edns = Some(edns);
+            let bytes = query.to_bytes().unwrap();
+            let reply = server.respond(&bytes, client, Protocol::Udp).unwrap();
+            let bytes = reply.messages[0].to_bytes().unwrap();
+            Message::from_bytes(&bytes).unwrap()
+        };
+
+        let mut e = edns(vec![]);
+        e.version = 1;
+        let reply = reply_to(e);
+        assert_eq!(reply.header.flags.response_code, ResponseCode::BadVers);
+        assert_eq!(reply.edns.unwrap().version, 0);
+
+        let reply = reply_to(edns(vec![EdnsOption::Unknown(OPTION_COOKIE, vec![1; 9])]));
+        assert_eq!(reply.header.flags.response_code, ResponseCode::FormatError);
+        assert_eq!(reply.header.identification, 1);
+    }
+}
@@ -0,0 +1,712 @@
+// Iterative resolution for clients that ask for recursion: from the root servers down, following
+// referrals and CNAMEs, keeping what the answers said in a cache for as long as their TTLs allow,
+// negative answers included.
+// https://datatracker.ietf.org/doc/html/rfc1034#section-5.3.3
+// https://datatracker.ietf.org/doc/html/rfc2308
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::client::{self, Transport};
+use super::edns::{Edns, PAYLOAD_SIZE};
+use super::store::name_key;
+use super::wire::{from_labels, labels};
+use super::{zone, Message, Question, QuestionClass, QuestionType, RData, Record, ResponseCode};
+
+/// Referrals followed for a single question before giving up
+const MAX_REFERRALS: usize = 16;
+/// Longest CNAME chain followed
+const MAX_CNAME_CHAIN: usize = 8;
+/// How many name servers deep resolving the address of a name server can go, as that may take
+/// resolving the address of another one
+const MAX_DEPTH: usize = 4;
+/// Longest anything is kept, whatever its TTL
+const MAX_TTL: u32 = 86400;
+/// Longest negative answers are kept
+/// https://datatracker.ietf.org/doc/html/rfc2308#section-5
+const MAX_NEGATIVE_TTL: u32 = 3 * 3600;
+/// Entries the cache holds at most
+const MAX_CACHE: usize = 100_000;
+/// How long to wait for each server
+const TIMEOUT: Duration = Duration::from_millis(1500);
+/// How long a resolution can take, all the servers it asks together. Clients have given up by
+/// then anyway.
+const MAX_TIME: Duration = Duration::from_secs(5);
+
+/// What we know about a name and type
+#[derive(Debug, Clone)]
+enum Cached {
+    /// The RRset, or the CNAME at the name
+    RRset(Vec<Record>),
+    /// The name doesn't exist, whatever the type, as the SOA of its zone says
+    NxDomain(Option<Record>),
+    /// The name exists, but without records of the type
+    NoData(Option<Record>),
+}
+
+struct Entry {
+    cached: Cached,
+    stored: Instant,
+    ttl: u32,
+    /// From a referral: the parent's NS records for a zone, or the glue for them. Only good for
+    /// finding the servers of the zone, never as an answer, and replaced by what the zone's
+    /// own servers say.
+    /// https://datatracker.ietf.org/doc/html/rfc2181#section-5.4.1
+    referral: bool,
+}
+
+/// By `name_key` and type. Names that don't exist are under type ANY, as replies to ANY
+/// queries aren't kept.
+type Cache = HashMap<(Vec<Vec<u8>>, QuestionType), Entry>;
+
+/// The answer to a question, for the client that asked it
+pub struct Resolution {
+    pub code: ResponseCode,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+}
+
+impl Resolution {
+    fn failed() -> Resolution {
+        Resolution {
+            code: ResponseCode::ServFail,
+            answers: vec![],
+            authorities: vec![],
+        }
+    }
+}
+
+pub struct Resolver {
+    /// Addresses of the root servers
+    roots: Vec<IpAddr>,
+    /// Where every server is asked, 53 but for testing
+    port: u16,
+    cache: Mutex<Cache>,
+    /// Resolutions waiting on servers. Past `max_in_flight` the others make do with the cache,
+    /// so slow servers can't hold up every thread answering queries.
+    in_flight: AtomicUsize,
+    max_in_flight: usize,
+}
+
+/// A resolution waiting on servers, counted in `Resolver::in_flight` until dropped
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Whether `name` is `zone` or below it
+fn is_subdomain(name: &str, zone: &str) -> bool {
+    name_key(name).starts_with(&name_key(zone))
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    name_key(a) == name_key(b)
+}
+
+/// The records, grouped in RRsets
+fn rrsets(records: &[Record]) -> HashMap<(Vec<Vec<u8>>, QuestionType), Vec<Record>> {
+    let mut sets: HashMap<_, Vec<Record>> = HashMap::new();
+    for r in records {
+        sets.entry((name_key(&r.name), r.rtype))
+            .or_default()
+            .push(r.clone());
+    }
+    sets
+}
+
+/// The SOA in a negative reply, with the TTL negative answers get: the lower of its own TTL
+/// and its MINIMUM
+/// https://datatracker.ietf.org/doc/html/rfc2308#section-5
+fn negative_soa(reply: &Message, zone: &str) -> Option<Record> {
+    let mut soa = reply
+        .authorities
+        .iter()
+        .find(|r| r.rtype == QuestionType::SOA && is_subdomain(&r.name, zone))?
+        .clone();
+    if let RData::SOA { minimum, .. } = soa.data {
+        soa.ttl = soa.ttl.min(minimum);
+    }
+    Some(soa)
+}
+
+impl Resolver {
+    /// Starts at the root servers in the master file at `path`, as in BIND's named.root: the
+    /// NS records of the root, and the addresses of the servers they name. Every server is asked
+    /// on `port`, and at most `max_in_flight` resolutions wait on them at once.
+    pub fn from_hints(path: &str, port: u16, max_in_flight: usize) -> Result<Resolver, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let hints = zone::parse(&text, "").map_err(|e| format!("{}: {}", path, e))?;
+        let servers: Vec<&String> = hints
+            .iter()
+            .filter_map(|a| match &a.data {
+                RData::NS(target) if a.key().is_empty() => Some(target),
+                _ => None,
+            })
+            .collect();
+        let mut roots: Vec<IpAddr> = hints
+            .iter()
+            .filter(|a| servers.iter().any(|s| same_name(s, &a.name)))
+            .filter_map(|a| match a.data {
+                RData::A(ip) => Some(IpAddr::V4(ip)),
+                RData::AAAA(ip) => Some(IpAddr::V6(ip)),
+                _ => None,
+            })
+            .collect();
+        if roots.is_empty() {
+            return Err(format!("{}: no addresses of root servers", path));
+        }
+        // not every host has IPv6
+        roots.sort_by_key(IpAddr::is_ipv6);
+        Ok(Resolver {
+            roots,
+            port,
+            cache: Mutex::new(Cache::new()),
+            in_flight: AtomicUsize::new(0),
+            max_in_flight,
+        })
+    }
+
+    /// Answers `q` from the cache, or else by asking the servers of the zones the name is in,
+    /// for `MAX_TIME` at most. SERVFAIL if that's not enough, or if too many resolutions are
+    /// waiting on servers already and the cache doesn't have the answer.
+    pub fn resolve(&self, q: &Question) -> Resolution {
+        let in_flight = self
+            .in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < self.max_in_flight).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| InFlight(&self.in_flight));
+        let deadline = in_flight.as_ref().map(|_| Instant::now() + MAX_TIME);
+        self.resolve_at(&q.domain, q.qtype, 0, deadline)
+    }
+
+    /// `deadline` is when to stop asking servers, `None` to only look in the cache
+    fn resolve_at(
+        &self,
+        name: &str,
+        qtype: QuestionType,
+        depth: usize,
+        deadline: Option<Instant>,
+    ) -> Resolution {
+        let mut answers: Vec<Record> = vec![];
+        let mut name = name.to_string();
+        for _ in 0..=MAX_CNAME_CHAIN {
+            let found = match self.cached_answer(&name, qtype) {
+                Some(found) => found,
+                None => match self.ask(&name, qtype, depth, deadline) {
+                    Some(found) => found,
+                    None => return Resolution::failed(),
+                },
+            };
+            let (code, soa) = match found {
+                Cached::RRset(records) => {
+                    let target = records.iter().find_map(|r| match &r.data {
+                        RData::CNAME(target) if qtype != QuestionType::CNAME => Some(target),
+                        _ => None,
+                    });
+                    let Some(target) = target.cloned() else {
+                        answers.extend(records);
+                        return Resolution {
+                            code: ResponseCode::NoError,
+                            answers,
+                            authorities: vec![],
+                        };
+                    };
+                    answers.extend(records);
+                    if answers.iter().any(|r| same_name(&r.name, &target)) {
+                        break; // a loop
+                    }
+                    name = target;
+                    continue;
+                }
+                Cached::NxDomain(soa) => (ResponseCode::NxDomain, soa),
+                Cached::NoData(soa) => (ResponseCode::NoError, soa),
+            };
+            return Resolution {
+                code,
+                answers,
+                authorities: soa.into_iter().collect(),
+            };
+        }
+        Resolution::failed()
+    }
+
+    /// What the cache says about `name` and `qtype`, TTLs counting down from when it was kept.
+    /// What came in referrals only with `referrals`.
+    fn cached(&self, name: &str, qtype: QuestionType, referrals: bool) -> Option<Cached> {
+        let mut cache = self.cache.lock().unwrap();
+        let key = (name_key(name), qtype);
+        let entry = cache.get(&key)?;
+        let age = entry.stored.elapsed().as_secs();
+        if age >= entry.ttl as u64 {
+            cache.remove(&key);
+            return None;
+        }
+        if entry.referral && !referrals {
+            return None;
+        }
+        let ttl = entry.ttl - age as u32;
+        let aged = |r: &Record| Record { ttl, ..r.clone() };
+        Some(match &entry.cached {
+            Cached::RRset(records) => Cached::RRset(records.iter().map(aged).collect()),
+            Cached::NxDomain(soa) => Cached::NxDomain(soa.as_ref().map(aged)),
+            Cached::NoData(soa) => Cached::NoData(soa.as_ref().map(aged)),
+        })
+    }
+
+    /// What the cache says answers `qtype` at `name`: that the name doesn't exist, the RRset or
+    /// that there isn't one, or else a CNAME at the name
+    fn cached_answer(&self, name: &str, qtype: QuestionType) -> Option<Cached> {
+        if let Some(nxdomain @ Cached::NxDomain(_)) = self.cached(name, QuestionType::ANY, false) {
+            return Some(nxdomain);
+        }
+        if let Some(found) = self.cached(name, qtype, false) {
+            return Some(found);
+        }
+        match self.cached(name, QuestionType::CNAME, false) {
+            Some(cname @ Cached::RRset(_)) if qtype != QuestionType::CNAME => Some(cname),
+            _ => None,
+        }
+    }
+
+    /// Keeps `cached` for `name` and `qtype`, unless it's from a `referral` and the zone's own
+    /// servers already said
+    fn keep(&self, name: &str, qtype: QuestionType, cached: Cached, referral: bool) {
+        let ttl = match &cached {
+            Cached::RRset(records) => records.iter().map(|r| r.ttl).min().unwrap_or(0),
+            Cached::NxDomain(soa) | Cached::NoData(soa) => {
+                soa.as_ref().map_or(0, |soa| soa.ttl.min(MAX_NEGATIVE_TTL))
+            }
+        };
+        let ttl = ttl.min(MAX_TTL);
+        if ttl == 0 {
+            return;
+        }
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHE {
+            cache.retain(|_, e| e.stored.elapsed().as_secs() < e.ttl as u64);
+            // dropping everything beats scanning it on every insert
+            if cache.len() >= MAX_CACHE {
+                cache.clear();
+            }
+        }
+        let key = (name_key(name), qtype);
+        let answered = cache
+            .get(&key)
+            .is_some_and(|e| !e.referral && e.stored.elapsed().as_secs() < e.ttl as u64);
+        if referral && answered {
+            return;
+        }
+        let entry = Entry {
+            cached,
+            stored: Instant::now(),
+            ttl,
+            referral,
+        };
+        cache.insert(key, entry);
+    }
+
+    /// The zone closest to `name` that we know the servers of, and their addresses. The root if
+    /// nothing else.
+    fn closest_servers(
+        &self,
+        name: &str,
+        depth: usize,
+        deadline: Instant,
+    ) -> (String, Vec<IpAddr>) {
+        let labels = labels(name);
+        for n in 0..labels.len() {
+            let zone = from_labels(&labels[n..]);
+            if let Some(Cached::RRset(ns)) = self.cached(&zone, QuestionType::NS, true) {
+                let addresses = self.addresses(&ns, &[], depth, deadline);
+                if !addresses.is_empty() {
+                    return (zone, addresses);
+                }
+            }
+        }
+        (String::new(), self.roots.clone())
+    }
+
+    /// The addresses of the name servers in `ns`: from the `glue` that came with them, from the
+    /// cache, or else resolving them
+    fn addresses(
+        &self,
+        ns: &[Record],
+        glue: &[Record],
+        depth: usize,
+        deadline: Instant,
+    ) -> Vec<IpAddr> {
+        let targets: Vec<&String> = ns
+            .iter()
+            .filter_map(|r| match &r.data {
+                RData::NS(target) => Some(target),
+                _ => None,
+            })
+            .collect();
+        let ips = |records: &[Record]| -> Vec<IpAddr> {
+            records
+                .iter()
+                .filter_map(|r| match r.data {
+                    RData::A(ip) => Some(IpAddr::V4(ip)),
+                    RData::AAAA(ip) => Some(IpAddr::V6(ip)),
+                    _ => None,
+                })
+                .collect()
+        };
+        let mut addresses = vec![];
+        for target in &targets {
+            let glued: Vec<Record> = glue
+                .iter()
+                .filter(|r| same_name(&r.name, target))
+                .cloned()
+                .collect();
+            addresses.extend(ips(&glued));
+            for t in [QuestionType::A, QuestionType::AAAA] {
+                if let Some(Cached::RRset(records)) = self.cached(target, t, true) {
+                    addresses.extend(ips(&records));
+                }
+            }
+        }
+        if addresses.is_empty() && depth < MAX_DEPTH {
+            // one server will do
+            for target in targets {
+                let found = self.resolve_at(target, QuestionType::A, depth + 1, Some(deadline));
+                addresses = ips(&found.answers);
+                if !addresses.is_empty() {
+                    break;
+                }
+            }
+        }
+        addresses.sort_by_key(IpAddr::is_ipv6);
+        addresses.dedup();
+        addresses
+    }
+
+    /// Sends the question to each of `servers` in turn, until one gives a reply that is an
+    /// answer or a referral, or `deadline` comes
+    fn query(
+        &self,
+        servers: &[IpAddr],
+        name: &str,
+        qtype: QuestionType,
+        deadline: Instant,
+    ) -> Option<Message> {
+        for &ip in servers {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                println!("Out of time for {} {}", name, qtype);
+                return None;
+            }
+            let transport = Transport {
+                tcp: false,
+                timeout: left.min(TIMEOUT),
+                tries: 1,
+            };
+            // a random ID, besides the random port, against forged replies
+            // https://datatracker.ietf.org/doc/html/rfc5452#section-9
+            let mut id = [0; 2];
+            SystemRandom::new().fill(&mut id).ok()?;
+            let question = Question {
+                domain: name.to_string(),
+                qtype,
+                class: QuestionClass::IN,
+            };
+            let mut query = Message::query(u16::from_be_bytes(id), question);
+            query.edns = Some(Edns {
+                payload_size: PAYLOAD_SIZE,
+                extended_rcode: 0,
+                version: 0,
+                dnssec_ok: false,
+                options: vec![],
+            });
+            let server = SocketAddr::new(ip, self.port);
+            match client::exchange(server, &query, transport) {
+                Ok(mut exchange) => {
+                    let reply = exchange.replies.remove(0);
+                    match reply.header.flags.response_code {
+                        ResponseCode::NoError | ResponseCode::NxDomain => return Some(reply),
+                        code => println!("{} answered {} {} with {}", ip, name, qtype, code),
+                    }
+                }
+                Err(e) => println!("Asking {} about {} {}: {}", ip, name, qtype, e),
+            }
+        }
+        None
+    }
+
+    /// Asks the servers of the closest zone we know about `name`, following referrals down until
+    /// one answers, and keeps what it says. `None` if none of them would, or there's no
+    /// `deadline` to ask by.
+    fn ask(
+        &self,
+        name: &str,
+        qtype: QuestionType,
+        depth: usize,
+        deadline: Option<Instant>,
+    ) -> Option<Cached> {
+        let deadline = deadline?;
+        let (mut zone, mut servers) = self.closest_servers(name, depth, deadline);
+        for _ in 0..MAX_REFERRALS {
+            let reply = self.query(&servers, name, qtype, deadline)?;
+            // only what the server is authoritative for, or at least the parent of
+            let answers: Vec<Record> = reply
+                .answers
+                .iter()
+                .filter(|r| is_subdomain(&r.name, &zone))
+                .cloned()
+                .collect();
+            let sets = rrsets(&answers);
+            if qtype != QuestionType::ANY {
+                for ((_, rtype), records) in &sets {
+                    self.keep(
+                        &records[0].name,
+                        *rtype,
+                        Cached::RRset(records.clone()),
+                        false,
+                    );
+                }
+            }
+            let key = name_key(name);
+            let found = sets
+                .get(&(key.clone(), qtype))
+                .or_else(|| sets.get(&(key, QuestionType::CNAME)));
+            if let Some(records) = found {
+                return Some(Cached::RRset(records.clone()));
+            }
+
+            let aa = reply.header.flags.authoritative_answer;
+            if reply.header.flags.response_code == ResponseCode::NxDomain {
+                let nxdomain = Cached::NxDomain(negative_soa(&reply, &zone));
+                self.keep(name, QuestionType::ANY, nxdomain.clone(), false);
+                return Some(nxdomain);
+            }
+            if aa {
+                let nodata = Cached::NoData(negative_soa(&reply, &zone));
+                if qtype != QuestionType::ANY {
+                    self.keep(name, qtype, nodata.clone(), false);
+                }
+                return Some(nodata);
+            }
+
+            // a referral to the servers of a zone between the one we asked and the name
+            let ns: Vec<Record> = reply
+                .authorities
+                .iter()
+                .filter(|r| r.rtype == QuestionType::NS)
+                .cloned()
+                .collect();
+            let Some(cut) = ns.first().map(|r| r.name.clone()) else {
+                println!("No answer and no referral for {} {}", name, qtype);
+                return None;
+            };
+            if !is_subdomain(name, &cut) || !is_subdomain(&cut, &zone) || same_name(&cut, &zone) {
+                println!("Bad referral to {} for {} {}", cut, name, qtype);
+                return None;
+            }
+            let ns: Vec<Record> = ns
+                .into_iter()
+                .filter(|r| same_name(&r.name, &cut))
+                .collect();
+            let glue: Vec<Record> = reply
+                .additionals
+                .iter()
+                .filter(|r| matches!(r.rtype, QuestionType::A | QuestionType::AAAA))
+                .filter(|r| is_subdomain(&r.name, &zone))
+                .cloned()
+                .collect();
+            self.keep(&cut, QuestionType::NS, Cached::RRset(ns.clone()), true);
+            for ((_, rtype), records) in rrsets(&glue) {
+                let owner = records[0].name.clone();
+                self.keep(&owner, rtype, Cached::RRset(records), true);
+            }
+            servers = self.addresses(&ns, &glue, depth, deadline);
+            if servers.is_empty() {
+                println!("No address for the servers of {}", cut);
+                return None;
+            }
+            zone = cut;
+        }
+        println!("Too many referrals for {} {}", name, qtype);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::process;
+    use std::thread;
+
+    use super::super::{store::Store, Options, Server};
+    use super::*;
+
+    const ROOT: &str = "$TTL 3600
+@ SOA a.root. hm.root. 1 3600 600 86400 60
+@ NS a.root.
+a.root. A 127.0.0.21
+test. NS ns.test.
+ns.test. A 127.0.0.22
+";
+
+    const TEST: &str = "$TTL 3600
+@ SOA ns hm 1 3600 600 86400 60
+@ NS ns
+ns A 127.0.0.22
+example NS ns.example
+ns.example A 127.0.0.23
+; no glue, it takes resolving the name of the server
+other NS ns.provider.test.
+provider NS ns.provider
+ns.provider A 127.0.0.24
+; nobody there
+dead NS ns.dead
+ns.dead A 127.0.0.25
+";
+
+    const EXAMPLE: &str = "$TTL 300
+@ SOA ns hm 1 3600 600 86400 30
+@ NS ns
+ns A 127.0.0.23
+www CNAME web
+web A 192.0.2.1
+alias CNAME www.other.test.
+";
+
+    const OTHER: &str = "$TTL 300
+other.test. SOA ns.provider.test. hm.provider.test. 1 3600 600 86400 30
+other.test. NS ns.provider.test.
+www.other.test. A 192.0.2.2
+provider.test. SOA ns.provider.test. hm.provider.test. 1 3600 600 86400 30
+provider.test. NS ns.provider.test.
+ns.provider.test. A 127.0.0.24
+";
+
+    /// Serves `zone` over UDP at `ip`, on `port` or any if 0, returning the port
+    fn serve(ip: &str, port: u16, zone: &str, origin: &str) -> u16 {
+        let socket = UdpSocket::bind((ip, port)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let store = Store::new(zone::parse(zone, origin).unwrap());
+        let server = Server::new(store, None, None, None, None, Options::default());
+        let server: &'static Server = Box::leak(Box::new(server));
+        thread::spawn(move || server.serve_udp(&socket));
+        port
+    }
+
+    /// A resolver starting at a root server of its own, with a hierarchy below it, all on
+    /// 127.0.0.21 to 127.0.0.24
+    fn resolver() -> Resolver {
+        let port = serve("127.0.0.21", 0, ROOT, "");
+        serve("127.0.0.22", port, TEST, "test");
+        serve("127.0.0.23", port, EXAMPLE, "example.test");
+        serve("127.0.0.24", port, OTHER, "");
+        let hints = std::env::temp_dir().join(format!("root-hints-{}-{}", process::id(), port));
+        fs::write(
+            &hints,
+            ". 3600000 NS a.root.\na.root. 3600000 A 127.0.0.21\n",
+        )
+        .unwrap();
+        let resolver = Resolver::from_hints(hints.to_str().unwrap(), port, 4).unwrap();
+        fs::remove_file(hints).unwrap();
+        resolver
+    }
+
+    fn resolve(resolver: &Resolver, name: &str, qtype: QuestionType) -> Resolution {
+        resolver.resolve(&Question {
+            domain: name.to_string(),
+            qtype,
+            class: QuestionClass::IN,
+        })
+    }
+
+    /// The records as `owner type data`
+    fn short(records: &[Record]) -> Vec<String> {
+        records
+            .iter()
+            .map(|r| format!("{} {} {}", r.name, r.rtype, r.data))
+            .collect()
+    }
+
+    #[test]
+    fn follows_referrals_and_cnames() {
+        let resolver = resolver();
+        let found = resolve(&resolver, "www.example.test", QuestionType::A);
+        assert_eq!(found.code, ResponseCode::NoError);
+        assert_eq!(
+            short(&found.answers),
+            [
+                "www.example.test CNAME web.example.test.",
+                "web.example.test A 192.0.2.1"
+            ]
+        );
+        // into another zone, whose server has to be resolved first
+        let found = resolve(&resolver, "alias.example.test", QuestionType::A);
+        assert_eq!(found.code, ResponseCode::NoError);
+        assert_eq!(
+            short(&found.answers),
+            [
+                "alias.example.test CNAME www.other.test.",
+                "www.other.test A 192.0.2.2"
+            ]
+        );
+    }
+
+    #[test]
+    fn negative_answers_have_the_soa() {
+        let resolver = resolver();
+        let found = resolve(&resolver, "nothere.example.test", QuestionType::A);
+        assert_eq!(found.code, ResponseCode::NxDomain);
+        assert!(found.answers.is_empty());
+        assert_eq!(found.authorities[0].rtype, QuestionType::SOA);
+        assert_eq!(found.authorities[0].name, "example.test");
+        // from the cache, the same
+        let found = resolve(&resolver, "nothere.example.test", QuestionType::A);
+        assert_eq!(found.code, ResponseCode::NxDomain);
+        let found = resolve(&resolver, "web.example.test", QuestionType::TXT);
+        assert_eq!(found.code, ResponseCode::NoError);
+        assert!(found.answers.is_empty());
+        assert_eq!(found.authorities.len(), 1);
+    }
+
+    #[test]
+    fn glue_is_not_an_answer() {
+        let resolver = resolver();
+        resolve(&resolver, "www.example.test", QuestionType::A);
+        // the referral from test. had the address of ns.example.test, with its TTL
+        assert!(resolver
+            .cached_answer("ns.example.test", QuestionType::A)
+            .is_none());
+        let found = resolve(&resolver, "ns.example.test", QuestionType::A);
+        assert_eq!(short(&found.answers), ["ns.example.test A 127.0.0.23"]);
+        assert_eq!(found.answers[0].ttl, 300);
+        // nor is the parent's NS
+        let found = resolve(&resolver, "example.test", QuestionType::NS);
+        assert_eq!(found.answers[0].ttl, 300);
+    }
+
+    #[test]
+    fn servers_that_dont_answer_fail() {
+        let resolver = resolver();
+        let found = resolve(&resolver, "www.dead.test", QuestionType::A);
+        assert_eq!(found.code, ResponseCode::ServFail);
+    }
+
+    #[test]
+    fn too_many_resolutions_only_get_the_cache() {
+        let resolver = resolver();
+        resolve(&resolver, "www.example.test", QuestionType::A);
+        resolver.in_flight.store(4, Ordering::Release);
+        let found = resolve(&resolver, "www.example.test", QuestionType::A);
+        assert_eq!(found.answers.len(), 2);
+        let found = resolve(&resolver, "alias.example.test", QuestionType::A);
+        assert_eq!(found.code, ResponseCode::ServFail);
+    }
+}
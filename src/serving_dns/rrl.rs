@@ -0,0 +1,242 @@
+// Response rate limiting: UDP replies can go to whoever the source address of a query says, so
+// a flood of spoofed queries turns us into an amplifier aimed at someone else. Replies of the same
+// kind to the same network get a rate, and past it they are dropped, or slipped: sent truncated,
+// empty, so real clients retry over TCP while a victim gets nothing bigger than the query.
+// https://kb.isc.org/docs/aa-00994
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use super::store::name_key;
+use super::{Message, RData, ResponseCode};
+
+/// Past this many buckets, those that filled up again get thrown away
+const MAX_BUCKETS: usize = 100_000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Replies a second to each network, for each name and kind of reply
+    pub rate: u32,
+    /// Every this many replies over the rate goes out truncated rather than dropped. 0 drops
+    /// them all.
+    pub slip: u32,
+    /// How much of the client address is its network
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+}
+
+/// What happens to a reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Send,
+    Slip,
+    Drop,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Verdict::Send => "send",
+            Verdict::Slip => "slip",
+            Verdict::Drop => "drop",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Replies that count against the same rate
+#[derive(Hash, PartialEq, Eq)]
+struct Key {
+    network: IpAddr,
+    /// The question for answers. For NXDOMAIN the zone, or else random names under it would
+    /// each get a rate of their own. Nothing for errors.
+    name: Vec<Vec<u8>>,
+    code: ResponseCode,
+}
+
+struct Bucket {
+    /// Replies that can still go out right away, up to a second's worth
+    tokens: f64,
+    last: Instant,
+    /// Replies over the rate since the bucket was made, to know which to slip
+    over: u32,
+}
+
+pub struct RateLimiter {
+    limits: Limits,
+    buckets: Mutex<HashMap<Key, Bucket>>,
+}
+
+/// The network `ip` is in, as long as `limits` has its prefix
+fn network(ip: IpAddr, limits: &Limits) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - limits.ipv4_prefix as u32);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask.unwrap_or(0)))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - limits.ipv6_prefix as u32);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask.unwrap_or(0)))
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(limits: Limits) -> RateLimiter {
+        RateLimiter {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// What to do with `reply`, going to `client`
+    pub fn check(&self, client: IpAddr, reply: &Message) -> Verdict {
+        let code = reply.header.flags.response_code;
+        let name = match code {
+            ResponseCode::NoError => reply.questions.first().map(|q| name_key(&q.domain)),
+            ResponseCode::NxDomain => reply.authorities.iter().find_map(|r| match r.data {
+                RData::SOA { .. } => Some(name_key(&r.name)),
+                _ => None,
+            }),
+            _ => None,
+        };
+        let key = Key {
+            network: network(client, &self.limits),
+            name: name.unwrap_or_default(),
+            code,
+        };
+        let rate = self.limits.rate as f64;
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            // a second without replies fills a bucket up, the same as a new one
+            buckets.retain(|_, b| now.duration_since(b.last).as_secs_f64() < 1.0);
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: rate,
+            last: now,
+            over: 0,
+        });
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Verdict::Send;
+        }
+        bucket.over = bucket.over.wrapping_add(1);
+        match self.limits.slip {
+            0 => Verdict::Drop,
+            slip if bucket.over.is_multiple_of(slip) => Verdict::Slip,
+            _ => Verdict::Drop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::super::{zone, Question, QuestionClass, QuestionType};
+    use super::*;
+
+    fn limits(slip: u32) -> Limits {
+        Limits {
+            rate: 5,
+            slip,
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+        }
+    }
+
+    fn reply(name: &str, code: ResponseCode) -> Message {
+        let question = Question {
+            domain: name.to_string(),
+            qtype: QuestionType::A,
+            class: QuestionClass::IN,
+        };
+        let mut reply = Message::query(1, question).reply(code);
+        if code == ResponseCode::NxDomain {
+            let soa = &zone::parse("@ 60 SOA ns hm 1 2 3 4 5", "example.com").unwrap()[0];
+            reply.authorities.push(soa.to_record(&soa.name));
+        }
+        reply
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn verdicts(limiter: &RateLimiter, client: &str, reply: &Message, n: usize) -> Vec<Verdict> {
+        (0..n).map(|_| limiter.check(ip(client), reply)).collect()
+    }
+
+    #[test]
+    fn replies_over_the_rate_slip_or_drop() {
+        let limiter = RateLimiter::new(limits(2));
+        let www = reply("www.example.com", ResponseCode::NoError);
+        assert_eq!(verdicts(&limiter, "192.0.2.1", &www, 5), [Verdict::Send; 5]);
+        assert_eq!(
+            verdicts(&limiter, "192.0.2.1", &www, 4),
+            [Verdict::Drop, Verdict::Slip, Verdict::Drop, Verdict::Slip]
+        );
+    }
+
+    #[test]
+    fn slip_zero_drops_them_all() {
+        let limiter = RateLimiter::new(limits(0));
+        let www = reply("www.example.com", ResponseCode::NoError);
+        verdicts(&limiter, "192.0.2.1", &www, 5);
+        assert_eq!(verdicts(&limiter, "192.0.2.1", &www, 3), [Verdict::Drop; 3]);
+        let limiter = RateLimiter::new(limits(1));
+        verdicts(&limiter, "192.0.2.1", &www, 5);
+        assert_eq!(verdicts(&limiter, "192.0.2.1", &www, 3), [Verdict::Slip; 3]);
+    }
+
+    #[test]
+    fn rate_is_per_network() {
+        let limiter = RateLimiter::new(limits(0));
+        let www = reply("www.example.com", ResponseCode::NoError);
+        verdicts(&limiter, "192.0.2.1", &www, 5);
+        assert_eq!(limiter.check(ip("192.0.2.200"), &www), Verdict::Drop);
+        assert_eq!(limiter.check(ip("192.0.3.1"), &www), Verdict::Send);
+        verdicts(&limiter, "2001:db8::1", &www, 5);
+        assert_eq!(limiter.check(ip("2001:db8:0:ff::1"), &www), Verdict::Drop);
+        assert_eq!(limiter.check(ip("2001:db8:0:100::1"), &www), Verdict::Send);
+    }
+
+    #[test]
+    fn rate_is_per_name_and_code() {
+        let limiter = RateLimiter::new(limits(0));
+        let www = reply("www.example.com", ResponseCode::NoError);
+        verdicts(&limiter, "192.0.2.1", &www, 5);
+        let mail = reply("mail.example.com", ResponseCode::NoError);
+        assert_eq!(limiter.check(ip("192.0.2.1"), &mail), Verdict::Send);
+        let refused = reply("www.example.com", ResponseCode::Refused);
+        assert_eq!(limiter.check(ip("192.0.2.1"), &refused), Verdict::Send);
+        // but NXDOMAIN for random names of a zone all count against the zone
+        for i in 0..5 {
+            let random = reply(&format!("{}.example.com", i), ResponseCode::NxDomain);
+            assert_eq!(limiter.check(ip("192.0.2.1"), &random), Verdict::Send);
+        }
+        let random = reply("5.example.com", ResponseCode::NxDomain);
+        assert_eq!(limiter.check(ip("192.0.2.1"), &random), Verdict::Drop);
+    }
+
+    #[test]
+    fn buckets_fill_up_again() {
+        let limiter = RateLimiter::new(limits(0));
+        let www = reply("www.example.com", ResponseCode::NoError);
+        verdicts(&limiter, "192.0.2.1", &www, 6);
+        for bucket in limiter.buckets.lock().unwrap().values_mut() {
+            bucket.last -= Duration::from_millis(400);
+        }
+        // at a rate of 5, 0.4 s is two more
+        assert_eq!(
+            verdicts(&limiter, "192.0.2.1", &www, 3),
+            [Verdict::Send, Verdict::Send, Verdict::Drop]
+        );
+    }
+}
@@ -0,0 +1,234 @@
+// Counters of the queries answered, served as JSON over plain HTTP for whatever keeps an eye on
+// the server
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use super::{doh, Served};
+
+const PATH: &str = "/stats";
+
+#[derive(Default)]
+struct Counters {
+    queries: u64,
+    /// Keyed by the mnemonics, so they read the same as in the query log
+    protocols: BTreeMap<String, u64>,
+    qtypes: BTreeMap<String, u64>,
+    rcodes: BTreeMap<String, u64>,
+    /// Replies over the rate, slipped or dropped
+    rate_limited: BTreeMap<String, u64>,
+    /// Of all the queries, to work out the average
+    latency: Duration,
+}
+
+pub struct Stats {
+    started: Instant,
+    counters: Mutex<Counters>,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            started: Instant::now(),
+            counters: Mutex::new(Counters::default()),
+        }
+    }
+
+    pub fn count(&self, served: &Served) {
+        let mut c = self.counters.lock().unwrap();
+        c.queries += 1;
+        *c.protocols.entry(served.protocol.to_string()).or_default() += 1;
+        // queries we couldn't make sense of have no question
+        let qtype = served
+            .question
+            .as_ref()
+            .map_or("-".to_string(), |q| q.qtype.to_string());
+        *c.qtypes.entry(qtype).or_default() += 1;
+        *c.rcodes.entry(served.code.to_string()).or_default() += 1;
+        if let Some(verdict) = served.limited {
+            *c.rate_limited.entry(verdict.to_string()).or_default() += 1;
+        }
+        c.latency += served.latency;
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let c = self.counters.lock().unwrap();
+        let average = match c.queries {
+            0 => Duration::ZERO,
+            n => c.latency / n as u32,
+        };
+        json!({
+            "uptime_secs": self.started.elapsed().as_secs(),
+            "queries": c.queries,
+            "protocols": c.protocols,
+            "qtypes": c.qtypes,
+            "rcodes": c.rcodes,
+            "rate_limited": c.rate_limited,
+            "average_latency_us": average.as_micros() as u64,
+        })
+    }
+
+    /// Answers GET /stats with the counters so far, until the client closes `stream`
+    pub fn serve_connection<S: Read + Write>(&self, mut stream: S) -> io::Result<()> {
+        let mut buf = vec![];
+        while let Some(request) = doh::read_request(&mut stream, &mut buf)? {
+            let path = request.target.split('?').next().unwrap_or_default();
+            let (status, body) = match (request.method.as_str(), path) {
+                ("GET", PATH) => ("200 OK", self.to_json().to_string() + "\n"),
+                (_, PATH) => ("405 Method Not Allowed", String::new()),
+                _ => ("404 Not Found", String::new()),
+            };
+            let mut response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\n", status, body.len());
+            if !body.is_empty() {
+                response.push_str("Content-Type: application/json\r\n");
+            }
+            if status.starts_with("405") {
+                response.push_str("Allow: GET\r\n");
+            }
+            if request.close {
+                response.push_str("Connection: close\r\n");
+            }
+            response.push_str("\r\n");
+            response.push_str(&body);
+            stream.write_all(response.as_bytes())?;
+            if request.close {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::super::rrl::Verdict;
+    use super::super::{Protocol, Question, QuestionClass, QuestionType, ResponseCode};
+    use super::*;
+
+    /// A connection with `input` from the client, keeping what goes back
+    struct Stream {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn served(
+        protocol: Protocol,
+        qtype: Option<QuestionType>,
+        code: ResponseCode,
+        limited: Option<Verdict>,
+        latency: Duration,
+    ) -> Served<'static> {
+        Served {
+            client: "192.0.2.1:5300".parse().unwrap(),
+            protocol,
+            query: b"",
+            reply: b"",
+            question: qtype.map(|qtype| Question {
+                domain: "example.com".to_string(),
+                qtype,
+                class: QuestionClass::IN,
+            }),
+            recursion_desired: false,
+            code,
+            limited,
+            received: UNIX_EPOCH,
+            latency,
+        }
+    }
+
+    fn stats() -> Stats {
+        let stats = Stats::new();
+        let ms = Duration::from_millis;
+        stats.count(&served(
+            Protocol::Udp,
+            Some(QuestionType::A),
+            ResponseCode::NoError,
+            None,
+            ms(1),
+        ));
+        stats.count(&served(
+            Protocol::Udp,
+            Some(QuestionType::A),
+            ResponseCode::NoError,
+            Some(Verdict::Slip),
+            ms(2),
+        ));
+        stats.count(&served(
+            Protocol::Tls,
+            Some(QuestionType::MX),
+            ResponseCode::NxDomain,
+            None,
+            ms(3),
+        ));
+        stats.count(&served(
+            Protocol::Tcp,
+            None,
+            ResponseCode::FormatError,
+            None,
+            ms(6),
+        ));
+        stats
+    }
+
+    #[test]
+    fn counts() {
+        let json = stats().to_json();
+        assert_eq!(json["queries"], 4);
+        assert_eq!(json["protocols"], json!({"UDP": 2, "TCP": 1, "DoT": 1}));
+        assert_eq!(json["qtypes"], json!({"A": 2, "MX": 1, "-": 1}));
+        assert_eq!(json["rate_limited"], json!({"slip": 1}));
+        assert_eq!(json["average_latency_us"], 3000);
+        assert_eq!(json["rcodes"].as_object().unwrap().len(), 3);
+        // no queries, no average, rather than a division by zero
+        assert_eq!(Stats::new().to_json()["average_latency_us"], 0);
+    }
+
+    #[test]
+    fn requests() {
+        let stats = stats();
+        let mut stream = Stream {
+            input: io::Cursor::new(
+                b"GET /stats?pretty HTTP/1.1\r\n\r\n\
+                  POST /stats HTTP/1.1\r\nContent-Length: 0\r\n\r\n\
+                  GET / HTTP/1.1\r\nConnection: close\r\n\r\n\
+                  GET /stats HTTP/1.1\r\n\r\n"
+                    .to_vec(),
+            ),
+            output: vec![],
+        };
+        stats.serve_connection(&mut stream).unwrap();
+        let output = String::from_utf8(stream.output).unwrap();
+        let responses: Vec<&str> = output.split("HTTP/1.1 ").skip(1).collect();
+        // nothing for the request after the one that closed
+        assert_eq!(responses.len(), 3);
+        assert!(responses[0].starts_with("200 OK\r\n"));
+        assert!(responses[0].contains("Content-Type: application/json\r\n"));
+        let body = responses[0].split("\r\n\r\n").nth(1).unwrap();
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(json["queries"], 4);
+        assert!(responses[1].starts_with("405 Method Not Allowed\r\n"));
+        assert!(responses[1].contains("Allow: GET\r\n"));
+        assert!(responses[2].starts_with("404 Not Found\r\n"));
+        assert!(responses[2].contains("Connection: close\r\n"));
+    }
+}
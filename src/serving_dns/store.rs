@@ -0,0 +1,566 @@
+// The records we serve, and how to find the ones answering a question.
+use std::collections::HashMap;
+
+use super::wire::labels;
+use super::{Question, QuestionClass, QuestionType, RData, Record, ResponseCode};
+
+/// Labels of a name in presentation format, lowercased as names compare case insensitively,
+/// and from the root down: `www.example.com` is `["com", "example", "www"]`
+pub fn name_key(name: &str) -> Vec<Vec<u8>> {
+    let mut key: Vec<Vec<u8>> = labels(name)
+        .into_iter()
+        .map(|l| l.to_ascii_lowercase())
+        .collect();
+    key.reverse();
+    key
+}
+
+#[derive(Debug, Clone)]
+pub struct Answer {
+    /// The owner, a leading `*` label making it a wildcard
+    pub name: String,
+    /// `name_key` of the owner
+    key: Vec<Vec<u8>>,
+    pub atype: QuestionType,
+    pub class: QuestionClass,
+    pub ttl: u32,
+    pub data: RData,
+}
+
+impl Answer {
+    pub fn new(
+        name: String,
+        atype: QuestionType,
+        class: QuestionClass,
+        ttl: u32,
+        data: RData,
+    ) -> Answer {
+        Answer {
+            key: name_key(&name),
+            name,
+            atype,
+            class,
+            ttl,
+            data,
+        }
+    }
+    /// `name_key` of the owner
+    pub fn key(&self) -> &[Vec<u8>] {
+        &self.key
+    }
+
+    /// The record to answer with, `name` being what was asked for, as wildcards own many names
+    pub fn to_record(&self, name: &str) -> Record {
+        Record {
+            name: name.to_string(),
+            rtype: self.atype,
+            class: self.class,
+            ttl: self.ttl,
+            data: self.data.clone(),
+        }
+    }
+}
+
+/// Longest CNAME chain followed before giving up on a name
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// What our records have to say about a question
+pub struct Lookup {
+    pub code: ResponseCode,
+    /// The last name of the CNAME chain, the one the answer is about
+    pub name: String,
+    /// The name is in a zone of ours, or we have records for it anyway
+    pub authoritative: bool,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+    /// Glue: the addresses of the name servers of a referral, when we have them
+    pub additionals: Vec<Record>,
+}
+
+/// A name we know of, either because it owns records or because some name below it does
+#[derive(Debug, Default)]
+struct Node {
+    /// Indexes into `Store::answers`
+    records: Vec<usize>,
+    /// The SOA, if the name is the apex of a zone
+    soa: Option<usize>,
+}
+
+/// Every record we serve, indexed by owner. It doesn't change once built, so the workers share
+/// one. Every lookup is a hash lookup per label at most, however many records there are.
+pub struct Store {
+    answers: Vec<Answer>,
+    /// Every owner and all of their ancestors, by `name_key`
+    nodes: HashMap<Vec<Vec<u8>>, Node>,
+}
+
+impl Store {
+    pub fn new(answers: Vec<Answer>) -> Store {
+        let mut nodes: HashMap<Vec<Vec<u8>>, Node> = HashMap::new();
+        for (i, a) in answers.iter().enumerate() {
+            for n in 0..a.key.len() {
+                if nodes.contains_key(&a.key[..n]) {
+                    continue;
+                }
+                nodes.insert(a.key[..n].to_vec(), Node::default());
+            }
+            let node = nodes.entry(a.key.clone()).or_default();
+            node.records.push(i);
+            if a.atype == QuestionType::SOA && node.soa.is_none() {
+                node.soa = Some(i);
+            }
+        }
+        Store { answers, nodes }
+    }
+
+    pub fn answers(&self) -> &[Answer] {
+        &self.answers
+    }
+
+    /// The SOA of the closest zone `key` is in, if it is in one of ours
+    pub fn zone_of(&self, key: &[Vec<u8>]) -> Option<&Answer> {
+        (0..=key.len())
+            .rev()
+            .find_map(|n| self.nodes.get(&key[..n])?.soa)
+            .map(|i| &self.answers[i])
+    }
+
+    /// The SOA of the zone whose apex is `name`, if we have that zone
+    pub fn soa(&self, name: &str) -> Option<&Answer> {
+        let i = self.nodes.get(&name_key(name))?.soa?;
+        Some(&self.answers[i])
+    }
+
+    /// The SOAs of all of our zones
+    pub fn zones(&self) -> impl Iterator<Item = &Answer> {
+        self.nodes
+            .values()
+            .filter_map(|n| n.soa)
+            .map(|i| &self.answers[i])
+    }
+
+    /// Every record in the zone of `soa`, but not those of zones below it
+    pub fn zone_records(&self, soa: &Answer) -> Vec<&Answer> {
+        self.answers
+            .iter()
+            .filter(|a| self.zone_of(&a.key).is_some_and(|z| std::ptr::eq(z, soa)))
+            .collect()
+    }
+
+    /// The records at `name`, following RFC 4592: a name exists if it owns records or has some
+    /// below it (an empty non-terminal), and only names that don't exist get the records of the
+    /// wildcard at their closest encloser, the nearest ancestor that does. `None` if the name
+    /// doesn't exist and there's no such wildcard.
+    /// https://datatracker.ietf.org/doc/html/rfc4592#section-3.3.1
+    fn records_at(&self, name: &str) -> Option<Vec<&Answer>> {
+        let mut key = name_key(name);
+        let node = match self.nodes.get(&key) {
+            Some(node) => node,
+            None => {
+                // the root exists as soon as anything does, so this finds the closest encloser
+                let encloser = (0..key.len())
+                    .rev()
+                    .find(|&n| self.nodes.contains_key(&key[..n]))?;
+                key.truncate(encloser);
+                key.push(b"*".to_vec());
+                self.nodes.get(&key)?
+            }
+        };
+        Some(node.records.iter().map(|&i| &self.answers[i]).collect())
+    }
+
+    /// The records owned by `name` itself, leaving wildcards out
+    pub fn owned_by(&self, name: &str) -> Vec<&Answer> {
+        self.nodes.get(&name_key(name)).map_or(vec![], |n| {
+            n.records.iter().map(|&i| &self.answers[i]).collect()
+        })
+    }
+
+    /// The NS records of the zone cut `key` is at or below, if it is in a zone of ours but
+    /// delegated away from it: the highest name between the apex and `key` with NS records of
+    /// its own
+    /// https://datatracker.ietf.org/doc/html/rfc1034#section-4.2.1
+    pub fn delegation(&self, key: &[Vec<u8>]) -> Option<Vec<&Answer>> {
+        let apex = self.zone_of(key)?.key.len();
+        (apex + 1..=key.len()).find_map(|n| {
+            let ns: Vec<&Answer> = self
+                .nodes
+                .get(&key[..n])?
+                .records
+                .iter()
+                .map(|&i| &self.answers[i])
+                .filter(|a| a.atype == QuestionType::NS)
+                .collect();
+            (!ns.is_empty()).then_some(ns)
+        })
+    }
+
+    /// The addresses we have for the name servers in `ns`, to go along with a referral
+    fn glue(&self, ns: &[&Answer]) -> Vec<Record> {
+        ns.iter()
+            .filter_map(|a| match &a.data {
+                RData::NS(target) => Some(target),
+                _ => None,
+            })
+            .flat_map(|target| {
+                self.owned_by(target)
+                    .into_iter()
+                    .filter(|a| matches!(a.atype, QuestionType::A | QuestionType::AAAA))
+                    .map(|a| a.to_record(&a.name))
+            })
+            .collect()
+    }
+
+    /// The types of the records at `name`, wildcards included, or `None` if it doesn't exist
+    pub fn types_at(&self, name: &str) -> Option<Vec<QuestionType>> {
+        let mut types: Vec<QuestionType> = self.records_at(name)?.iter().map(|a| a.atype).collect();
+        types.sort_by_key(|&t| u16::from(t));
+        types.dedup();
+        Some(types)
+    }
+
+    /// Answers `q`, following CNAMEs through our records. The RRset asked for is rotated left by
+    /// `rotate` records. ANY only gets one RRset of those at the name, so it can't be used to
+    /// amplify. Names below a zone cut get a referral to the name servers of the cut, except for
+    /// DS records at the cut itself, which belong to the parent. For names that don't exist
+    /// (NXDOMAIN) or lack the type (NODATA), the SOA of the closest zone goes into the authority
+    /// section, with the negative TTL as its TTL.
+    /// https://datatracker.ietf.org/doc/html/rfc1034#section-4.3.2
+    /// https://datatracker.ietf.org/doc/html/rfc2308#section-3
+    /// https://datatracker.ietf.org/doc/html/rfc8482#section-4.1
+    pub fn lookup(&self, q: &Question, rotate: usize) -> Lookup {
+        let mut found = vec![];
+        let mut name = q.domain.clone();
+        let mut code = ResponseCode::NoError;
+        let mut chain = 0;
+        let mut cut = None;
+        loop {
+            let key = name_key(&name);
+            if let Some(ns) = self.delegation(&key) {
+                if ns[0].key.len() != key.len() || q.qtype != QuestionType::DS {
+                    cut = Some(ns);
+                    break;
+                }
+            }
+            let Some(owned) = self.records_at(&name) else {
+                // a CNAME to a name of ours that doesn't exist makes the whole answer NXDOMAIN, as
+                // it is about the last name in the chain. Names elsewhere are for the client to
+                // chase.
+                if chain == 0 || self.zone_of(&name_key(&name)).is_some() {
+                    code = ResponseCode::NxDomain;
+                }
+                break;
+            };
+            let cname = owned.iter().find(|a| a.atype == QuestionType::CNAME);
+            match cname {
+                Some(a) if q.qtype != QuestionType::CNAME => {
+                    found.push(a.to_record(&name));
+                    let RData::CNAME(target) = &a.data else {
+                        unreachable!("CNAME without a CNAME target")
+                    };
+                    name = target.clone();
+                    chain += 1;
+                    // a loop, or too long to bother
+                    if chain > MAX_CNAME_CHAIN
+                        || found.iter().any(|r| r.name.eq_ignore_ascii_case(&name))
+                    {
+                        return Lookup {
+                            code: ResponseCode::ServFail,
+                            name,
+                            authoritative: false,
+                            answers: vec![],
+                            authorities: vec![],
+                            additionals: vec![],
+                        };
+                    }
+                }
+                _ => {
+                    let qtype = match q.qtype {
+                        QuestionType::ANY => owned.first().map_or(q.qtype, |a| a.atype),
+                        qtype => qtype,
+                    };
+                    let mut rrset: Vec<Record> = owned
+                        .iter()
+                        .filter(|a| a.atype == qtype)
+                        .map(|a| a.to_record(&name))
+                        .collect();
+                    if !rrset.is_empty() {
+                        let n = rrset.len();
+                        rrset.rotate_left(rotate % n);
+                    }
+                    found.extend(rrset);
+                    break;
+                }
+            }
+        }
+
+        if let Some(ns) = cut {
+            // the CNAMEs on the way, if any, are ours
+            return Lookup {
+                code,
+                name,
+                authoritative: !found.is_empty(),
+                answers: found,
+                authorities: ns.iter().map(|a| a.to_record(&a.name)).collect(),
+                additionals: self.glue(&ns),
+            };
+        }
+        let mut authorities = vec![];
+        // NODATA is a NoError without the records for the last name
+        if code == ResponseCode::NxDomain || found.last().is_none_or(|r| r.name != name) {
+            if let Some(soa) = self.zone_of(&name_key(&name)) {
+                let mut record = soa.to_record(&soa.name);
+                if let RData::SOA { minimum, .. } = soa.data {
+                    record.ttl = record.ttl.min(minimum);
+                }
+                authorities.push(record);
+            }
+        }
+        Lookup {
+            code,
+            name,
+            authoritative: !found.is_empty() || self.zone_of(&name_key(&q.domain)).is_some(),
+            answers: found,
+            authorities,
+            additionals: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::zone;
+    use super::*;
+
+    const ZONE: &str = "$TTL 3600
+@ SOA ns hostmaster 1 3600 600 86400 300
+@ NS ns
+ns A 192.0.2.53
+www CNAME web
+web A 192.0.2.1
+web A 192.0.2.2
+alias CNAME www
+gone CNAME nothere
+out CNAME www.example.net.
+loop1 CNAME loop2
+loop2 CNAME loop1
+*.wild A 192.0.2.9
+sub.wild TXT \"here\"
+a.b.ent A 192.0.2.3
+*.ent A 192.0.2.4
+child NS ns.child
+ns.child A 192.0.2.54
+child DS \\# 8 3039 0D 02 01234567
+tochild CNAME www.child
+";
+
+    fn store(text: &str) -> Store {
+        Store::new(zone::parse(text, "example.com").unwrap())
+    }
+
+    fn lookup(store: &Store, name: &str, qtype: QuestionType) -> Lookup {
+        let q = Question {
+            domain: name.to_string(),
+            qtype,
+            class: QuestionClass::IN,
+        };
+        store.lookup(&q, 0)
+    }
+
+    /// The records as `owner type data`
+    fn short(records: &[Record]) -> Vec<String> {
+        records
+            .iter()
+            .map(|r| format!("{} {} {}", r.name, r.rtype, r.data))
+            .collect()
+    }
+
+    #[test]
+    fn follows_cname_chains() {
+        let found = lookup(&store(ZONE), "alias.example.com", QuestionType::A);
+        assert_eq!(found.code, ResponseCode::NoError);
+        assert_eq!(found.name, "web.example.com");
+        assert_eq!(
+            short(&found.answers),
+            [
+                "alias.example.com CNAME www.example.com.",
+                "www.example.com CNAME web.example.com.",
+                "web.example.com A 192.0.2.1",
+                "web.example.com A 192.0.2.2",
+            ]
+        );
+        assert!(found.authorities.is_empty());
+    }
+
+    #[test]
+    fn cname_questions_get_the_cname() {
+        let found = lookup(&store(ZONE), "www.example.com", QuestionType::CNAME);
+        assert_eq!(
+            short(&found.answers),
+            ["www.example.com CNAME web.example.com."]
+        );
+    }
+
+    #[test]
+    fn cname_to_missing_name_of_ours_is_nxdomain() {
+        let found = lookup(&store(ZONE), "gone.example.com", QuestionType::A);
+        assert_eq!(found.code, ResponseCode::NxDomain);
+        assert_eq!(
+            short(&found.answers),
+            ["gone.example.com CNAME nothere.example.com."]
+        );
+        assert_eq!(found.authorities.len(), 1);
+    }
+
+    #[test]
+    fn cname_out_of_our_zones_is_for_the_client() {
+        let found = lookup(&store(ZONE), "out.example.com", QuestionType::A);
+        assert_eq!(found.code, ResponseCode::NoError);
+        assert_eq!(
+            short(&found.answers),
+            ["out.example.com CNAME www.example.net."]
+        );
+        assert!(found.authorities.is_empty());
+    }
+
+    #[test]
+    fn cname_loops_fail() {
+        let found = lookup(&store(ZONE), "loop1.example.com", QuestionType::A);
+        assert_eq!(found.code, ResponseCode::ServFail);
+        assert!(found.answers.is_empty());
+    }
+
+    #[test]
+    fn long_cname_chains_fail() {
+        let mut text = ZONE.to_string();
+        for i in 0..=MAX_CNAME_CHAIN {
+            text += &format!("c{} CNAME c{}\n", i, i + 1);
+        }
+        text += &format!("c{} A 192.0.2.3\n", MAX_CNAME_CHAIN + 1);
+        let zone = store(&text);
+        let found = lookup(&zone, "c0.example.com", QuestionType::A);
+        assert_eq!(found.code, ResponseCode::ServFail);
+        let found = lookup(&zone, "c1.example.com", QuestionType::A);
+        assert_eq!(found.code, ResponseCode::NoError);
+        assert_eq!(found.answers.len(), MAX_CNAME_CHAIN + 1);
+    }
+
+    #[test]
+    fn negative_answers_have_the_soa_with_the_negative_ttl() {
+        let zone = store(ZONE);
+        let nxdomain = lookup(&zone, "nothere.example.com", QuestionType::A);
+        let nodata = lookup(&zone, "web.example.com", QuestionType::TXT);
+        assert_eq!(nxdomain.code, ResponseCode::NxDomain);
+        assert_eq!(nodata.code, ResponseCode::NoError);
+        for found in [nxdomain, nodata] {
+            assert!(found.answers.is_empty());
+            assert_eq!(found.authorities.len(), 1);
+            assert_eq!(found.authorities[0].rtype, QuestionType::SOA);
+            assert_eq!(found.authorities[0].name, "example.com");
+            assert_eq!(found.authorities[0].ttl, 300);
+        }
+        // or the SOA's own TTL if lower
+        let lower = store(&ZONE.replace("@ SOA", "@ 60 SOA"));
+        let found = lookup(&lower, "nothere.example.com", QuestionType::A);
+        assert_eq!(found.authorities[0].ttl, 60);
+    }
+
+    #[test]
+    fn wildcards_answer_for_names_that_dont_exist() {
+        let zone = store(ZONE);
+        for name in ["foo.wild.example.com", "x.y.wild.example.com"] {
+            let found = lookup(&zone, name, QuestionType::A);
+            assert_eq!(found.code, ResponseCode::NoError);
+            // owned by the name asked about, not the wildcard
+            assert_eq!(short(&found.answers), [format!("{} A 192.0.2.9", name)]);
+        }
+        // but only for their types
+        let found = lookup(&zone, "foo.wild.example.com", QuestionType::TXT);
+        assert_eq!(found.code, ResponseCode::NoError);
+        assert!(found.answers.is_empty());
+        assert_eq!(found.authorities.len(), 1);
+    }
+
+    #[test]
+    fn wildcards_leave_names_that_exist_alone() {
+        let found = lookup(&store(ZONE), "sub.wild.example.com", QuestionType::A);
+        assert_eq!(found.code, ResponseCode::NoError);
+        assert!(found.answers.is_empty());
+        let found = lookup(&store(ZONE), "x.sub.wild.example.com", QuestionType::A);
+        assert_eq!(found.code, ResponseCode::NxDomain);
+    }
+
+    #[test]
+    fn empty_non_terminals_exist() {
+        let zone = store(ZONE);
+        // b.ent owns nothing but has a.b.ent below it: NODATA, and the wildcard beside it
+        // doesn't apply
+        for name in ["ent.example.com", "b.ent.example.com"] {
+            let found = lookup(&zone, name, QuestionType::A);
+            assert_eq!(found.code, ResponseCode::NoError, "{}", name);
+            assert!(found.answers.is_empty(), "{}", name);
+            assert_eq!(found.authorities.len(), 1, "{}", name);
+        }
+        let found = lookup(&zone, "c.ent.example.com", QuestionType::A);
+        assert_eq!(short(&found.answers), ["c.ent.example.com A 192.0.2.4"]);
+        let found = lookup(&zone, "x.b.ent.example.com", QuestionType::A);
+        assert_eq!(found.code, ResponseCode::NxDomain);
+    }
+
+    #[test]
+    fn names_compare_case_insensitively() {
+        let found = lookup(&store(ZONE), "WEB.Example.COM", QuestionType::A);
+        assert_eq!(found.answers.len(), 2);
+        let found = lookup(&store(ZONE), "Foo.WILD.example.com", QuestionType::A);
+        assert_eq!(found.answers.len(), 1);
+    }
+
+    #[test]
+    fn names_below_a_cut_get_a_referral() {
+        let zone = store(ZONE);
+        for name in ["child.example.com", "www.child.example.com"] {
+            let found = lookup(&zone, name, QuestionType::A);
+            assert_eq!(found.code, ResponseCode::NoError);
+            assert!(!found.authoritative);
+            assert!(found.answers.is_empty());
+            assert_eq!(
+                short(&found.authorities),
+                ["child.example.com NS ns.child.example.com."]
+            );
+            assert_eq!(
+                short(&found.additionals),
+                ["ns.child.example.com A 192.0.2.54"]
+            );
+        }
+    }
+
+    #[test]
+    fn ds_at_the_cut_is_the_parents() {
+        let zone = store(ZONE);
+        let found = lookup(&zone, "child.example.com", QuestionType::DS);
+        assert!(found.authoritative);
+        assert_eq!(
+            short(&found.answers),
+            ["child.example.com DS 12345 13 2 01234567"]
+        );
+        assert!(found.authorities.is_empty());
+        // below it, the child's
+        let found = lookup(&zone, "www.child.example.com", QuestionType::DS);
+        assert!(found.answers.is_empty());
+        assert_eq!(found.authorities[0].rtype, QuestionType::NS);
+    }
+
+    #[test]
+    fn cnames_into_a_cut_come_with_the_referral() {
+        let found = lookup(&store(ZONE), "tochild.example.com", QuestionType::A);
+        assert!(found.authoritative);
+        assert_eq!(
+            short(&found.answers),
+            ["tochild.example.com CNAME www.child.example.com."]
+        );
+        assert_eq!(
+            short(&found.authorities),
+            ["child.example.com NS ns.child.example.com."]
+        );
+    }
+}
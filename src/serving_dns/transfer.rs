@@ -0,0 +1,393 @@
+// Zone transfers to secondaries, whole (AXFR) or just what changed since the serial they have
+// (IXFR), and the NOTIFY messages telling them there's something new to transfer.
+// https://datatracker.ietf.org/doc/html/rfc5936
+// https://datatracker.ietf.org/doc/html/rfc1995
+// https://datatracker.ietf.org/doc/html/rfc1996
+use std::collections::HashMap;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::store::{name_key, Answer, Store};
+use super::wire::Writer;
+use super::{
+    Flags, Header, Message, Opcode, QueryType, Question, QuestionClass, QuestionType, RData,
+    Record, ResponseCode,
+};
+
+/// Changes kept per zone. Secondaries further behind than this get the whole zone.
+const MAX_JOURNAL: usize = 100;
+/// Transfers are split in messages of about this size, well below the 64K TCP allows
+const MESSAGE_SIZE: usize = 16 * 1024;
+/// How many times a NOTIFY is sent without an answer before giving up, waiting twice as long
+/// each time
+const NOTIFY_TRIES: u32 = 5;
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What it takes to go from one version of a zone to the next
+struct Change {
+    /// The SOA before and after
+    from: Record,
+    to: Record,
+    removed: Vec<Record>,
+    added: Vec<Record>,
+}
+
+/// The recent changes of every zone, for IXFR
+#[derive(Default)]
+pub struct Journal {
+    /// By `name_key` of the apex, oldest first
+    zones: HashMap<Vec<Vec<u8>>, Vec<Change>>,
+}
+
+/// Whether serial `a` comes after `b`, as serials wrap around
+/// https://datatracker.ietf.org/doc/html/rfc1982#section-3.2
+pub fn serial_newer(a: u32, b: u32) -> bool {
+    a != b && (a.wrapping_sub(b) as i32) > 0
+}
+
+fn serial(soa: &Answer) -> u32 {
+    match soa.data {
+        RData::SOA { serial, .. } => serial,
+        _ => unreachable!("SOA without SOA data"),
+    }
+}
+
+/// A record in canonical form, to tell whether two versions of a zone have it in common
+fn canonical(a: &Answer) -> Vec<u8> {
+    let mut w = Writer::canonical();
+    // names in the store were checked when loaded
+    a.to_record(&a.name)
+        .write(&mut w)
+        .expect("record in the store can't be encoded");
+    w.into_bytes()
+}
+
+impl Journal {
+    /// Records how every zone changed from `old` to `new`, returning the SOAs of the ones that
+    /// did. Zones whose serial didn't go up lose their journal, as secondaries can't tell what
+    /// they have from what we have anymore.
+    pub fn record(&mut self, old: &Store, new: &Store) -> Vec<Record> {
+        let mut changed = vec![];
+        for soa in new.zones() {
+            let Some(old_soa) = old.soa(&soa.name) else {
+                continue;
+            };
+            let index = |store: &Store, soa| -> HashMap<Vec<u8>, Record> {
+                store
+                    .zone_records(soa)
+                    .into_iter()
+                    .map(|a| (canonical(a), a.to_record(&a.name)))
+                    .collect()
+            };
+            let before = index(old, old_soa);
+            let after = index(new, soa);
+            if before.len() == after.len() && before.keys().all(|k| after.contains_key(k)) {
+                continue;
+            }
+            let key = name_key(&soa.name);
+            if !serial_newer(serial(soa), serial(old_soa)) {
+                println!(
+                    "{}: records changed but the serial didn't go up from {}",
+                    soa.name,
+                    serial(old_soa)
+                );
+                self.zones.remove(&key);
+                continue;
+            }
+            let diff = |a: &HashMap<Vec<u8>, Record>, b: &HashMap<Vec<u8>, Record>| {
+                a.iter()
+                    .filter(|(k, r)| r.rtype != QuestionType::SOA && !b.contains_key(*k))
+                    .map(|(_, r)| r.clone())
+                    .collect()
+            };
+            let changes = self.zones.entry(key).or_default();
+            changes.push(Change {
+                from: old_soa.to_record(&old_soa.name),
+                to: soa.to_record(&soa.name),
+                removed: diff(&before, &after),
+                added: diff(&after, &before),
+            });
+            if changes.len() > MAX_JOURNAL {
+                changes.remove(0);
+            }
+            changed.push(soa.to_record(&soa.name));
+        }
+        changed
+    }
+
+    /// The records of an IXFR from `serial` of the zone at `soa`: just the SOA if the client is
+    /// up to date, the changes since if we still have all of them, or else the whole zone
+    /// https://datatracker.ietf.org/doc/html/rfc1995#section-4
+    pub fn ixfr(&self, store: &Store, soa: &Answer, serial_from: u32) -> Vec<Record> {
+        let current = soa.to_record(&soa.name);
+        if !serial_newer(serial(soa), serial_from) {
+            return vec![current];
+        }
+        let changes = self
+            .zones
+            .get(&name_key(&soa.name))
+            .map_or(&[][..], |c| &c[..]);
+        let from_serial = |c: &Change| match c.from.data {
+            RData::SOA { serial, .. } => serial,
+            _ => unreachable!("SOA without SOA data"),
+        };
+        let Some(start) = changes.iter().position(|c| from_serial(c) == serial_from) else {
+            return axfr(store, soa);
+        };
+        let mut records = vec![current.clone()];
+        for c in &changes[start..] {
+            records.push(c.from.clone());
+            records.extend(c.removed.iter().cloned());
+            records.push(c.to.clone());
+            records.extend(c.added.iter().cloned());
+        }
+        records.push(current);
+        records
+    }
+}
+
+/// The records of an AXFR of the zone at `soa`: all of them, between two copies of the SOA
+/// https://datatracker.ietf.org/doc/html/rfc5936#section-2.2
+pub fn axfr(store: &Store, soa: &Answer) -> Vec<Record> {
+    let soa_record = soa.to_record(&soa.name);
+    let mut records = vec![soa_record.clone()];
+    records.extend(
+        store
+            .zone_records(soa)
+            .into_iter()
+            .filter(|a| !std::ptr::eq(*a, soa))
+            .map(|a| a.to_record(&a.name)),
+    );
+    records.push(soa_record);
+    records
+}
+
+/// Replies to `query` carrying `records`, as many as it takes for each to stay around
+/// `MESSAGE_SIZE`
+pub fn messages(query: &Message, records: Vec<Record>) -> Vec<Message> {
+    let reply = || {
+        let mut m = query.reply(ResponseCode::NoError);
+        m.header.flags.authoritative_answer = true;
+        m
+    };
+    let mut messages = vec![reply()];
+    let mut size = 0;
+    for r in records {
+        // alone it can't compress as much as among others, so this is the most it takes
+        let mut w = Writer::new();
+        let len = r.write(&mut w).map_or(0, |_| w.len());
+        let last = messages.last_mut().unwrap();
+        if size + len > MESSAGE_SIZE && !last.answers.is_empty() {
+            messages.push(reply());
+            size = 0;
+        }
+        size += len;
+        messages.last_mut().unwrap().answers.push(r);
+    }
+    messages
+}
+
+/// Tells each of `secondaries` that the zone of `soa` changed, in the background. Each gets the
+/// NOTIFY again until it answers, a few times at most.
+/// https://datatracker.ietf.org/doc/html/rfc1996#section-3.6
+pub fn notify(soa: Record, secondaries: &[String]) {
+    for secondary in secondaries {
+        let secondary = secondary.clone();
+        let soa = soa.clone();
+        thread::spawn(move || match notify_one(&soa, &secondary) {
+            Ok(()) => println!("{} notified of {}", secondary, soa.name),
+            Err(e) => println!("Can't notify {} of {}: {}", secondary, soa.name, e),
+        });
+    }
+}
+
+fn notify_one(soa: &Record, secondary: &str) -> Result<(), String> {
+    let mut id = [0; 2];
+    SystemRandom::new()
+        .fill(&mut id)
+        .map_err(|_| "no random id")?;
+    let id = u16::from_be_bytes(id);
+    let mut flags = Flags::query(false);
+    flags.opcode = Opcode::Notify;
+    flags.authoritative_answer = true;
+    let message = Message {
+        header: Header {
+            identification: id,
+            flags,
+        },
+        questions: vec![Question {
+            domain: soa.name.clone(),
+            qtype: QuestionType::SOA,
+            class: QuestionClass::IN,
+        }],
+        // a hint of the new serial
+        answers: vec![soa.clone()],
+        authorities: vec![],
+        additionals: vec![],
+        edns: None,
+        tsig: None,
+    };
+    let bytes = message.to_bytes().map_err(|e| e.to_string())?;
+    let addr = secondary
+        .to_socket_addrs()
+        .map_err(|e| e.to_string())?
+        .next()
+        .ok_or("no address to send to")?;
+    let local = if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).map_err(|e| e.to_string())?;
+    socket.connect(addr).map_err(|e| e.to_string())?;
+    let mut buf = [0; 512];
+    let mut timeout = NOTIFY_TIMEOUT;
+    for _ in 0..NOTIFY_TRIES {
+        socket.send(&bytes).map_err(|e| e.to_string())?;
+        socket
+            .set_read_timeout(Some(timeout))
+            .map_err(|e| e.to_string())?;
+        while let Ok(len) = socket.recv(&mut buf) {
+            match Message::from_bytes(&buf[..len]) {
+                Ok(m)
+                    if m.header.identification == id
+                        && matches!(m.header.flags.query_type, QueryType::Reply) =>
+                {
+                    return Ok(())
+                }
+                _ => continue,
+            }
+        }
+        timeout *= 2;
+    }
+    Err("no answer".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::zone;
+    use super::*;
+
+    /// The zone at `serial`, with `records` besides the SOA
+    fn store(serial: u32, records: &str) -> Store {
+        let text = format!(
+            "$TTL 3600\n@ SOA ns hostmaster {} 3600 600 86400 300\n@ NS ns\n{}",
+            serial, records
+        );
+        Store::new(zone::parse(&text, "example.com").unwrap())
+    }
+
+    fn ixfr(journal: &Journal, store: &Store, serial_from: u32) -> Vec<String> {
+        let soa = store.soa("example.com").unwrap();
+        journal
+            .ixfr(store, soa, serial_from)
+            .iter()
+            .map(|r| match &r.data {
+                RData::SOA { serial, .. } => format!("SOA {}", serial),
+                data => format!("{} {} {}", r.name, r.rtype, data),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn serials_wrap_around() {
+        assert!(serial_newer(2, 1));
+        assert!(!serial_newer(1, 2));
+        assert!(!serial_newer(1, 1));
+        assert!(serial_newer(0, u32::MAX));
+        assert!(serial_newer(5, u32::MAX - 5));
+    }
+
+    #[test]
+    fn clients_up_to_date_get_the_soa() {
+        let journal = Journal::default();
+        let zone = store(5, "");
+        assert_eq!(ixfr(&journal, &zone, 5), ["SOA 5"]);
+        // or one from the future
+        assert_eq!(ixfr(&journal, &zone, 6), ["SOA 5"]);
+    }
+
+    #[test]
+    fn journal_has_the_differences() {
+        let mut journal = Journal::default();
+        let v1 = store(1, "a A 192.0.2.1\nb A 192.0.2.2\n");
+        let v2 = store(2, "a A 192.0.2.1\nb A 192.0.2.3\n");
+        let v3 = store(3, "a A 192.0.2.1\nb A 192.0.2.3\nc A 192.0.2.4\n");
+        let changed = journal.record(&v1, &v2);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].name, "example.com");
+        journal.record(&v2, &v3);
+        assert_eq!(
+            ixfr(&journal, &v3, 1),
+            [
+                "SOA 3",
+                "SOA 1",
+                "b.example.com A 192.0.2.2",
+                "SOA 2",
+                "b.example.com A 192.0.2.3",
+                "SOA 2",
+                "SOA 3",
+                "c.example.com A 192.0.2.4",
+                "SOA 3",
+            ]
+        );
+        assert_eq!(
+            ixfr(&journal, &v3, 2),
+            [
+                "SOA 3",
+                "SOA 2",
+                "SOA 3",
+                "c.example.com A 192.0.2.4",
+                "SOA 3"
+            ]
+        );
+    }
+
+    #[test]
+    fn only_changed_zones_are_recorded() {
+        let mut journal = Journal::default();
+        let v1 = store(1, "a A 192.0.2.1\n");
+        assert!(journal.record(&v1, &store(1, "a A 192.0.2.1\n")).is_empty());
+        // a new serial alone is a change, of the SOA
+        let v2 = store(2, "a A 192.0.2.1\n");
+        assert_eq!(journal.record(&v1, &v2).len(), 1);
+        assert_eq!(ixfr(&journal, &v2, 1), ["SOA 2", "SOA 1", "SOA 2", "SOA 2"]);
+    }
+
+    #[test]
+    fn clients_too_far_behind_get_the_whole_zone() {
+        let mut journal = Journal::default();
+        let mut old = store(1, "");
+        for serial in 2..=MAX_JOURNAL as u32 + 2 {
+            let new = store(serial, &format!("a A 192.0.2.{}\n", serial));
+            journal.record(&old, &new);
+            old = new;
+        }
+        let last = MAX_JOURNAL as u32 + 2;
+        let axfr = [
+            format!("SOA {}", last),
+            "example.com NS ns.example.com.".to_string(),
+            format!("a.example.com A 192.0.2.{}", last),
+            format!("SOA {}", last),
+        ];
+        // the first change fell out of the journal
+        assert_eq!(ixfr(&journal, &old, 1), axfr);
+        assert_eq!(ixfr(&journal, &old, 2).len(), 2 + 4 * MAX_JOURNAL);
+        // and nobody had this one
+        assert_eq!(ixfr(&journal, &old, u32::MAX - 10), axfr);
+    }
+
+    #[test]
+    fn changes_without_a_new_serial_empty_the_journal() {
+        let mut journal = Journal::default();
+        let v1 = store(1, "a A 192.0.2.1\n");
+        let v2 = store(2, "a A 192.0.2.2\n");
+        journal.record(&v1, &v2);
+        let v2_again = store(2, "a A 192.0.2.3\n");
+        assert!(journal.record(&v2, &v2_again).is_empty());
+        assert_eq!(ixfr(&journal, &v2_again, 1).len(), 4);
+    }
+}
@@ -0,0 +1,382 @@
+// TSIG: messages signed with a secret shared between client and server, HMAC-SHA256 only. The
+// MAC of a reply covers the MAC of the request, and over TCP each message of a zone transfer
+// covers the one before.
+// https://datatracker.ietf.org/doc/html/rfc8945
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::hmac;
+
+use super::store::name_key;
+use super::wire::{canonical_name, Writer};
+use super::{QuestionClass, QuestionType, RData, Record};
+
+const ALGORITHM: &str = "hmac-sha256";
+/// How many seconds our clocks can disagree by
+const FUDGE: u16 = 300;
+
+// https://datatracker.ietf.org/doc/html/rfc8945#section-4.3
+const BADSIG: u16 = 16;
+const BADKEY: u16 = 17;
+const BADTIME: u16 = 18;
+
+#[derive(Clone)]
+pub struct Key {
+    /// Lowercase, as the MAC covers it in canonical form
+    name: String,
+    secret: hmac::Key,
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // no secrets in logs
+        write!(f, "Key({})", self.name)
+    }
+}
+
+/// `NAME:SECRET`, the secret in base64, as `tsig-keygen` and `dig -y` have it
+impl FromStr for Key {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Key, String> {
+        let (name, secret) = s
+            .split_once(':')
+            .ok_or_else(|| format!("TSIG key {} is not NAME:SECRET", s))?;
+        let secret = base64::decode(secret).map_err(|e| format!("TSIG key {}: {}", name, e))?;
+        Ok(Key {
+            name: canonical_name(name).to_ascii_lowercase(),
+            secret: hmac::Key::new(hmac::HMAC_SHA256, &secret),
+        })
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Signs what goes back for a signed message, or carries the error when its TSIG didn't check
+/// out
+pub struct Signing {
+    key_name: String,
+    /// `None` if we don't know the key, and the replies go unsigned
+    key: Option<hmac::Key>,
+    /// The MAC of the request, and after the first reply the MAC of the last one
+    prior_mac: Vec<u8>,
+    original_id: u16,
+    error: u16,
+    /// Later messages of a zone transfer cover less
+    subsequent: bool,
+}
+
+impl Signing {
+    /// The TSIG record for the reply, with `mac` as its MAC
+    fn record(&self, time_signed: u64, mac: Vec<u8>) -> Record {
+        Record {
+            name: self.key_name.clone(),
+            rtype: QuestionType::TSIG,
+            class: QuestionClass::ANY,
+            ttl: 0,
+            data: RData::TSIG {
+                algorithm: ALGORITHM.to_string(),
+                time_signed,
+                fudge: FUDGE,
+                mac,
+                original_id: self.original_id,
+                error: self.error,
+                // a BADTIME tells the client what time we think it is
+                other: if self.error == BADTIME {
+                    time_signed.to_be_bytes()[2..].to_vec()
+                } else {
+                    vec![]
+                },
+            },
+        }
+    }
+
+    /// How much longer signing makes a message
+    pub fn overhead(&self) -> usize {
+        let mut w = Writer::new();
+        // names are checked before getting here
+        self.record(0, vec![0; 32])
+            .write(&mut w)
+            .map_or(0, |_| w.len())
+    }
+
+    /// Appends the TSIG to the encoded message in `bytes`
+    /// https://datatracker.ietf.org/doc/html/rfc8945#section-5.3
+    pub fn sign(&mut self, bytes: &mut Vec<u8>) {
+        let time_signed = now();
+        let mut data = vec![];
+        if !self.prior_mac.is_empty() {
+            data.extend((self.prior_mac.len() as u16).to_be_bytes());
+            data.extend(&self.prior_mac);
+        }
+        data.extend(&bytes[..]);
+        let record = self.record(time_signed, vec![]);
+        if self.subsequent {
+            data.extend(&time_signed.to_be_bytes()[2..]);
+            data.extend(FUDGE.to_be_bytes());
+        } else {
+            data.extend(variables(&record));
+        }
+        let mac = match &self.key {
+            Some(key) if self.error != BADSIG && self.error != BADKEY => {
+                hmac::sign(key, &data).as_ref().to_vec()
+            }
+            _ => vec![],
+        };
+        let mut w = Writer::new();
+        self.record(time_signed, mac.clone())
+            .write(&mut w)
+            .expect("TSIG name checked when verifying");
+        bytes.extend(w.into_bytes());
+        // one more additional record
+        let additionals = u16::from_be_bytes([bytes[10], bytes[11]]) + 1;
+        bytes[10..12].copy_from_slice(&additionals.to_be_bytes());
+        self.prior_mac = mac;
+        self.subsequent = true;
+    }
+
+    /// Whether the TSIG checked out
+    pub fn ok(&self) -> bool {
+        self.error == 0
+    }
+}
+
+/// The fields of a TSIG covered by its MAC, besides the message: all but the MAC itself and the
+/// original ID, names in canonical form
+fn variables(tsig: &Record) -> Vec<u8> {
+    let RData::TSIG {
+        algorithm,
+        time_signed,
+        fudge,
+        error,
+        other,
+        ..
+    } = &tsig.data
+    else {
+        unreachable!("TSIG without TSIG data")
+    };
+    let mut w = Writer::canonical();
+    // names that don't fit don't make it off the wire
+    let _ = w.name(&tsig.name, false);
+    w.u16(u16::from(QuestionClass::ANY));
+    w.u32(0);
+    let _ = w.name(algorithm, false);
+    w.bytes(&time_signed.to_be_bytes()[2..]);
+    w.u16(*fudge);
+    w.u16(*error);
+    w.u16(other.len() as u16);
+    w.bytes(other);
+    w.into_bytes()
+}
+
+/// Checks the TSIG at `signed_len` of the message in `buf` against `keys`. Either way it
+/// returns how to sign the replies, which carry the error if there was one.
+/// https://datatracker.ietf.org/doc/html/rfc8945#section-5.2
+pub fn verify(keys: &[Key], buf: &[u8], tsig: &Record, signed_len: usize) -> Signing {
+    let RData::TSIG {
+        algorithm,
+        time_signed,
+        fudge,
+        mac,
+        original_id,
+        ..
+    } = &tsig.data
+    else {
+        unreachable!("TSIG without TSIG data")
+    };
+    let key = keys
+        .iter()
+        .find(|k| name_key(&k.name) == name_key(&tsig.name));
+    let mut signing = Signing {
+        key_name: tsig.name.clone(),
+        key: key.map(|k| k.secret.clone()),
+        prior_mac: vec![],
+        original_id: *original_id,
+        error: 0,
+        subsequent: false,
+    };
+    if key.is_none() || !algorithm.eq_ignore_ascii_case(ALGORITHM) {
+        signing.error = BADKEY;
+        return signing;
+    }
+    // the message as it was signed: with its original ID and without the TSIG
+    let mut data = buf[..signed_len].to_vec();
+    data[..2].copy_from_slice(&original_id.to_be_bytes());
+    let additionals = u16::from_be_bytes([data[10], data[11]]) - 1;
+    data[10..12].copy_from_slice(&additionals.to_be_bytes());
+    data.extend(variables(tsig));
+    if hmac::verify(&key.unwrap().secret, &data, mac).is_err() {
+        signing.error = BADSIG;
+        return signing;
+    }
+    signing.prior_mac = mac.clone();
+    if now().abs_diff(*time_signed) > *fudge as u64 {
+        signing.error = BADTIME;
+    }
+    signing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Message, Question, ResponseCode};
+    use super::*;
+
+    fn key() -> Key {
+        "Key.Example.:c2VjcmV0c2VjcmV0c2VjcmV0c2VjcmV0"
+            .parse()
+            .unwrap()
+    }
+
+    fn query() -> Message {
+        let question = Question {
+            domain: "example.com".to_string(),
+            qtype: QuestionType::SOA,
+            class: QuestionClass::IN,
+        };
+        Message::query(0x1234, question)
+    }
+
+    /// `message` signed with `key` as a client would, at `time_signed`
+    fn signed(message: &Message, key: &Key, time_signed: u64) -> Vec<u8> {
+        let mut bytes = message.to_bytes().unwrap();
+        let mut tsig = Record {
+            name: key.name.clone(),
+            rtype: QuestionType::TSIG,
+            class: QuestionClass::ANY,
+            ttl: 0,
+            data: RData::TSIG {
+                algorithm: ALGORITHM.to_string(),
+                time_signed,
+                fudge: FUDGE,
+                mac: vec![],
+                original_id: message.header.identification,
+                error: 0,
+                other: vec![],
+            },
+        };
+        let data = [bytes.clone(), variables(&tsig)].concat();
+        if let RData::TSIG { mac, .. } = &mut tsig.data {
+            *mac = hmac::sign(&key.secret, &data).as_ref().to_vec();
+        }
+        let mut w = Writer::new();
+        tsig.write(&mut w).unwrap();
+        bytes.extend(w.into_bytes());
+        bytes[11] += 1;
+        bytes
+    }
+
+    /// Checks the TSIG of the message in `bytes`
+    fn verified(keys: &[Key], bytes: &[u8]) -> Signing {
+        let (tsig, len) = Message::from_bytes(bytes).unwrap().tsig.unwrap();
+        verify(keys, bytes, &tsig, len)
+    }
+
+    /// The TSIG of the message in `bytes`, and the message as it was before signing
+    fn split(bytes: &[u8]) -> (Record, Vec<u8>) {
+        let (tsig, len) = Message::from_bytes(bytes).unwrap().tsig.unwrap();
+        let mut unsigned = bytes[..len].to_vec();
+        unsigned[11] -= 1;
+        (tsig, unsigned)
+    }
+
+    fn mac(tsig: &Record) -> Vec<u8> {
+        match &tsig.data {
+            RData::TSIG { mac, .. } => mac.clone(),
+            _ => unreachable!("TSIG without TSIG data"),
+        }
+    }
+
+    #[test]
+    fn verifies_signed_requests() {
+        let bytes = signed(&query(), &key(), now());
+        let signing = verified(&[key()], &bytes);
+        assert!(signing.ok());
+        assert_eq!(signing.prior_mac, mac(&split(&bytes).0));
+    }
+
+    #[test]
+    fn verifies_requests_with_a_new_id() {
+        // forwarders can change it, the TSIG has the one it was signed with
+        let mut bytes = signed(&query(), &key(), now());
+        bytes[..2].copy_from_slice(&0x4321u16.to_be_bytes());
+        assert!(verified(&[key()], &bytes).ok());
+    }
+
+    #[test]
+    fn rejects_changed_messages() {
+        let mut bytes = signed(&query(), &key(), now());
+        // the question's type, SOA to NS
+        bytes[26] = 2;
+        let mut signing = verified(&[key()], &bytes);
+        assert_eq!(signing.error, BADSIG);
+        // the error goes back unsigned
+        let mut reply = query().reply(ResponseCode::NotAuth).to_bytes().unwrap();
+        signing.sign(&mut reply);
+        assert!(mac(&split(&reply).0).is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let other: Key = "other:c2VjcmV0c2VjcmV0c2VjcmV0c2VjcmV0".parse().unwrap();
+        let bytes = signed(&query(), &other, now());
+        assert_eq!(verified(&[key()], &bytes).error, BADKEY);
+        assert_eq!(verified(&[], &bytes).error, BADKEY);
+    }
+
+    #[test]
+    fn rejects_old_signatures() {
+        let time_signed = now() - FUDGE as u64 - 10;
+        let mut signing = verified(&[key()], &signed(&query(), &key(), time_signed));
+        assert_eq!(signing.error, BADTIME);
+        // signed, telling the client our time
+        let mut reply = query().reply(ResponseCode::NotAuth).to_bytes().unwrap();
+        signing.sign(&mut reply);
+        let (tsig, _) = split(&reply);
+        assert!(!mac(&tsig).is_empty());
+        let RData::TSIG { other, .. } = &tsig.data else {
+            unreachable!()
+        };
+        assert_eq!(other.len(), 6);
+    }
+
+    #[test]
+    fn replies_cover_the_request_mac() {
+        let request = signed(&query(), &key(), now());
+        let request_mac = mac(&split(&request).0);
+        let mut signing = verified(&[key()], &request);
+        let reply = query().reply(ResponseCode::NoError);
+        let mut first = reply.to_bytes().unwrap();
+        signing.sign(&mut first);
+        let (tsig, unsigned) = split(&first);
+        let data = [
+            &(request_mac.len() as u16).to_be_bytes()[..],
+            &request_mac,
+            &unsigned,
+            &variables(&tsig),
+        ]
+        .concat();
+        assert!(hmac::verify(&key().secret, &data, &mac(&tsig)).is_ok());
+
+        // and over TCP, each message covers the one before, with only the time of its own TSIG
+        let mut second = reply.to_bytes().unwrap();
+        signing.sign(&mut second);
+        let prior = mac(&tsig);
+        let (tsig, unsigned) = split(&second);
+        let RData::TSIG { time_signed, .. } = tsig.data else {
+            unreachable!()
+        };
+        let data = [
+            &(prior.len() as u16).to_be_bytes()[..],
+            &prior,
+            &unsigned,
+            &time_signed.to_be_bytes()[2..],
+            &FUDGE.to_be_bytes(),
+        ]
+        .concat();
+        assert!(hmac::verify(&key().secret, &data, &mac(&tsig)).is_ok());
+    }
+}
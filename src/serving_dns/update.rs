@@ -0,0 +1,488 @@
+// Dynamic updates: prerequisites on what a zone has, then records to add and delete, applied
+// all together or not at all. In an UPDATE the question section names the zone, the answer
+// section holds the prerequisites and the authority section the updates.
+// https://datatracker.ietf.org/doc/html/rfc2136
+use std::collections::HashMap;
+
+use super::store::{name_key, Answer, Store};
+use super::transfer::serial_newer;
+use super::{Message, QuestionClass, QuestionType, RData, Record, ResponseCode};
+
+/// Types that stand for more than one type, or for none, and so can't be added
+fn is_meta(t: QuestionType) -> bool {
+    matches!(
+        t,
+        QuestionType::ANY
+            | QuestionType::AXFR
+            | QuestionType::IXFR
+            | QuestionType::TSIG
+            | QuestionType::OPT
+    )
+}
+
+/// The same record, telling names apart case insensitively
+fn same_record(a: &Answer, r: &Record) -> bool {
+    a.atype == r.rtype
+        && a.key() == name_key(&r.name)
+        && a.data.to_canonical().ok() == r.data.to_canonical().ok()
+}
+
+/// The records of the zone after the update in `message`, or `None` if it changes nothing.
+/// Errors are the RCODE to reply with.
+pub fn apply(store: &Store, message: &Message) -> Result<Option<Vec<Answer>>, ResponseCode> {
+    // https://datatracker.ietf.org/doc/html/rfc2136#section-3.1
+    let [zone] = &message.questions[..] else {
+        return Err(ResponseCode::FormatError);
+    };
+    if zone.qtype != QuestionType::SOA || zone.class != QuestionClass::IN {
+        return Err(ResponseCode::FormatError);
+    }
+    let Some(soa) = store.soa(&zone.domain) else {
+        return Err(ResponseCode::NotAuth);
+    };
+    let apex = soa.key().to_vec();
+    let in_zone = |name: &str| {
+        store
+            .zone_of(&name_key(name))
+            .is_some_and(|z| std::ptr::eq(z, soa))
+    };
+
+    check_prerequisites(store, &message.answers, &in_zone)?;
+
+    // https://datatracker.ietf.org/doc/html/rfc2136#section-3.4.1
+    for r in &message.authorities {
+        if !in_zone(&r.name) {
+            return Err(ResponseCode::NotZone);
+        }
+        let empty = matches!(&r.data, RData::Unknown(d) if d.is_empty());
+        let ok = match r.class {
+            // data we couldn't make sense of is only fine for types we don't know
+            QuestionClass::IN => {
+                !is_meta(r.rtype)
+                    && (matches!(r.rtype, QuestionType::Unknown(_))
+                        || !matches!(r.data, RData::Unknown(_)))
+            }
+            QuestionClass::ANY => {
+                r.ttl == 0
+                    && empty
+                    && r.rtype != QuestionType::AXFR
+                    && r.rtype != QuestionType::IXFR
+            }
+            QuestionClass::NONE => r.ttl == 0 && !is_meta(r.rtype),
+            _ => false,
+        };
+        if !ok {
+            return Err(ResponseCode::FormatError);
+        }
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc2136#section-3.4.2
+    let mut answers = store.answers().to_vec();
+    let mut changed = false;
+    let mut new_soa = false;
+    for r in &message.authorities {
+        let key = name_key(&r.name);
+        let at_apex = key == apex;
+        // the apex always keeps its SOA and NS records
+        let protected =
+            |t: QuestionType| at_apex && (t == QuestionType::SOA || t == QuestionType::NS);
+        let before = answers.len();
+        match r.class {
+            QuestionClass::ANY if r.rtype == QuestionType::ANY => {
+                answers.retain(|a| a.key() != key || protected(a.atype));
+            }
+            QuestionClass::ANY => {
+                if !protected(r.rtype) {
+                    answers.retain(|a| a.key() != key || a.atype != r.rtype);
+                }
+            }
+            QuestionClass::NONE => {
+                let ns_left = answers
+                    .iter()
+                    .filter(|a| a.key() == key && a.atype == QuestionType::NS)
+                    .count();
+                let last_ns = at_apex && r.rtype == QuestionType::NS && ns_left == 1;
+                if r.rtype != QuestionType::SOA && !last_ns {
+                    answers.retain(|a| !same_record(a, r));
+                }
+            }
+            _ => {
+                if r.rtype == QuestionType::SOA {
+                    // only a newer SOA, and only at the apex
+                    let (RData::SOA { serial, .. }, RData::SOA { serial: old, .. }) =
+                        (&r.data, &soa.data)
+                    else {
+                        return Err(ResponseCode::FormatError);
+                    };
+                    if at_apex && serial_newer(*serial, *old) {
+                        answers.retain(|a| !(a.key() == key && a.atype == QuestionType::SOA));
+                        new_soa = true;
+                    } else {
+                        continue;
+                    }
+                }
+                // a CNAME can't share its name with other types
+                let is_cname = r.rtype == QuestionType::CNAME;
+                let clash = answers
+                    .iter()
+                    .any(|a| a.key() == key && (a.atype == QuestionType::CNAME) != is_cname);
+                if clash {
+                    continue;
+                }
+                let in_rrset = |a: &Answer| a.key() == key && a.atype == r.rtype;
+                // and there's only one, which a new one replaces
+                // https://datatracker.ietf.org/doc/html/rfc2136#section-3.4.2.2
+                if is_cname {
+                    answers.retain(|a| !in_rrset(a) || same_record(a, r));
+                    changed |= answers.len() != before;
+                }
+                // the records of an RRset all have the same TTL, the one last added
+                // https://datatracker.ietf.org/doc/html/rfc2181#section-5.2
+                for a in answers.iter_mut().filter(|a| in_rrset(a) && a.ttl != r.ttl) {
+                    a.ttl = r.ttl;
+                    changed = true;
+                }
+                if !answers.iter().any(|a| same_record(a, r)) {
+                    answers.push(Answer::new(
+                        r.name.clone(),
+                        r.rtype,
+                        r.class,
+                        r.ttl,
+                        r.data.clone(),
+                    ));
+                    changed = true;
+                }
+                continue;
+            }
+        }
+        changed |= answers.len() != before;
+    }
+    if !changed {
+        return Ok(None);
+    }
+
+    // every change gets a new serial, if the update didn't give one
+    // https://datatracker.ietf.org/doc/html/rfc2136#section-3.6
+    if !new_soa {
+        let soa = answers
+            .iter_mut()
+            .find(|a| a.key() == apex && a.atype == QuestionType::SOA)
+            .expect("the SOA can't be deleted");
+        if let RData::SOA { serial, .. } = &mut soa.data {
+            *serial = serial.wrapping_add(1);
+        }
+    }
+    Ok(Some(answers))
+}
+
+/// https://datatracker.ietf.org/doc/html/rfc2136#section-3.2
+fn check_prerequisites(
+    store: &Store,
+    prerequisites: &[Record],
+    in_zone: &dyn Fn(&str) -> bool,
+) -> Result<(), ResponseCode> {
+    // RRsets that have to exist exactly as given, by owner and type
+    let mut rrsets: HashMap<(Vec<Vec<u8>>, QuestionType), Vec<&Record>> = HashMap::new();
+    for r in prerequisites {
+        if r.ttl != 0 {
+            return Err(ResponseCode::FormatError);
+        }
+        if !in_zone(&r.name) {
+            return Err(ResponseCode::NotZone);
+        }
+        let owned = store.owned_by(&r.name);
+        let has_type = owned.iter().any(|a| a.atype == r.rtype);
+        let empty = matches!(&r.data, RData::Unknown(d) if d.is_empty());
+        match r.class {
+            QuestionClass::ANY if !empty => return Err(ResponseCode::FormatError),
+            // the name is in use
+            QuestionClass::ANY if r.rtype == QuestionType::ANY => {
+                if owned.is_empty() {
+                    return Err(ResponseCode::NxDomain);
+                }
+            }
+            // the RRset exists, whatever it holds
+            QuestionClass::ANY => {
+                if !has_type {
+                    return Err(ResponseCode::NXRRSet);
+                }
+            }
+            QuestionClass::NONE if !empty => return Err(ResponseCode::FormatError),
+            // the name is not in use
+            QuestionClass::NONE if r.rtype == QuestionType::ANY => {
+                if !owned.is_empty() {
+                    return Err(ResponseCode::YXDomain);
+                }
+            }
+            // the RRset doesn't exist
+            QuestionClass::NONE => {
+                if has_type {
+                    return Err(ResponseCode::YXRRSet);
+                }
+            }
+            QuestionClass::IN if !is_meta(r.rtype) => rrsets
+                .entry((name_key(&r.name), r.rtype))
+                .or_default()
+                .push(r),
+            _ => return Err(ResponseCode::FormatError),
+        }
+    }
+    for ((_, rtype), wanted) in rrsets {
+        let have: Vec<&Answer> = store
+            .owned_by(&wanted[0].name)
+            .into_iter()
+            .filter(|a| a.atype == rtype)
+            .collect();
+        let all_wanted = have
+            .iter()
+            .all(|a| wanted.iter().any(|r| same_record(a, r)));
+        let all_have = wanted
+            .iter()
+            .all(|r| have.iter().any(|a| same_record(a, r)));
+        if !all_wanted || !all_have {
+            return Err(ResponseCode::NXRRSet);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::super::{zone, Flags, Header, Opcode, Question};
+    use super::*;
+
+    const ZONE: &str = "$TTL 3600
+@ SOA ns hostmaster 1 3600 600 86400 300
+@ NS ns
+ns A 192.0.2.53
+www A 192.0.2.1
+www A 192.0.2.2
+alias CNAME www
+";
+
+    fn store() -> Store {
+        Store::new(zone::parse(ZONE, "example.com").unwrap())
+    }
+
+    fn record(name: &str, class: QuestionClass, rtype: QuestionType, data: RData) -> Record {
+        Record {
+            name: name.to_string(),
+            rtype,
+            class,
+            ttl: 0,
+            data,
+        }
+    }
+
+    /// Data of the prerequisites and deletes that have none
+    fn empty() -> RData {
+        RData::Unknown(vec![])
+    }
+
+    fn a(ip: [u8; 4]) -> RData {
+        RData::A(Ipv4Addr::from(ip))
+    }
+
+    fn update(prerequisites: Vec<Record>, updates: Vec<Record>) -> Message {
+        Message {
+            header: Header {
+                identification: 1,
+                flags: Flags {
+                    opcode: Opcode::Update,
+                    ..Flags::query(false)
+                },
+            },
+            questions: vec![Question {
+                domain: "example.com".to_string(),
+                qtype: QuestionType::SOA,
+                class: QuestionClass::IN,
+            }],
+            answers: prerequisites,
+            authorities: updates,
+            additionals: vec![],
+            edns: None,
+            tsig: None,
+        }
+    }
+
+    /// What `prerequisites` alone come to
+    fn check(prerequisites: Vec<Record>) -> Result<(), ResponseCode> {
+        apply(&store(), &update(prerequisites, vec![])).map(|changed| assert!(changed.is_none()))
+    }
+
+    fn serial(answers: &[Answer]) -> u32 {
+        answers
+            .iter()
+            .find_map(|a| match a.data {
+                RData::SOA { serial, .. } => Some(serial),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn name_in_use() {
+        let in_use = |name| record(name, QuestionClass::ANY, QuestionType::ANY, empty());
+        assert_eq!(check(vec![in_use("www.example.com")]), Ok(()));
+        assert_eq!(
+            check(vec![in_use("nothere.example.com")]),
+            Err(ResponseCode::NxDomain)
+        );
+        let not_in_use = |name| record(name, QuestionClass::NONE, QuestionType::ANY, empty());
+        assert_eq!(check(vec![not_in_use("nothere.example.com")]), Ok(()));
+        assert_eq!(
+            check(vec![not_in_use("WWW.example.com")]),
+            Err(ResponseCode::YXDomain)
+        );
+    }
+
+    #[test]
+    fn rrset_exists() {
+        let exists = |rtype| record("www.example.com", QuestionClass::ANY, rtype, empty());
+        assert_eq!(check(vec![exists(QuestionType::A)]), Ok(()));
+        assert_eq!(
+            check(vec![exists(QuestionType::TXT)]),
+            Err(ResponseCode::NXRRSet)
+        );
+        let missing = |rtype| record("www.example.com", QuestionClass::NONE, rtype, empty());
+        assert_eq!(check(vec![missing(QuestionType::TXT)]), Ok(()));
+        assert_eq!(
+            check(vec![missing(QuestionType::A)]),
+            Err(ResponseCode::YXRRSet)
+        );
+    }
+
+    #[test]
+    fn rrset_exists_with_exactly_these_records() {
+        let www = |ip| record("www.example.com", QuestionClass::IN, QuestionType::A, a(ip));
+        assert_eq!(
+            check(vec![www([192, 0, 2, 2]), www([192, 0, 2, 1])]),
+            Ok(())
+        );
+        assert_eq!(check(vec![www([192, 0, 2, 1])]), Err(ResponseCode::NXRRSet));
+        assert_eq!(
+            check(vec![
+                www([192, 0, 2, 1]),
+                www([192, 0, 2, 2]),
+                www([192, 0, 2, 3])
+            ]),
+            Err(ResponseCode::NXRRSet)
+        );
+        // names in the data compare case insensitively
+        let cname = RData::CNAME("WWW.example.COM".to_string());
+        let alias = record(
+            "alias.example.com",
+            QuestionClass::IN,
+            QuestionType::CNAME,
+            cname,
+        );
+        assert_eq!(check(vec![alias]), Ok(()));
+    }
+
+    #[test]
+    fn malformed_prerequisites() {
+        let mut with_ttl = record(
+            "www.example.com",
+            QuestionClass::ANY,
+            QuestionType::A,
+            empty(),
+        );
+        with_ttl.ttl = 60;
+        assert_eq!(check(vec![with_ttl]), Err(ResponseCode::FormatError));
+        let with_data = record(
+            "www.example.com",
+            QuestionClass::ANY,
+            QuestionType::A,
+            a([192, 0, 2, 1]),
+        );
+        assert_eq!(check(vec![with_data]), Err(ResponseCode::FormatError));
+        let elsewhere = record(
+            "www.example.net",
+            QuestionClass::ANY,
+            QuestionType::A,
+            empty(),
+        );
+        assert_eq!(check(vec![elsewhere]), Err(ResponseCode::NotZone));
+    }
+
+    #[test]
+    fn updates_only_apply_if_the_prerequisites_hold() {
+        let mut add = record(
+            "new.example.com",
+            QuestionClass::IN,
+            QuestionType::A,
+            a([192, 0, 2, 9]),
+        );
+        add.ttl = 60;
+        let absent = record(
+            "new.example.com",
+            QuestionClass::NONE,
+            QuestionType::ANY,
+            empty(),
+        );
+        let changed = apply(&store(), &update(vec![absent.clone()], vec![add.clone()]))
+            .unwrap()
+            .unwrap();
+        assert!(changed.iter().any(|a| same_record(a, &add)));
+        assert_eq!(serial(&changed), 2);
+
+        let present = record(
+            "new.example.com",
+            QuestionClass::ANY,
+            QuestionType::ANY,
+            empty(),
+        );
+        assert_eq!(
+            apply(&store(), &update(vec![present], vec![add])).err(),
+            Some(ResponseCode::NxDomain)
+        );
+    }
+
+    #[test]
+    fn new_cname_replaces_the_old() {
+        let target = RData::CNAME("ns.example.com".to_string());
+        let mut cname = record(
+            "alias.example.com",
+            QuestionClass::IN,
+            QuestionType::CNAME,
+            target,
+        );
+        cname.ttl = 60;
+        let changed = apply(&store(), &update(vec![], vec![cname.clone()]))
+            .unwrap()
+            .unwrap();
+        let cnames: Vec<&Answer> = changed
+            .iter()
+            .filter(|a| a.atype == QuestionType::CNAME)
+            .collect();
+        assert_eq!(cnames.len(), 1);
+        assert!(same_record(cnames[0], &cname));
+        // but it can't go where other types are
+        cname.name = "www.example.com".to_string();
+        assert!(apply(&store(), &update(vec![], vec![cname]))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn added_records_set_the_ttl_of_their_rrset() {
+        let mut add = record(
+            "www.example.com",
+            QuestionClass::IN,
+            QuestionType::A,
+            a([192, 0, 2, 3]),
+        );
+        add.ttl = 60;
+        let changed = apply(&store(), &update(vec![], vec![add]))
+            .unwrap()
+            .unwrap();
+        let ttls: Vec<u32> = changed
+            .iter()
+            .filter(|a| a.name == "www.example.com")
+            .map(|a| a.ttl)
+            .collect();
+        assert_eq!(ttls, [60, 60, 60]);
+    }
+}
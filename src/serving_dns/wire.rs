@@ -0,0 +1,403 @@
+// Wire format primitives: a cursor to read messages from and a buffer to write them into, both
+// aware of name compression.
+// https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.4
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+/// Longest name on the wire, length octets and root label included
+const MAX_NAME_LEN: usize = 255;
+/// Longest label, as the top two bits of the length octet mark pointers
+const MAX_LABEL_LEN: usize = 63;
+/// Compression pointers only have 14 bits for the offset
+const MAX_POINTER: usize = 0x3FFF;
+
+#[derive(Debug)]
+pub enum WireError {
+    /// The message ended in the middle of something
+    Truncated,
+    /// A compression pointer that does not point before itself
+    BadPointer(usize),
+    /// The extended (0b01) and the reserved (0b10) label types
+    BadLabelType(u8),
+    LabelTooLong,
+    NameTooLong,
+    /// Anything else that does not follow the format
+    Malformed(String),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::Truncated => write!(f, "truncated message"),
+            WireError::BadPointer(p) => write!(f, "bad compression pointer to {}", p),
+            WireError::BadLabelType(b) => write!(f, "unsupported label type {:#x}", b),
+            WireError::LabelTooLong => write!(f, "label longer than {} bytes", MAX_LABEL_LEN),
+            WireError::NameTooLong => write!(f, "name longer than {} bytes", MAX_NAME_LEN),
+            WireError::Malformed(what) => write!(f, "malformed message: {}", what),
+        }
+    }
+}
+
+impl Error for WireError {}
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf, pos: 0 }
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Result<&'a [u8], WireError> {
+        let b = self
+            .buf
+            .get(self.pos..self.pos + n)
+            .ok_or(WireError::Truncated)?;
+        self.pos += n;
+        Ok(b)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, WireError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, WireError> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, WireError> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    /// Reads a possibly compressed name, returned in presentation format without the trailing
+    /// dot. Pointers have to point before themselves and the expanded name can't be longer than
+    /// 255 bytes, which between them rule out loops.
+    pub fn name(&mut self) -> Result<String, WireError> {
+        let mut name = String::new();
+        let mut len = 1; // root label
+        let mut pos = self.pos;
+        let mut jumped = false;
+        loop {
+            let b = *self.buf.get(pos).ok_or(WireError::Truncated)? as usize;
+            match b >> 6 {
+                0b00 if b == 0 => {
+                    pos += 1;
+                    break;
+                }
+                0b00 => {
+                    let label = self
+                        .buf
+                        .get(pos + 1..pos + 1 + b)
+                        .ok_or(WireError::Truncated)?;
+                    len += b + 1;
+                    if len > MAX_NAME_LEN {
+                        return Err(WireError::NameTooLong);
+                    }
+                    if !name.is_empty() {
+                        name.push('.');
+                    }
+                    push_label(&mut name, label);
+                    pos += b + 1;
+                }
+                0b11 => {
+                    let low = *self.buf.get(pos + 1).ok_or(WireError::Truncated)? as usize;
+                    let target = ((b & 0x3F) << 8) | low;
+                    if target >= pos {
+                        return Err(WireError::BadPointer(target));
+                    }
+                    if !jumped {
+                        // the name continues elsewhere, but it ends here for the caller
+                        self.pos = pos + 2;
+                        jumped = true;
+                    }
+                    pos = target;
+                }
+                _ => return Err(WireError::BadLabelType(b as u8)),
+            }
+        }
+        if !jumped {
+            self.pos = pos;
+        }
+        Ok(name)
+    }
+}
+
+/// Appends a label in presentation format: dots and backslashes are escaped, and so is
+/// anything that isn't printable ASCII, as `\DDD`.
+fn push_label(name: &mut String, label: &[u8]) {
+    for &c in label {
+        match c {
+            b'.' | b'\\' => {
+                name.push('\\');
+                name.push(c as char);
+            }
+            0x21..=0x7E => name.push(c as char),
+            _ => name.push_str(&format!("\\{:03}", c)),
+        }
+    }
+}
+
+/// Splits a name in presentation format into its labels, undoing the escapes `push_label` adds.
+/// Empty labels are skipped, so both `example.com` and `example.com.` work, and `""` is the root.
+pub fn labels(name: &str) -> Vec<Vec<u8>> {
+    let name = name.as_bytes();
+    let mut labels = vec![];
+    let mut label = vec![];
+    let mut i = 0;
+    while i < name.len() {
+        match name[i] {
+            b'.' => {
+                if !label.is_empty() {
+                    labels.push(std::mem::take(&mut label));
+                }
+                i += 1;
+            }
+            b'\\' => match name.get(i + 1..i + 4) {
+                Some(d) if d.iter().all(u8::is_ascii_digit) => {
+                    let v = d.iter().fold(0u32, |v, d| v * 10 + (d - b'0') as u32);
+                    label.push(v as u8);
+                    i += 4;
+                }
+                _ => {
+                    // a trailing backslash stands for itself
+                    label.push(*name.get(i + 1).unwrap_or(&b'\\'));
+                    i += 2;
+                }
+            },
+            c => {
+                label.push(c);
+                i += 1;
+            }
+        }
+    }
+    if !label.is_empty() {
+        labels.push(label);
+    }
+    labels
+}
+
+/// The name in presentation format with these labels, the undoing of `labels`
+pub fn from_labels(labels: &[Vec<u8>]) -> String {
+    let mut name = String::new();
+    for label in labels {
+        if !name.is_empty() {
+            name.push('.');
+        }
+        push_label(&mut name, label);
+    }
+    name
+}
+
+/// The same name, with exactly the escapes `push_label` would use: `\065bc.` is `Abc`
+pub fn canonical_name(name: &str) -> String {
+    from_labels(&labels(name))
+}
+
+/// Uncompressed wire format of a name in presentation format, if it fits the limits
+pub fn data_as_sized_labels(buf: &'_ str) -> Result<Vec<u8>, WireError> {
+    let mut ret = Vec::with_capacity(16);
+    for mut part in labels(buf) {
+        if part.len() > MAX_LABEL_LEN {
+            return Err(WireError::LabelTooLong);
+        }
+        ret.push(part.len() as u8);
+        ret.append(&mut part);
+    }
+    ret.push(0);
+    if ret.len() > MAX_NAME_LEN {
+        return Err(WireError::NameTooLong);
+    }
+    Ok(ret)
+}
+
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+    /// Where each name (and every suffix of it) written so far starts, keyed by its lowercase
+    /// uncompressed wire format
+    names: HashMap<Vec<u8>, usize>,
+    /// Names go out lowercased and never compressed, as in the data DNSSEC signs
+    canonical: bool,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        Writer::default()
+    }
+
+    /// A writer for the canonical form of records
+    /// https://datatracker.ietf.org/doc/html/rfc4034#section-6.2
+    pub fn canonical() -> Writer {
+        Writer {
+            canonical: true,
+            ..Writer::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn bytes(&mut self, b: &[u8]) {
+        self.buf.extend(b);
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.buf.extend(v.to_be_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.buf.extend(v.to_be_bytes());
+    }
+
+    /// Overwrites a u16 written earlier, for lengths only known afterwards
+    pub fn set_u16(&mut self, pos: usize, v: u16) {
+        self.buf[pos..pos + 2].copy_from_slice(&v.to_be_bytes());
+    }
+
+    /// Writes a name, pointing to an earlier occurrence of its longest known suffix if
+    /// `compress` is set. Either way, its suffixes become available to later names.
+    pub fn name(&mut self, name: &str, compress: bool) -> Result<(), WireError> {
+        let mut wire = data_as_sized_labels(name)?;
+        // length octets are below 64, so this only touches the labels
+        if self.canonical {
+            wire.make_ascii_lowercase();
+        }
+        let compress = compress && !self.canonical;
+        let mut i = 0;
+        while wire[i] != 0 {
+            let suffix = wire[i..].to_ascii_lowercase();
+            if compress {
+                if let Some(&target) = self.names.get(&suffix) {
+                    self.u16(0xC000 | target as u16);
+                    return Ok(());
+                }
+            }
+            if self.buf.len() <= MAX_POINTER {
+                self.names.entry(suffix).or_insert(self.buf.len());
+            }
+            let len = wire[i] as usize;
+            self.buf.extend(&wire[i..i + 1 + len]);
+            i += 1 + len;
+        }
+        self.u8(0);
+        Ok(())
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_name(buf: &[u8], at: usize) -> Result<(String, usize), WireError> {
+        let mut reader = Reader::new(buf);
+        reader.pos = at;
+        let name = reader.name()?;
+        Ok((name, reader.pos()))
+    }
+
+    #[test]
+    fn follows_pointers_back() {
+        let mut buf = b"\x07example\x03com\x00".to_vec();
+        buf.extend(b"\x03www\xC0\x00");
+        let (name, end) = read_name(&buf, 13).unwrap();
+        assert_eq!(name, "www.example.com");
+        // the name ends at the pointer, not where it points
+        assert_eq!(end, buf.len());
+    }
+
+    #[test]
+    fn writes_what_it_reads() {
+        let mut writer = Writer::new();
+        writer.name("example.com", true).unwrap();
+        writer.name("www.Example.com", true).unwrap();
+        let buf = writer.into_bytes();
+        assert_eq!(buf.len(), 13 + 6);
+        assert_eq!(read_name(&buf, 13).unwrap().0, "www.example.com");
+    }
+
+    #[test]
+    fn rejects_pointer_to_itself() {
+        assert!(matches!(
+            read_name(b"\xC0\x00", 0),
+            Err(WireError::BadPointer(0))
+        ));
+    }
+
+    #[test]
+    fn rejects_forward_pointer() {
+        let buf = b"\x03www\xC0\x06\x00";
+        assert!(matches!(read_name(buf, 0), Err(WireError::BadPointer(6))));
+    }
+
+    #[test]
+    fn stops_pointer_loop() {
+        // a label, then a pointer back to it: every pointer goes back, but the name never ends
+        let buf = b"\x01a\xC0\x00";
+        assert!(matches!(read_name(buf, 0), Err(WireError::NameTooLong)));
+    }
+
+    #[test]
+    fn rejects_name_over_255_bytes() {
+        // three labels of 63 bytes, one of `last`, and the root label
+        let name = |last: usize| {
+            let mut buf = vec![];
+            for len in [63, 63, 63, last] {
+                buf.push(len as u8);
+                buf.extend(vec![b'a'; len]);
+            }
+            buf.push(0);
+            buf
+        };
+        assert_eq!(read_name(&name(61), 0).unwrap().1, 255);
+        assert!(matches!(
+            read_name(&name(62), 0),
+            Err(WireError::NameTooLong)
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_names() {
+        for buf in [&b""[..], b"\x03ww", b"\x03www", b"\x03www\xC0"] {
+            assert!(
+                matches!(read_name(buf, 0), Err(WireError::Truncated)),
+                "{:?}",
+                buf
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_reserved_label_types() {
+        assert!(matches!(
+            read_name(b"\x40", 0),
+            Err(WireError::BadLabelType(0x40))
+        ));
+        assert!(matches!(
+            read_name(b"\x80", 0),
+            Err(WireError::BadLabelType(0x80))
+        ));
+    }
+
+    #[test]
+    fn escapes_and_unescapes_labels() {
+        let (name, _) = read_name(b"\x04a.b\\\x02\x01c\x00", 0).unwrap();
+        assert_eq!(name, "a\\.b\\\\.\\001c");
+        assert_eq!(labels(&name), vec![b"a.b\\".to_vec(), b"\x01c".to_vec()]);
+    }
+}
@@ -0,0 +1,536 @@
+// Master files, as BIND reads them: `$ORIGIN`, `$TTL`, `@`, relative names, parentheses
+// spanning lines, comments and escapes.
+// https://datatracker.ietf.org/doc/html/rfc1035#section-5
+// https://datatracker.ietf.org/doc/html/rfc2308#section-4 ($TTL)
+// https://datatracker.ietf.org/doc/html/rfc3597#section-5 (unknown types)
+use std::error::Error;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use super::wire::{canonical_name, data_as_sized_labels, Reader};
+use super::{Answer, QuestionClass, QuestionType, RData};
+
+#[derive(Debug)]
+pub struct ZoneError {
+    line: usize,
+    message: String,
+}
+
+impl fmt::Display for ZoneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for ZoneError {}
+
+#[derive(Debug)]
+struct Token {
+    /// As written, escapes included, without the quotes of quoted strings
+    text: String,
+    quoted: bool,
+}
+
+/// A record or directive, which parentheses may have spread over several lines
+#[derive(Debug)]
+struct Entry {
+    /// Line where it starts
+    line: usize,
+    /// Starts with blanks, so it belongs to the previous owner
+    continued: bool,
+    tokens: Vec<Token>,
+}
+
+fn entries(text: &str) -> Result<Vec<Entry>, ZoneError> {
+    let mut entries: Vec<Entry> = vec![];
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    let mut parens = 0;
+    let mut at_line_start = true;
+    while let Some(&c) = chars.peek() {
+        if at_line_start && parens == 0 {
+            entries.push(Entry {
+                line,
+                continued: c == ' ' || c == '\t',
+                tokens: vec![],
+            });
+        }
+        at_line_start = false;
+        let entry = entries.last_mut().unwrap();
+        match c {
+            '\n' => {
+                chars.next();
+                line += 1;
+                at_line_start = true;
+            }
+            ' ' | '\t' | '\r' => {
+                chars.next();
+            }
+            ';' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '(' => {
+                chars.next();
+                parens += 1;
+            }
+            ')' => {
+                chars.next();
+                if parens == 0 {
+                    return Err(ZoneError {
+                        line,
+                        message: "unbalanced )".into(),
+                    });
+                }
+                parens -= 1;
+            }
+            '"' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            text.push('\\');
+                            text.extend(chars.next());
+                        }
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            text.push(c);
+                        }
+                        None => {
+                            return Err(ZoneError {
+                                line,
+                                message: "unterminated string".into(),
+                            })
+                        }
+                    }
+                }
+                entry.tokens.push(Token { text, quoted: true });
+            }
+            _ => {
+                let mut text = String::new();
+                while let Some(c) = chars.next_if(|&c| !" \t\r\n;()\"".contains(c)) {
+                    text.push(c);
+                    if c == '\\' {
+                        text.extend(chars.next());
+                    }
+                }
+                entry.tokens.push(Token {
+                    text,
+                    quoted: false,
+                });
+            }
+        }
+    }
+    if parens != 0 {
+        return Err(ZoneError {
+            line,
+            message: "unbalanced (".into(),
+        });
+    }
+    entries.retain(|e| !e.tokens.is_empty());
+    Ok(entries)
+}
+
+/// The bytes a token stands for, with `\X` and `\DDD` escapes resolved
+fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let b = text.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < b.len() {
+        if b[i] != b'\\' {
+            out.push(b[i]);
+            i += 1;
+            continue;
+        }
+        match b.get(i + 1..i + 4) {
+            Some(d) if d.iter().all(u8::is_ascii_digit) => {
+                let v = d.iter().fold(0u32, |v, d| v * 10 + (d - b'0') as u32);
+                out.push(u8::try_from(v).map_err(|_| format!("bad escape \\{}", v))?);
+                i += 4;
+            }
+            _ => {
+                out.push(*b.get(i + 1).ok_or("trailing backslash")?);
+                i += 2;
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// TTLs in seconds, or with BIND's units: `1h30m`, `2d`, ...
+fn parse_ttl(s: &str) -> Option<u32> {
+    if let Ok(ttl) = s.parse() {
+        return Some(ttl);
+    }
+    let mut ttl: u32 = 0;
+    let mut n: u32 = 0;
+    let mut digits = false;
+    for c in s.chars() {
+        if let Some(d) = c.to_digit(10) {
+            n = n.checked_mul(10)?.checked_add(d)?;
+            digits = true;
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+        if !digits {
+            return None;
+        }
+        ttl = ttl.checked_add(n.checked_mul(unit)?)?;
+        n = 0;
+        digits = false;
+    }
+    if digits {
+        return None;
+    }
+    Some(ttl)
+}
+
+struct Parser {
+    origin: String,
+    default_ttl: Option<u32>,
+    last_owner: Option<String>,
+    last_ttl: Option<u32>,
+}
+
+impl Parser {
+    /// Makes `name` absolute (without the trailing dot, like every other name here), as long as
+    /// it still fits on the wire
+    fn name(&self, name: &str) -> Result<String, String> {
+        if name == "@" {
+            return Ok(self.origin.clone());
+        }
+        // a trailing dot that isn't escaped makes it absolute already
+        let absolute = name.ends_with('.') && !name.ends_with("\\.") || name.ends_with("\\\\.");
+        let name = if absolute || self.origin.is_empty() {
+            canonical_name(name)
+        } else {
+            canonical_name(&format!("{}.{}", name, self.origin))
+        };
+        data_as_sized_labels(&name).map_err(|e| format!("{}: {}", name, e))?;
+        Ok(name)
+    }
+
+    fn directive(&mut self, tokens: &[Token]) -> Result<(), String> {
+        let arg = tokens.get(1).ok_or("missing argument")?;
+        match tokens[0].text.to_ascii_uppercase().as_str() {
+            "$ORIGIN" => self.origin = self.name(&arg.text)?,
+            "$TTL" => {
+                self.default_ttl = Some(parse_ttl(&arg.text).ok_or("bad TTL")?);
+            }
+            d => return Err(format!("unsupported directive {}", d)),
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, entry: &Entry) -> Result<Answer, String> {
+        let mut tokens = entry.tokens.iter();
+        let owner = if entry.continued {
+            self.last_owner.clone().ok_or("no previous owner")?
+        } else {
+            self.name(&tokens.next().unwrap().text)?
+        };
+
+        // TTL and class can come in either order, and are both optional
+        let mut ttl = None;
+        let mut class = QuestionClass::IN;
+        let rtype = loop {
+            let t = tokens.next().ok_or("missing type")?;
+            if t.quoted {
+                return Err(format!("expected a type, got \"{}\"", t.text));
+            } else if let Some(v) = parse_ttl(&t.text) {
+                ttl = Some(v);
+            } else if let Ok(c) = QuestionClass::from_str(&t.text.to_ascii_uppercase()) {
+                class = c;
+            } else {
+                break QuestionType::from_str(&t.text.to_ascii_uppercase())
+                    .map_err(|_| format!("unsupported type {}", t.text))?;
+            }
+        };
+        let ttl = ttl
+            .or(self.default_ttl)
+            .or(self.last_ttl)
+            .ok_or("no TTL and no $TTL before")?;
+
+        let rdata: Vec<&Token> = tokens.collect();
+        let data = self.rdata(rtype, &rdata)?;
+
+        self.last_owner = Some(owner.clone());
+        self.last_ttl = Some(ttl);
+        Ok(Answer::new(owner, rtype, class, ttl, data))
+    }
+
+    fn rdata(&self, rtype: QuestionType, tokens: &[&Token]) -> Result<RData, String> {
+        if tokens.first().is_some_and(|t| t.text == "\\#" && !t.quoted) {
+            return generic_rdata(rtype, &tokens[1..]);
+        }
+        let field = |i: usize| -> Result<&str, String> {
+            tokens
+                .get(i)
+                .map(|t| t.text.as_str())
+                .ok_or_else(|| format!("missing {} data", rtype))
+        };
+        fn number<T: FromStr>(s: &str) -> Result<T, String> {
+            s.parse().map_err(|_| format!("bad number {}", s))
+        }
+        let timer = |s: &str| parse_ttl(s).ok_or_else(|| format!("bad time {}", s));
+        let (data, used) = match rtype {
+            QuestionType::A => (
+                RData::A(Ipv4Addr::from_str(field(0)?).map_err(|e| e.to_string())?),
+                1,
+            ),
+            QuestionType::AAAA => (
+                RData::AAAA(Ipv6Addr::from_str(field(0)?).map_err(|e| e.to_string())?),
+                1,
+            ),
+            QuestionType::CNAME => (RData::CNAME(self.name(field(0)?)?), 1),
+            QuestionType::NS => (RData::NS(self.name(field(0)?)?), 1),
+            QuestionType::PTR => (RData::PTR(self.name(field(0)?)?), 1),
+            QuestionType::MX => (
+                RData::MX {
+                    preference: number(field(0)?)?,
+                    exchange: self.name(field(1)?)?,
+                },
+                2,
+            ),
+            QuestionType::SOA => (
+                RData::SOA {
+                    mname: self.name(field(0)?)?,
+                    rname: self.name(field(1)?)?,
+                    serial: number(field(2)?)?,
+                    refresh: timer(field(3)?)?,
+                    retry: timer(field(4)?)?,
+                    expire: timer(field(5)?)?,
+                    minimum: timer(field(6)?)?,
+                },
+                7,
+            ),
+            QuestionType::SRV => (
+                RData::SRV {
+                    priority: number(field(0)?)?,
+                    weight: number(field(1)?)?,
+                    port: number(field(2)?)?,
+                    target: self.name(field(3)?)?,
+                },
+                4,
+            ),
+            QuestionType::CAA => {
+                let tag = field(1)?;
+                if tag.is_empty() || !tag.bytes().all(|c| c.is_ascii_alphanumeric()) {
+                    return Err(format!("bad CAA tag {}", tag));
+                }
+                let data = RData::CAA {
+                    flags: number(field(0)?)?,
+                    tag: tag.as_bytes().to_vec(),
+                    value: unescape(field(2)?)?,
+                };
+                (data, 3)
+            }
+            QuestionType::TXT => {
+                field(0)?;
+                let strings = tokens
+                    .iter()
+                    .map(|t| unescape(&t.text))
+                    .collect::<Result<Vec<_>, _>>()?;
+                (RData::TXT(strings), tokens.len())
+            }
+            QuestionType::RP => (
+                RData::RP {
+                    mbox: self.name(field(0)?)?,
+                    txt: self.name(field(1)?)?,
+                },
+                2,
+            ),
+            t => return Err(format!("unsupported type {}, use \\# for its data", t)),
+        };
+        if tokens.len() > used {
+            return Err(format!("trailing data after {} record", rtype));
+        }
+        Ok(data)
+    }
+}
+
+/// RFC 3597 generic data, `\# <length> <hex>...`, which any type can use. Known types still
+/// have to make sense.
+fn generic_rdata(rtype: QuestionType, tokens: &[&Token]) -> Result<RData, String> {
+    let len: usize = tokens
+        .first()
+        .and_then(|t| t.text.parse().ok())
+        .ok_or("\\# needs a length")?;
+    let hex: String = tokens[1..].iter().map(|t| t.text.as_str()).collect();
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("bad hex data {}", hex));
+    }
+    let data: Vec<u8> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect();
+    if data.len() != len {
+        return Err(format!("{} bytes of data, expected {}", data.len(), len));
+    }
+    let rdata = RData::read(&mut Reader::new(&data), rtype, len).map_err(|e| e.to_string())?;
+    // reading lets A and AAAA of the wrong length through, and empty data for UPDATE, as unknown
+    if matches!(rdata, RData::Unknown(_)) && !matches!(rtype, QuestionType::Unknown(_)) {
+        return Err(format!("{} bytes of data isn't {} data", len, rtype));
+    }
+    Ok(rdata)
+}
+
+/// The data of a single record of type `rtype`, in master file syntax. Names have to be
+/// absolute, as there's no origin.
+pub fn rdata(rtype: QuestionType, text: &str) -> Result<RData, String> {
+    let parser = Parser {
+        origin: String::new(),
+        default_ttl: None,
+        last_owner: None,
+        last_ttl: None,
+    };
+    let entries = entries(text).map_err(|e| e.message)?;
+    let tokens: Vec<&Token> = entries.iter().flat_map(|e| &e.tokens).collect();
+    parser.rdata(rtype, &tokens)
+}
+
+/// Parses a master file. `origin` is where relative names are relative to until a `$ORIGIN`.
+pub fn parse(text: &str, origin: &str) -> Result<Vec<Answer>, ZoneError> {
+    let mut parser = Parser {
+        origin: canonical_name(origin),
+        default_ttl: None,
+        last_owner: None,
+        last_ttl: None,
+    };
+    let mut answers = vec![];
+    for entry in entries(text)? {
+        let res = if entry.tokens[0].text.starts_with('$') && !entry.continued {
+            parser.directive(&entry.tokens)
+        } else {
+            parser.record(&entry).map(|a| answers.push(a))
+        };
+        res.map_err(|message| ZoneError {
+            line: entry.line,
+            message,
+        })?;
+    }
+    Ok(answers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The records as `owner ttl type data`
+    fn records(text: &str, origin: &str) -> Vec<String> {
+        parse(text, origin)
+            .unwrap()
+            .iter()
+            .map(|a| format!("{} {} {} {}", a.name, a.ttl, a.atype, a.data))
+            .collect()
+    }
+
+    #[test]
+    fn ttl_directive_sets_the_default() {
+        let text =
+            "$TTL 1h\na A 192.0.2.1\nb 60 A 192.0.2.2\n  A 192.0.2.3\n$TTL 30\nc A 192.0.2.4\n";
+        assert_eq!(
+            records(text, "example.com"),
+            [
+                "a.example.com 3600 A 192.0.2.1",
+                "b.example.com 60 A 192.0.2.2",
+                // a blank owner is the previous one, with the default TTL
+                "b.example.com 3600 A 192.0.2.3",
+                "c.example.com 30 A 192.0.2.4",
+            ]
+        );
+    }
+
+    #[test]
+    fn ttl_is_needed_somewhere() {
+        let e = parse("a A 192.0.2.1\n", "example.com").unwrap_err();
+        assert_eq!(e.to_string(), "line 1: no TTL and no $TTL before");
+        let e = parse("$TTL 1x\n", "example.com").unwrap_err();
+        assert_eq!(e.to_string(), "line 1: bad TTL");
+        // without $TTL, the last one given
+        assert_eq!(
+            records("a 5 A 192.0.2.1\nb A 192.0.2.2\n", "example.com"),
+            ["a.example.com 5 A 192.0.2.1", "b.example.com 5 A 192.0.2.2"]
+        );
+    }
+
+    #[test]
+    fn origin_directive_changes_relative_names() {
+        let text = "$TTL 60
+@ NS ns
+$ORIGIN sub.example.com.
+@ CNAME target
+www CNAME other.example.net.
+$ORIGIN deeper
+x MX 10 mail
+";
+        assert_eq!(
+            records(text, "example.com"),
+            [
+                "example.com 60 NS ns.example.com.",
+                "sub.example.com 60 CNAME target.sub.example.com.",
+                "www.sub.example.com 60 CNAME other.example.net.",
+                // a relative $ORIGIN is relative to the one before
+                "x.deeper.sub.example.com 60 MX 10 mail.deeper.sub.example.com.",
+            ]
+        );
+    }
+
+    #[test]
+    fn parentheses_span_lines() {
+        let text = "$TTL 60
+@ SOA ns hostmaster ( 1 ; serial
+    1h 10m 1w 30 )
+";
+        assert_eq!(
+            records(text, "example.com"),
+            ["example.com 60 SOA ns.example.com. hostmaster.example.com. 1 3600 600 604800 30"]
+        );
+    }
+
+    #[test]
+    fn generic_rdata() {
+        let text = "$TTL 60
+a TYPE65280 \\# 4 0A00 0001
+b A \\# 4 c0000201
+c TYPE65280 \\# 0
+";
+        assert_eq!(
+            records(text, "example.com"),
+            [
+                "a.example.com 60 TYPE65280 \\# 4 0A000001",
+                // known types come out as themselves
+                "b.example.com 60 A 192.0.2.1",
+                "c.example.com 60 TYPE65280 \\# 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn generic_rdata_has_to_match() {
+        for (text, error) in [
+            ("a TYPE65280 \\# 3 0A00", "2 bytes of data, expected 3"),
+            ("a TYPE65280 \\# 1 0A0", "bad hex data 0A0"),
+            ("a TYPE65280 \\# 1 zz", "bad hex data zz"),
+            ("a TYPE65280 \\#", "\\# needs a length"),
+            ("a A \\# 3 c00002", "3 bytes of data isn't A data"),
+            ("a AAAA \\# 0", "0 bytes of data isn't AAAA data"),
+            (
+                "a TYPE65280 0A00",
+                "unsupported type TYPE65280, use \\# for its data",
+            ),
+        ] {
+            let e = parse(&format!("$TTL 60\n{}\n", text), "example.com").unwrap_err();
+            assert_eq!(e.to_string(), format!("line 2: {}", error), "{}", text);
+        }
+    }
+}